@@ -0,0 +1,64 @@
+// Runs random ROMs with random quirks and keys: the interpreter must not panic, read outside memory
+// nor overflow its stack. Run it with `cargo fuzz run run_rom`.
+
+#![no_main]
+
+use chip8::{
+    chip8::{Chip8, MEMORY_SIZE, START_ALLOWED_ADDRESS},
+    quirks::Quirks,
+    rng::SeededRandom,
+};
+use libfuzzer_sys::{
+    arbitrary::{self, Arbitrary},
+    fuzz_target,
+};
+
+const FRAMES: usize = 120;
+const CYCLES_PER_FRAME: usize = 20;
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    // One quirk per bit
+    quirks: u8,
+    seed: u64,
+    // Keys held down, one bit per key, for each frame
+    keys: Vec<u16>,
+    rom: Vec<u8>,
+}
+
+fn quirks(bits: u8) -> Quirks {
+    let bit = |n: u8| bits >> n & 1 == 1;
+    Quirks {
+        key_wait_release: bit(0),
+        key_wait_beep: bit(1),
+        vf_reset: bit(2),
+        memory_increment: bit(3),
+        memory_increment_by_x: bit(4),
+        shift_vy: bit(5),
+        jump_vx: bit(6),
+        clip: bit(7),
+    }
+}
+
+fuzz_target!(|input: Input| {
+    let mut chip8 = Chip8::new(
+        quirks(input.quirks),
+        Box::new(SeededRandom::new(input.seed)),
+    );
+    if chip8.load_rom(input.rom, START_ALLOWED_ADDRESS).is_err() {
+        return;
+    }
+    for frame in 0..FRAMES {
+        let held = input.keys.get(frame).copied().unwrap_or(0);
+        for key in 0..16 {
+            chip8.keypad.set_key(key, held >> key & 1 == 1);
+        }
+        for _ in 0..CYCLES_PER_FRAME {
+            chip8.cycle();
+            assert!(chip8.pc() < MEMORY_SIZE);
+            assert!(chip8.i() < MEMORY_SIZE);
+            assert!(chip8.sp() <= chip8.stack().len());
+        }
+        chip8.decrement_timers();
+    }
+});
@@ -0,0 +1,721 @@
+// Static analysis of ROMs (`chip8 analyze`): the control-flow graph of the instructions reachable from
+// the start, which bytes are code, sprites or other data, and what the ROM needs from the interpreter:
+// platform extensions, quirks that change its behaviour, whether it modifies itself or may overflow
+// the stack.
+
+use crate::{chip8::MEMORY_SIZE, disassembler::disassemble};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    ops::Range,
+};
+
+// Return addresses the stack holds
+const STACK_SIZE: usize = 16;
+// Entries of a BNNN jump table followed, at most
+const MAX_TABLE_SIZE: usize = 128;
+
+/// How an instruction passes control on
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Flow {
+    Next,
+    Jump(usize),
+    // To the next instruction or the one after
+    Skip,
+    Call(usize),
+    Return,
+    // BNNN: NNN plus a register
+    Computed(usize),
+    // EXIT, or bytes that are not an instruction
+    Stop,
+}
+
+fn flow(opcode: u16) -> Flow {
+    let nnn = (opcode & 0xfff) as usize;
+    match opcode >> 12 {
+        0x0 => match opcode {
+            0x00ee => Flow::Return,
+            0x00fd => Flow::Stop,
+            _ => Flow::Next,
+        },
+        0x1 => Flow::Jump(nnn),
+        0x2 => Flow::Call(nnn),
+        0x3 | 0x4 => Flow::Skip,
+        0x5 | 0x9 if opcode & 0xf == 0 => Flow::Skip,
+        0xb => Flow::Computed(nnn),
+        0xe if matches!(opcode & 0xff, 0x9e | 0xa1) => Flow::Skip,
+        _ if disassemble(opcode).starts_with("DW") => Flow::Stop,
+        _ => Flow::Next,
+    }
+}
+
+/// Bytes taken by the instruction, XO-CHIP's `LD I, long` is followed by the address
+pub fn size(opcode: u16) -> usize {
+    if opcode == 0xf000 {
+        4
+    } else {
+        2
+    }
+}
+
+/// Platform the instruction comes from, when it is not plain CHIP-8
+fn extension(opcode: u16) -> Option<&'static str> {
+    let kk = opcode & 0xff;
+    match opcode >> 12 {
+        0x0 => match opcode {
+            0x00e0 | 0x00ee => None,
+            0x00fb..=0x00ff => Some("SUPER-CHIP"),
+            _ if opcode & 0xfff0 == 0x00c0 => Some("SUPER-CHIP"),
+            _ if opcode & 0xfff0 == 0x00d0 => Some("XO-CHIP"),
+            _ => Some("machine code"),
+        },
+        0x5 if matches!(opcode & 0xf, 2 | 3) => Some("XO-CHIP"),
+        0xd if opcode & 0xf == 0 => Some("SUPER-CHIP"),
+        0xf => match kk {
+            0x30 | 0x75 | 0x85 => Some("SUPER-CHIP"),
+            0x00 | 0x01 | 0x02 | 0x3a => Some("XO-CHIP"),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Quirk that changes what the instruction does
+fn quirk(opcode: u16) -> Option<&'static str> {
+    let x = (opcode >> 8) & 0xf;
+    let y = (opcode >> 4) & 0xf;
+    match (opcode >> 12, opcode & 0xf, opcode & 0xff) {
+        (0x8, 0x1..=0x3, _) => Some("vf_reset"),
+        (0x8, 0x6 | 0xe, _) if x != y => Some("shift_vy"),
+        (0xb, _, _) => Some("jump_vx"),
+        (0xf, _, 0x55 | 0x65) => Some("memory_increment"),
+        (0xf, _, 0x0a) => Some("key_wait_release"),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Edge {
+    // Running on, or returning from a call
+    Next,
+    Jump,
+    // Taken when the skip instruction skips
+    Skip,
+    Call,
+    // One of the targets of BNNN
+    Computed,
+}
+
+/// Instructions running one after the other, control only enters at the first one
+#[derive(Debug, PartialEq)]
+pub struct Block {
+    pub instructions: Vec<usize>,
+    pub successors: Vec<(usize, Edge)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Code,
+    Sprite,
+    // Read or written through I, other than sprites
+    Data,
+}
+
+/// Deepest chain of calls
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CallDepth {
+    Bounded(usize),
+    // A subroutine that can call itself again
+    Recursive(usize),
+}
+
+/// Value of I while the instructions run
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Index {
+    Known(usize),
+    Unknown,
+}
+
+impl Index {
+    fn merge(self, other: Index) -> Index {
+        if self == other {
+            self
+        } else {
+            Index::Unknown
+        }
+    }
+}
+
+pub struct Analysis {
+    memory: Vec<u8>,
+    rom: Range<usize>,
+    // Basic blocks by first address
+    pub blocks: BTreeMap<usize, Block>,
+    pub subroutines: BTreeSet<usize>,
+    // What each ROM byte is, None when nothing reaches it
+    kinds: Vec<Option<Kind>>,
+    pub computed_jumps: BTreeSet<usize>,
+    // Instructions going to addresses outside the ROM
+    pub outside: BTreeSet<usize>,
+    // Instructions of other platforms, by platform
+    pub extensions: BTreeMap<&'static str, Vec<usize>>,
+    // First instruction each quirk matters for
+    pub quirks: BTreeMap<&'static str, usize>,
+    // Instructions writing over code, with the addresses written
+    pub self_modifying: Vec<(usize, Range<usize>)>,
+    pub call_depth: CallDepth,
+}
+
+/// Analyses the ROM loaded at the address, from its first instruction
+pub fn analyze(rom: &[u8], load_address: usize) -> Analysis {
+    let mut memory = vec![0; MEMORY_SIZE];
+    let load_address = load_address.min(MEMORY_SIZE);
+    let end = (load_address + rom.len()).min(MEMORY_SIZE);
+    memory[load_address..end].copy_from_slice(&rom[..end - load_address]);
+    let mut analysis = Analysis {
+        memory,
+        rom: load_address..end,
+        blocks: BTreeMap::new(),
+        subroutines: BTreeSet::new(),
+        kinds: vec![None; end - load_address],
+        computed_jumps: BTreeSet::new(),
+        outside: BTreeSet::new(),
+        extensions: BTreeMap::new(),
+        quirks: BTreeMap::new(),
+        self_modifying: Vec::new(),
+        call_depth: CallDepth::Bounded(0),
+    };
+    analysis.build_blocks();
+    // Nothing to follow when the ROM doesn't hold a whole instruction
+    if !analysis.blocks.contains_key(&load_address) {
+        return analysis;
+    }
+    analysis.find_data();
+    analysis.call_depth = analysis.call_depth();
+    analysis
+}
+
+impl Analysis {
+    pub fn start(&self) -> usize {
+        self.rom.start
+    }
+
+    /// Opcode at the address, 0 outside memory
+    pub fn opcode_at(&self, address: usize) -> u16 {
+        let byte = |address: usize| *self.memory.get(address).unwrap_or(&0) as u16;
+        byte(address) << 8 | byte(address + 1)
+    }
+
+    pub fn kind(&self, address: usize) -> Option<Kind> {
+        let offset = address.checked_sub(self.rom.start)?;
+        *self.kinds.get(offset)?
+    }
+
+    fn in_rom(&self, address: usize) -> bool {
+        self.rom.contains(&address) && self.rom.contains(&(address + 1))
+    }
+
+    /// Where the instruction passes control to, in and out of the ROM
+    fn successors(&self, address: usize) -> Vec<(usize, Edge)> {
+        let opcode = self.opcode_at(address);
+        let next = address + size(opcode);
+        match flow(opcode) {
+            Flow::Next => vec![(next, Edge::Next)],
+            Flow::Jump(target) => vec![(target, Edge::Jump)],
+            Flow::Skip => {
+                let skipped = next + size(self.opcode_at(next));
+                vec![(next, Edge::Next), (skipped, Edge::Skip)]
+            }
+            Flow::Call(target) => vec![(target, Edge::Call), (next, Edge::Next)],
+            Flow::Return | Flow::Stop => Vec::new(),
+            Flow::Computed(table) => {
+                // Jump tables are usually jumps, one per value of the register
+                let entries = (table..).step_by(2).take(MAX_TABLE_SIZE);
+                let mut entries: Vec<usize> = entries
+                    .take_while(|&entry| self.in_rom(entry) && self.opcode_at(entry) >> 12 == 0x1)
+                    .collect();
+                if entries.is_empty() {
+                    entries.push(table);
+                }
+                entries.into_iter().map(|e| (e, Edge::Computed)).collect()
+            }
+        }
+    }
+
+    /// Follows every path from the start, then splits the instructions into blocks
+    fn build_blocks(&mut self) {
+        let mut reached = BTreeSet::new();
+        let mut leaders = BTreeSet::from([self.rom.start]);
+        let mut pending = vec![self.rom.start];
+        while let Some(address) = pending.pop() {
+            if !self.in_rom(address) || !reached.insert(address) {
+                continue;
+            }
+            let opcode = self.opcode_at(address);
+            let flow = flow(opcode);
+            match flow {
+                Flow::Call(target) if self.in_rom(target) => {
+                    self.subroutines.insert(target);
+                }
+                Flow::Computed(_) => {
+                    self.computed_jumps.insert(address);
+                }
+                _ => (),
+            }
+            if let Some(platform) = extension(opcode) {
+                self.extensions.entry(platform).or_default().push(address);
+            }
+            if let Some(quirk) = quirk(opcode) {
+                let first = self.quirks.entry(quirk).or_insert(address);
+                *first = (*first).min(address);
+            }
+            for (target, _) in self.successors(address) {
+                if !self.in_rom(target) {
+                    self.outside.insert(address);
+                    continue;
+                }
+                if flow != Flow::Next {
+                    leaders.insert(target);
+                }
+                pending.push(target);
+            }
+        }
+        for addresses in self.extensions.values_mut() {
+            addresses.sort();
+        }
+
+        for &leader in leaders.iter().filter(|leader| reached.contains(*leader)) {
+            let mut instructions = Vec::new();
+            let mut address = leader;
+            let successors = loop {
+                instructions.push(address);
+                let opcode = self.opcode_at(address);
+                for offset in 0..size(opcode) {
+                    self.set_kind(address + offset, Kind::Code);
+                }
+                let next = address + size(opcode);
+                let ends = flow(opcode) != Flow::Next;
+                if ends || !reached.contains(&next) || leaders.contains(&next) {
+                    break self.successors(address);
+                }
+                address = next;
+            };
+            let successors = successors
+                .into_iter()
+                .filter(|(target, _)| self.in_rom(*target))
+                .collect();
+            let block = Block {
+                instructions,
+                successors,
+            };
+            self.blocks.insert(leader, block);
+        }
+    }
+
+    fn set_kind(&mut self, address: usize, kind: Kind) {
+        let Some(offset) = address.checked_sub(self.rom.start) else {
+            return;
+        };
+        if let Some(current) = self.kinds.get_mut(offset) {
+            // Code wins over sprites, sprites over data
+            let rank = |kind: Option<Kind>| match kind {
+                None => 0,
+                Some(Kind::Data) => 1,
+                Some(Kind::Sprite) => 2,
+                Some(Kind::Code) => 3,
+            };
+            if rank(Some(kind)) > rank(*current) {
+                *current = Some(kind);
+            }
+        }
+    }
+
+    /// Value of I when each block starts, as far as it can be told
+    fn indexes(&self) -> HashMap<usize, Index> {
+        let mut indexes = HashMap::from([(self.rom.start, Index::Unknown)]);
+        let mut pending = vec![self.rom.start];
+        while let Some(start) = pending.pop() {
+            let block = &self.blocks[&start];
+            let mut index = indexes[&start];
+            for &address in &block.instructions {
+                index = self.next_index(address, index);
+            }
+            // Subroutines start with any I, and may change it before returning
+            let changed = block.successors.iter().any(|&(target, edge)| {
+                edge == Edge::Call && self.changes_index(target, &mut BTreeSet::new())
+            });
+            for &(target, edge) in &block.successors {
+                let incoming = if edge == Edge::Call || changed {
+                    Index::Unknown
+                } else {
+                    index
+                };
+                let merged = indexes.get(&target).map_or(incoming, |i| i.merge(incoming));
+                if indexes.insert(target, merged) != Some(merged) {
+                    pending.push(target);
+                }
+            }
+        }
+        indexes
+    }
+
+    /// Whether the subroutine, or those it calls, may change I
+    fn changes_index(&self, entry: usize, seen: &mut BTreeSet<usize>) -> bool {
+        if !seen.insert(entry) {
+            return false;
+        }
+        let body = self.body(entry);
+        let changes = body
+            .iter()
+            .flat_map(|start| &self.blocks[start].instructions);
+        let changes = changes
+            .into_iter()
+            .any(|&address| self.next_index(address, Index::Known(0)) != Index::Known(0));
+        changes
+            || body
+                .iter()
+                .flat_map(|start| &self.blocks[start].successors)
+                .any(|&(target, edge)| edge == Edge::Call && self.changes_index(target, seen))
+    }
+
+    fn next_index(&self, address: usize, index: Index) -> Index {
+        let opcode = self.opcode_at(address);
+        match (opcode >> 12, opcode & 0xff) {
+            (0xa, _) => Index::Known((opcode & 0xfff) as usize),
+            _ if opcode == 0xf000 => Index::Known(self.opcode_at(address + 2) as usize),
+            // FX55 and FX65 move I with some quirks
+            (0xf, 0x1e | 0x29 | 0x30 | 0x55 | 0x65) => Index::Unknown,
+            _ => index,
+        }
+    }
+
+    /// Marks what I points at when instructions use it: sprites, data, or code being overwritten
+    fn find_data(&mut self) {
+        let indexes = self.indexes();
+        let starts: Vec<usize> = self.blocks.keys().copied().collect();
+        for start in starts {
+            let mut index = indexes.get(&start).copied().unwrap_or(Index::Unknown);
+            for address in self.blocks[&start].instructions.clone() {
+                if let Index::Known(i) = index {
+                    self.use_index(address, i);
+                }
+                index = self.next_index(address, index);
+            }
+        }
+    }
+
+    fn use_index(&mut self, address: usize, i: usize) {
+        let opcode = self.opcode_at(address);
+        let x = ((opcode >> 8) & 0xf) as usize;
+        let y = ((opcode >> 4) & 0xf) as usize;
+        let (range, kind, writes) = match (opcode >> 12, opcode & 0xf, opcode & 0xff) {
+            // DXY0 draws 16x16 sprites on SUPER-CHIP
+            (0xd, 0, _) => (i..i + 32, Kind::Sprite, false),
+            (0xd, n, _) => (i..i + n as usize, Kind::Sprite, false),
+            (0x5, 2 | 3, _) => (i..i + x.abs_diff(y) + 1, Kind::Data, opcode & 0xf == 2),
+            (0xf, _, 0x33) => (i..i + 3, Kind::Data, true),
+            (0xf, _, 0x55) => (i..i + x + 1, Kind::Data, true),
+            (0xf, _, 0x65) => (i..i + x + 1, Kind::Data, false),
+            _ => return,
+        };
+        if writes && range.clone().any(|a| self.kind(a) == Some(Kind::Code)) {
+            self.self_modifying.push((address, range.clone()));
+        }
+        for a in range {
+            self.set_kind(a, kind);
+        }
+    }
+
+    /// Blocks the code from the entry runs, without following calls
+    pub fn body(&self, entry: usize) -> BTreeSet<usize> {
+        let mut body = BTreeSet::from([entry]);
+        let mut pending = vec![entry];
+        while let Some(start) = pending.pop() {
+            for &(target, edge) in &self.blocks[&start].successors {
+                if edge != Edge::Call && body.insert(target) {
+                    pending.push(target);
+                }
+            }
+        }
+        body
+    }
+
+    /// Subroutines the code from the entry calls directly
+    fn callees(&self, entry: usize) -> BTreeSet<usize> {
+        let body = self.body(entry);
+        let successors = body.iter().flat_map(|start| &self.blocks[start].successors);
+        successors
+            .filter(|(_, edge)| *edge == Edge::Call)
+            .map(|(target, _)| *target)
+            .collect()
+    }
+
+    fn call_depth(&self) -> CallDepth {
+        let mut depths = HashMap::new();
+        match self.depth(self.rom.start, &mut depths, &mut Vec::new()) {
+            Ok(depth) => CallDepth::Bounded(depth),
+            Err(recursive) => CallDepth::Recursive(recursive),
+        }
+    }
+
+    /// Deepest nesting of calls from the entry, or the subroutine that recurses
+    fn depth(
+        &self,
+        entry: usize,
+        depths: &mut HashMap<usize, usize>,
+        calling: &mut Vec<usize>,
+    ) -> Result<usize, usize> {
+        if let Some(&depth) = depths.get(&entry) {
+            return Ok(depth);
+        }
+        if calling.contains(&entry) {
+            return Err(entry);
+        }
+        calling.push(entry);
+        let mut depth = 0;
+        for callee in self.callees(entry) {
+            depth = depth.max(self.depth(callee, depths, calling)? + 1);
+        }
+        calling.pop();
+        depths.insert(entry, depth);
+        Ok(depth)
+    }
+
+    /// Address ranges of the bytes of that kind
+    pub fn ranges(&self, kind: Option<Kind>) -> Vec<Range<usize>> {
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        for (offset, _) in self.kinds.iter().enumerate().filter(|(_, k)| **k == kind) {
+            let address = self.rom.start + offset;
+            match ranges.last_mut() {
+                Some(range) if range.end == address => range.end += 1,
+                _ => ranges.push(address..address + 1),
+            }
+        }
+        ranges
+    }
+
+    /// The control-flow graph in Graphviz DOT, one node per block
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph rom {\n    node [shape=box, fontname=monospace];\n");
+        for (start, block) in &self.blocks {
+            let lines: Vec<String> = block
+                .instructions
+                .iter()
+                .map(|&address| format!("0x{:03X}  {}\\l", address, self.mnemonic(address)))
+                .collect();
+            dot += &format!("    b{:03X} [label=\"{}\"];\n", start, lines.concat());
+        }
+        for (start, block) in &self.blocks {
+            for (target, edge) in &block.successors {
+                let style = match edge {
+                    Edge::Next => "",
+                    Edge::Jump => " [label=jump]",
+                    Edge::Skip => " [label=skip]",
+                    Edge::Call => " [label=call, style=dashed]",
+                    Edge::Computed => " [label=computed, style=dotted, color=red]",
+                };
+                dot += &format!("    b{:03X} -> b{:03X}{};\n", start, target, style);
+            }
+        }
+        dot + "}\n"
+    }
+
+    fn mnemonic(&self, address: usize) -> String {
+        let opcode = self.opcode_at(address);
+        if opcode == 0xf000 {
+            format!("LD I, 0x{:04X}", self.opcode_at(address + 2))
+        } else {
+            disassemble(opcode)
+        }
+    }
+}
+
+fn format_ranges(ranges: &[Range<usize>]) -> String {
+    let ranges: Vec<String> = ranges
+        .iter()
+        .map(|range| match range.len() {
+            1 => format!("0x{:03X}", range.start),
+            _ => format!("0x{:03X}-0x{:03X}", range.start, range.end - 1),
+        })
+        .collect();
+    ranges.join(", ")
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let instructions: usize = self.blocks.values().map(|b| b.instructions.len()).sum();
+        writeln!(
+            f,
+            "Code: {} ({} instructions in {} blocks, subroutines: {})",
+            match self.blocks.is_empty() {
+                true => String::from("none"),
+                false => format_ranges(&self.ranges(Some(Kind::Code))),
+            },
+            instructions,
+            self.blocks.len(),
+            self.subroutines.len()
+        )?;
+        let other = [
+            ("Sprites", Some(Kind::Sprite)),
+            ("Data", Some(Kind::Data)),
+            ("Unreached", None),
+        ];
+        for (name, kind) in other {
+            let ranges = self.ranges(kind);
+            if !ranges.is_empty() {
+                writeln!(f, "{}: {}", name, format_ranges(&ranges))?;
+            }
+        }
+
+        let at = |address: &usize| format!("{} at 0x{:03X}", self.mnemonic(*address), address);
+        if self.extensions.is_empty() {
+            writeln!(f, "Platform: CHIP-8")?;
+        }
+        for (platform, addresses) in &self.extensions {
+            let first: Vec<String> = addresses.iter().take(3).map(at).collect();
+            let more = match addresses.len() {
+                n if n > 3 => format!(" and {} more", n - 3),
+                _ => String::new(),
+            };
+            writeln!(f, "Uses {}: {}{}", platform, first.join(", "), more)?;
+        }
+        if !self.quirks.is_empty() {
+            let quirks: Vec<String> = self
+                .quirks
+                .iter()
+                .map(|(quirk, address)| format!("{} ({})", quirk, at(address)))
+                .collect();
+            writeln!(f, "Quirks that matter: {}", quirks.join(", "))?;
+        }
+        for address in &self.computed_jumps {
+            writeln!(f, "Computed jump: {}, targets are guessed", at(address))?;
+        }
+        for address in &self.outside {
+            writeln!(f, "Leaves the ROM: {}", at(address))?;
+        }
+        for (address, range) in &self.self_modifying {
+            writeln!(
+                f,
+                "Self-modifying code: {} writes {}",
+                at(address),
+                format_ranges(std::slice::from_ref(range))
+            )?;
+        }
+        match self.call_depth {
+            CallDepth::Bounded(depth) if depth > STACK_SIZE => write!(
+                f,
+                "Stack overflow: calls nest {} deep, the stack holds {}",
+                depth, STACK_SIZE
+            ),
+            CallDepth::Bounded(depth) => {
+                write!(f, "Call depth: {} (the stack holds {})", depth, STACK_SIZE)
+            }
+            CallDepth::Recursive(address) => write!(
+                f,
+                "Possible stack overflow: the subroutine at 0x{:03X} can call itself",
+                address
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octo;
+
+    #[test]
+    fn builds_the_control_flow_graph() {
+        let rom = octo::assemble(
+            ": main
+                i := score  bcd v0
+                i := ball
+                v0 := 0
+                loop
+                    sprite v0 v0 3
+                    if v0 == 8 then wait
+                    v0 += 1
+                again
+            : wait
+                v1 := key
+                ;
+            : ball  0x40 0xE0 0x40
+            : score  0 0 0",
+        )
+        .unwrap();
+        let analysis = analyze(&rom, 0x200);
+        let starts: Vec<usize> = analysis.blocks.keys().copied().collect();
+        assert_eq!(starts, [0x200, 0x208, 0x20C, 0x20E, 0x212]);
+        assert_eq!(
+            analysis.blocks[&0x208].successors,
+            [(0x20C, Edge::Next), (0x20E, Edge::Skip)]
+        );
+        assert_eq!(
+            analysis.blocks[&0x20C].successors,
+            [(0x212, Edge::Call), (0x20E, Edge::Next)]
+        );
+        assert_eq!(analysis.blocks[&0x20E].successors, [(0x208, Edge::Jump)]);
+        assert_eq!(analysis.subroutines, BTreeSet::from([0x212]));
+        // The subroutine leaves I alone, so the loop always draws the ball
+        let ranges = |kind| format_ranges(&analysis.ranges(kind));
+        assert_eq!(ranges(Some(Kind::Code)), "0x200-0x215");
+        assert_eq!(ranges(Some(Kind::Sprite)), "0x216-0x218");
+        assert_eq!(ranges(Some(Kind::Data)), "0x219-0x21B");
+        assert_eq!(ranges(None), "");
+        assert_eq!(analysis.call_depth, CallDepth::Bounded(1));
+        assert_eq!(
+            analysis.quirks,
+            BTreeMap::from([("key_wait_release", 0x212)])
+        );
+
+        let dot = analysis.to_dot();
+        assert!(
+            dot.contains("    b208 [label=\"0x208  DRW V0, V0, 3\\l0x20A  SNE V0, 0x08\\l\"];\n")
+        );
+        assert!(dot.contains("    b20C -> b212 [label=call, style=dashed];\n"));
+    }
+
+    #[test]
+    fn reports_what_the_rom_needs() {
+        let rom = octo::assemble(
+            ": main
+                hires
+                v1 >>= v2
+                i := main  save v0
+                jump0 table
+            : table
+                jump recurse
+                jump main
+            : recurse
+                recurse
+                ;",
+        )
+        .unwrap();
+        let analysis = analyze(&rom, 0x200);
+        assert_eq!(analysis.extensions["SUPER-CHIP"], [0x200]);
+        assert_eq!(analysis.self_modifying, [(0x206, 0x200..0x201)]);
+        assert_eq!(analysis.computed_jumps, BTreeSet::from([0x208]));
+        assert_eq!(
+            analysis.blocks[&0x200].successors,
+            [(0x20A, Edge::Computed), (0x20C, Edge::Computed)]
+        );
+        assert_eq!(analysis.call_depth, CallDepth::Recursive(0x20E));
+        let report = analysis.to_string();
+        assert!(report.contains("Uses SUPER-CHIP: HIGH at 0x200\n"));
+        assert!(report.contains(
+            "Quirks that matter: jump_vx (JP V0, 0x20A at 0x208), memory_increment (LD [I], V0 at 0x206), shift_vy (SHR V1, V2 at 0x202)\n"
+        ));
+        assert!(report.ends_with("the subroutine at 0x20E can call itself"));
+    }
+
+    #[test]
+    fn analyzes_roms_without_instructions() {
+        for (rom, load_address) in [(&[][..], 0x200), (&[0x00], 0x200), (&[0x12, 0x00], 0xfff)] {
+            let analysis = analyze(rom, load_address);
+            assert!(analysis.blocks.is_empty());
+            assert_eq!(analysis.call_depth, CallDepth::Bounded(0));
+            analysis.to_string();
+            analysis.to_dot();
+        }
+    }
+}
@@ -0,0 +1,348 @@
+// Audio outputs for the buzzer.
+// Reference: https://nukep.github.io/glium-sdl2/sdl2/audio/index.html
+
+use crate::{
+    speaker::{Tone, ToneConfig},
+    wav::WavWriter,
+};
+use sdl2::{
+    audio::{AudioCallback, AudioDevice, AudioSpecDesired},
+    Sdl,
+};
+#[cfg(test)]
+use std::{cell::RefCell, rc::Rc};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+// Sample rate of the sinks following emulated time, 735 samples per 60Hz tick
+pub const FRAME_SAMPLE_RATE: u32 = 44100;
+const SAMPLES_PER_FRAME: u32 = FRAME_SAMPLE_RATE / 60;
+
+/// Somewhere the buzzer can be played
+pub trait AudioSink {
+    /// Plays the buzzer for the given number of 60Hz ticks, or stops it when 0
+    fn set_sound_timer(&mut self, ticks: u8);
+    /// Keeps the buzzer playing for at least one more tick
+    fn sustain(&mut self);
+    /// Called once per emulated frame
+    fn end_frame(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+    fn set_volume(&mut self, volume: f32);
+    fn set_muted(&mut self, muted: bool);
+}
+
+/// Plays through an SDL audio device, following the wall clock
+pub struct SdlSink {
+    device: AudioDevice<SdlCallback>,
+    remaining: Arc<AtomicU32>,
+    // Number of samples for one tick of the 60Hz sound timer
+    samples_per_tick: u32,
+}
+
+struct SdlCallback {
+    tone: Tone,
+    // Samples of tone left to play, set by the emulator and counted down here
+    remaining: Arc<AtomicU32>,
+}
+
+impl AudioCallback for SdlCallback {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        let remaining = self.remaining.load(Ordering::Acquire);
+        let left = self.tone.fill(out, remaining);
+        // If the emulator changed the sound timer meanwhile, its new value wins
+        let _ =
+            self.remaining
+                .compare_exchange(remaining, left, Ordering::AcqRel, Ordering::Relaxed);
+    }
+}
+
+impl SdlSink {
+    pub fn open(sdl: &Sdl, config: ToneConfig) -> Result<Self, String> {
+        let audio_subsystem = sdl.audio()?;
+
+        let desired_spec = AudioSpecDesired {
+            freq: Some(44100),
+            channels: Some(1),  // mono
+            samples: Some(512), // small buffers, so a new sound starts quickly
+        };
+
+        let remaining = Arc::new(AtomicU32::new(0));
+        let device = audio_subsystem.open_playback(None, &desired_spec, |spec| SdlCallback {
+            tone: Tone::new(config, spec.freq as u32),
+            remaining: remaining.clone(),
+        })?;
+        let samples_per_tick = device.spec().freq as u32 / 60;
+
+        // The device keeps running, the callback itself plays the tone for as long as the sound timer lasts
+        device.resume();
+
+        Ok(SdlSink {
+            device,
+            remaining,
+            samples_per_tick,
+        })
+    }
+}
+
+impl AudioSink for SdlSink {
+    fn set_sound_timer(&mut self, ticks: u8) {
+        self.remaining
+            .store(ticks as u32 * self.samples_per_tick, Ordering::Release);
+    }
+
+    fn sustain(&mut self) {
+        self.remaining
+            .fetch_max(self.samples_per_tick, Ordering::AcqRel);
+    }
+
+    fn set_volume(&mut self, volume: f32) {
+        self.device.lock().tone.set_volume(volume);
+    }
+
+    fn set_muted(&mut self, muted: bool) {
+        self.device.lock().tone.set_muted(muted);
+    }
+}
+
+/// Discards everything, for machines without an audio device
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn set_sound_timer(&mut self, _ticks: u8) {}
+    fn sustain(&mut self) {}
+    fn set_volume(&mut self, _volume: f32) {}
+    fn set_muted(&mut self, _muted: bool) {}
+}
+
+/// Renders the buzzer one emulated frame at a time, following emulated time rather than the wall clock
+struct FrameRenderer {
+    tone: Tone,
+    // Samples of tone left to play
+    remaining: u32,
+}
+
+impl FrameRenderer {
+    fn new(config: ToneConfig) -> Self {
+        FrameRenderer {
+            tone: Tone::new(config, FRAME_SAMPLE_RATE),
+            remaining: 0,
+        }
+    }
+
+    fn set_sound_timer(&mut self, ticks: u8) {
+        self.remaining = ticks as u32 * SAMPLES_PER_FRAME;
+    }
+
+    fn sustain(&mut self) {
+        self.remaining = self.remaining.max(SAMPLES_PER_FRAME);
+    }
+
+    fn render(&mut self) -> [f32; SAMPLES_PER_FRAME as usize] {
+        let mut samples = [0.0; SAMPLES_PER_FRAME as usize];
+        self.remaining = self.tone.fill(&mut samples, self.remaining);
+        samples
+    }
+}
+
+/// Keeps the samples in memory, for tests.
+/// Clones share them, to read them once a speaker owns the sink.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct CaptureSink {
+    renderer: Rc<RefCell<FrameRenderer>>,
+    samples: Rc<RefCell<Vec<f32>>>,
+}
+
+#[cfg(test)]
+impl CaptureSink {
+    pub fn new(config: ToneConfig) -> Self {
+        CaptureSink {
+            renderer: Rc::new(RefCell::new(FrameRenderer::new(config))),
+            samples: Rc::default(),
+        }
+    }
+
+    /// Samples at FRAME_SAMPLE_RATE, one 60th of a second per emulated frame
+    pub fn samples(&self) -> Vec<f32> {
+        self.samples.borrow().clone()
+    }
+}
+
+#[cfg(test)]
+impl AudioSink for CaptureSink {
+    fn set_sound_timer(&mut self, ticks: u8) {
+        self.renderer.borrow_mut().set_sound_timer(ticks);
+    }
+
+    fn sustain(&mut self) {
+        self.renderer.borrow_mut().sustain();
+    }
+
+    fn end_frame(&mut self) -> io::Result<()> {
+        let samples = self.renderer.borrow_mut().render();
+        self.samples.borrow_mut().extend_from_slice(&samples);
+        Ok(())
+    }
+
+    fn set_volume(&mut self, volume: f32) {
+        self.renderer.borrow_mut().tone.set_volume(volume);
+    }
+
+    fn set_muted(&mut self, muted: bool) {
+        self.renderer.borrow_mut().tone.set_muted(muted);
+    }
+}
+
+/// Writes the samples to a WAV file, one 60th of a second per emulated frame
+pub struct WavSink {
+    renderer: FrameRenderer,
+    wav: WavWriter,
+}
+
+impl WavSink {
+    pub fn create(path: &str, config: ToneConfig) -> io::Result<Self> {
+        Ok(WavSink {
+            renderer: FrameRenderer::new(config),
+            wav: WavWriter::create(path, FRAME_SAMPLE_RATE)?,
+        })
+    }
+
+    /// Completes the WAV header
+    pub fn finish(self) -> io::Result<()> {
+        self.wav.finish()
+    }
+}
+
+impl AudioSink for WavSink {
+    fn set_sound_timer(&mut self, ticks: u8) {
+        self.renderer.set_sound_timer(ticks);
+    }
+
+    fn sustain(&mut self) {
+        self.renderer.sustain();
+    }
+
+    fn end_frame(&mut self) -> io::Result<()> {
+        self.wav.write(&self.renderer.render())
+    }
+
+    fn set_volume(&mut self, volume: f32) {
+        self.renderer.tone.set_volume(volume);
+    }
+
+    fn set_muted(&mut self, muted: bool) {
+        self.renderer.tone.set_muted(muted);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chip8::Chip8, speaker::Speaker};
+    use std::{fs, process};
+
+    #[test]
+    fn short_beep_lasts_one_tick() {
+        let remaining = Arc::new(AtomicU32::new(0));
+        let mut callback = SdlCallback {
+            tone: Tone::new(ToneConfig::default(), 44100),
+            remaining: remaining.clone(),
+        };
+
+        // Sound timer = 1, set in the middle of a buffer: 735 samples at 44.1kHz
+        let mut out = [0.0; 512];
+        callback.callback(&mut out);
+        remaining.store(735, Ordering::Release);
+        let mut played = Vec::new();
+        for _ in 0..4 {
+            callback.callback(&mut out);
+            played.extend_from_slice(&out);
+        }
+
+        assert_eq!(remaining.load(Ordering::Acquire), 0);
+        assert_eq!(played[734].abs(), 0.25);
+        // Silent again once the ramp (5ms = 220 samples) is over
+        assert!(played[735] != 0.0);
+        assert!(played[735 + 221..].iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn capture_follows_frames() {
+        let mut sink = CaptureSink::new(ToneConfig::default());
+
+        // Silent frame, then a 2 ticks beep, then silence
+        sink.end_frame().unwrap();
+        sink.set_sound_timer(2);
+        for _ in 0..4 {
+            sink.end_frame().unwrap();
+        }
+
+        let samples = sink.samples();
+        assert_eq!(samples.len(), 5 * 735);
+        assert!(samples[..735].iter().all(|&x| x == 0.0));
+        assert!(samples[735..3 * 735].iter().any(|&x| x != 0.0));
+        assert!(samples[3 * 735 + 221..].iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn wav_matches_capture() {
+        let path = std::env::temp_dir().join(format!("chip8-audio-{}.wav", process::id()));
+        let mut wav = WavSink::create(path.to_str().unwrap(), ToneConfig::default()).unwrap();
+        let mut capture = CaptureSink::new(ToneConfig::default());
+        let sinks: [&mut dyn AudioSink; 2] = [&mut wav, &mut capture];
+        for sink in sinks {
+            sink.set_sound_timer(1);
+            sink.end_frame().unwrap();
+            sink.end_frame().unwrap();
+        }
+        wav.finish().unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let samples: Vec<i16> = bytes[44..]
+            .chunks(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        assert_eq!(samples.len(), capture.samples().len());
+        assert!(samples[..735].iter().any(|&x| x != 0));
+    }
+
+    #[test]
+    fn rom_beeps_at_the_right_frames() {
+        // LD V0, 3; LD ST, V0; LD V1, 6; LD DT, V1
+        // LD V1, DT; SE V1, 0; JP 0x208; LD ST, V0; JP 0x210
+        let program = [
+            0x60, 0x03, 0xf0, 0x18, 0x61, 0x06, 0xf1, 0x15, 0xf1, 0x07, 0x31, 0x00, 0x12, 0x08,
+            0xf0, 0x18, 0x12, 0x10,
+        ];
+        let mut chip8 = Chip8::from_program(&program);
+        let capture = CaptureSink::new(ToneConfig::default());
+        let mut speaker = Speaker::new(Box::new(capture.clone()), ToneConfig::default());
+        for _ in 0..12 {
+            for _ in 0..10 {
+                chip8.cycle();
+            }
+            chip8.decrement_timers();
+            chip8.handle_sound(&mut speaker);
+            speaker.end_frame().unwrap();
+        }
+
+        // Beeping frames, leaving out the ramp down at the start of the next one
+        let samples = capture.samples();
+        let beeping: Vec<usize> = (0..12)
+            .filter(|frame| {
+                let frame = &samples[frame * 735..(frame + 1) * 735];
+                frame[221..].iter().any(|&x| x != 0.0)
+            })
+            .collect();
+        assert_eq!(beeping, [0, 1, 2, 6, 7, 8]);
+    }
+}
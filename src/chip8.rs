@@ -1,12 +1,18 @@
-use crate::{display::Display, font::*, keypad::Keypad, speaker::Speaker};
-use rand::Rng;
-use sdl2::Sdl;
-use std::{fs::File, io::Read};
+use crate::{
+    font::*,
+    framebuffer::Framebuffer,
+    keypad::Keypad,
+    quirks::Quirks,
+    rng::RandomSource,
+    rom::{self, RomError},
+    speaker::Speaker,
+    state::SaveState,
+};
 
 // Chip8 has 4KB of RAM
-const MEMORY_SIZE: usize = 4096;
+pub const MEMORY_SIZE: usize = 4096;
 // Chip8's memory from 0x000 to 0x1FF is reserved, so the ROM instructions must start at 0x200
-const START_ALLOWED_ADDRESS: usize = 0x200;
+pub const START_ALLOWED_ADDRESS: usize = 0x200;
 
 pub struct Chip8 {
     // Program counter
@@ -22,14 +28,61 @@ pub struct Chip8 {
     // Timers
     delay_timer: u8,
     sound_timer: u8,
+    // Value the sound timer was last set to, until it is passed to the speaker
+    sound_timer_write: Option<u8>,
     // Peripherals
-    pub display: Display,
+    pub framebuffer: Framebuffer,
     pub keypad: Keypad,
-    speaker: Speaker,
+    quirks: Quirks,
+    // Random source for CXKK, seeded so that runs can be replayed
+    rng: Box<dyn RandomSource>,
+    // Loaded ROM bytes, and where they are in memory
+    rom: Vec<u8>,
+    load_address: usize,
+}
+
+/// Code watching the instructions as they run, such as debuggers
+pub trait Hook {
+    /// Called before the instruction at the address runs, returns false to stop before it
+    fn before_execute(&mut self, _chip8: &Chip8, _address: usize, _opcode: u16) -> bool {
+        true
+    }
+
+    /// Called once the instruction at the address ran
+    fn after_execute(&mut self, _chip8: &Chip8, _address: usize, _opcode: u16) {}
+}
+
+// No hook
+impl Hook for () {}
+
+impl<T: Hook> Hook for Option<T> {
+    fn before_execute(&mut self, chip8: &Chip8, address: usize, opcode: u16) -> bool {
+        self.as_mut()
+            .is_none_or(|hook| hook.before_execute(chip8, address, opcode))
+    }
+
+    fn after_execute(&mut self, chip8: &Chip8, address: usize, opcode: u16) {
+        if let Some(hook) = self {
+            hook.after_execute(chip8, address, opcode);
+        }
+    }
+}
+
+// Both hooks, the second one only sees the instructions the first one lets run
+impl<A: Hook, B: Hook> Hook for (&mut A, &mut B) {
+    fn before_execute(&mut self, chip8: &Chip8, address: usize, opcode: u16) -> bool {
+        self.0.before_execute(chip8, address, opcode)
+            && self.1.before_execute(chip8, address, opcode)
+    }
+
+    fn after_execute(&mut self, chip8: &Chip8, address: usize, opcode: u16) {
+        self.0.after_execute(chip8, address, opcode);
+        self.1.after_execute(chip8, address, opcode);
+    }
 }
 
 impl Chip8 {
-    pub fn new(sdl: &Sdl, window_title: &str) -> Self {
+    pub fn new(quirks: Quirks, rng: Box<dyn RandomSource>) -> Self {
         Chip8 {
             pc: START_ALLOWED_ADDRESS,
             v: [0; 16],
@@ -39,40 +92,234 @@ impl Chip8 {
             memory: Self::init_memory(),
             delay_timer: 0,
             sound_timer: 0,
-            display: Display::new(sdl, window_title),
+            sound_timer_write: None,
+            framebuffer: Framebuffer::new(),
             keypad: Keypad::new(),
-            speaker: Speaker::new(sdl),
+            quirks,
+            rng,
+            rom: Vec::new(),
+            load_address: START_ALLOWED_ADDRESS,
         }
     }
 
+    /// Machine running the program from 0x200, with the default quirks and a fixed random seed,
+    /// e.g. for tests. Panics if the program doesn't fit in memory.
+    pub fn from_program(program: &[u8]) -> Self {
+        let mut chip8 = Chip8::new(
+            Quirks::default(),
+            Box::new(crate::rng::SeededRandom::new(0)),
+        );
+        chip8
+            .load_rom(program.to_vec(), START_ALLOWED_ADDRESS)
+            .unwrap();
+        chip8
+    }
+
     /// Returns a fresh memory with loaded font set
     fn init_memory() -> [u8; MEMORY_SIZE] {
         let mut memory = [0; MEMORY_SIZE];
-        for i in 0..FONT_SET.len() {
-            memory[i] = FONT_SET[i];
-        }
+        memory[..FONT_SET.len()].copy_from_slice(&FONT_SET);
         memory
     }
 
-    pub fn load_rom(&mut self, rom_path: &str) {
-        let mut f = File::open(rom_path).expect("Rom file not found");
-        let mut buffer = Vec::<u8>::new();
-        f.read_to_end(&mut buffer).unwrap();
+    /// Loads the ROM at the given address (0x200, or 0x600 for the ETI 660) and starts running it from there
+    pub fn load_rom(&mut self, rom: Vec<u8>, load_address: usize) -> Result<(), RomError> {
+        Self::check_rom(&rom, load_address)?;
+        self.rom = rom;
+        self.load_address = load_address;
+        self.restore_program();
+        self.pc = load_address;
+        Ok(())
+    }
+
+    fn check_rom(rom: &[u8], load_address: usize) -> Result<(), RomError> {
+        if !(START_ALLOWED_ADDRESS..MEMORY_SIZE).contains(&load_address) {
+            return Err(RomError::InvalidLoadAddress(load_address));
+        }
+        if rom.is_empty() {
+            return Err(RomError::Empty);
+        }
+        let max = MEMORY_SIZE - load_address;
+        if rom.len() > max {
+            return Err(RomError::TooLarge {
+                size: rom.len(),
+                max,
+                platform: rom::detect_platform(rom),
+            });
+        }
+        Ok(())
+    }
+
+    /// Injects the ROM into memory again, e.g. over a state saved with a previous build of the ROM
+    pub fn restore_program(&mut self) {
+        let end = self.load_address + self.rom.len();
+        self.memory[self.load_address..end].copy_from_slice(&self.rom);
+    }
+
+    /// Restarts the ROM on a fresh machine: memory, registers, timers and display.
+    /// The quirks, the random source and the keys held down are kept.
+    pub fn reset(&mut self) {
+        self.pc = self.load_address;
+        self.v = [0; 16];
+        self.i = 0;
+        self.stack = [0; 16];
+        self.sp = 0;
+        self.memory = Self::init_memory();
+        self.delay_timer = 0;
+        self.sound_timer = 0;
+        self.sound_timer_write = Some(0);
+        self.framebuffer = Framebuffer::new();
+        self.restore_program();
+    }
+
+    /// Resets the machine with another ROM at the same address, e.g. a new build of the game.
+    /// On error, the current ROM keeps running.
+    pub fn reload(&mut self, rom: Vec<u8>) -> Result<(), RomError> {
+        Self::check_rom(&rom, self.load_address)?;
+        self.rom = rom;
+        self.reset();
+        Ok(())
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// Address of the next instruction
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn v(&self) -> &[u8; 16] {
+        &self.v
+    }
+
+    pub fn i(&self) -> usize {
+        self.i
+    }
+
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+
+    /// Return addresses of the subroutines being run, outermost first
+    pub fn stack(&self) -> &[usize] {
+        &self.stack[..self.sp.min(self.stack.len())]
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+
+    // Setters for debuggers. Those taking addresses return false, changing nothing, if they are out of range.
+
+    pub fn set_v(&mut self, x: usize, value: u8) {
+        self.v[x] = value;
+    }
+
+    pub fn set_i(&mut self, address: u16) {
+        self.i = address as usize % MEMORY_SIZE;
+    }
+
+    pub fn set_pc(&mut self, address: usize) -> bool {
+        let valid = address + 1 < MEMORY_SIZE;
+        if valid {
+            self.pc = address;
+        }
+        valid
+    }
+
+    pub fn set_sp(&mut self, sp: usize) -> bool {
+        let valid = sp <= self.stack.len();
+        if valid {
+            self.sp = sp;
+        }
+        valid
+    }
+
+    pub fn write_memory(&mut self, address: usize, bytes: &[u8]) -> bool {
+        let end = address.checked_add(bytes.len());
+        match end.and_then(|end| self.memory.get_mut(address..end)) {
+            Some(memory) => {
+                memory.copy_from_slice(bytes);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Instruction at the address, None past the end of memory
+    pub fn opcode_at(&self, address: usize) -> Option<u16> {
+        let bytes = self.memory.get(address..address + 2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
 
-        // Inject rom into memory
-        for i in 0..buffer.len() {
-            self.memory[START_ALLOWED_ADDRESS + i] = buffer[i];
+    pub fn save_state(&self) -> SaveState {
+        SaveState {
+            pc: self.pc,
+            v: self.v,
+            i: self.i,
+            stack: self.stack,
+            sp: self.sp,
+            memory: self.memory,
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+            screen: *self.framebuffer.screen(),
+            rng: self.rng.state(),
         }
     }
 
+    pub fn load_state(&mut self, state: &SaveState) {
+        self.pc = state.pc;
+        self.v = state.v;
+        self.i = state.i;
+        self.stack = state.stack;
+        self.sp = state.sp;
+        self.memory = state.memory;
+        self.delay_timer = state.delay_timer;
+        self.sound_timer = state.sound_timer;
+        self.sound_timer_write = Some(state.sound_timer);
+        self.framebuffer.set_screen(state.screen);
+        self.rng.set_state(state.rng);
+    }
+
     /// Cycle = Fetch -> decode -> execute
     pub fn cycle(&mut self) {
+        self.cycle_with(&mut ());
+    }
+
+    /// Runs one cycle with the hook around the instruction, returns whether the hook let it run
+    pub fn cycle_with(&mut self, hook: &mut dyn Hook) -> bool {
+        let address = self.pc;
         // Fetch
         let opcode = self.fetch_opcode();
+        if !hook.before_execute(self, address, opcode) {
+            return false;
+        }
         // Increment the PC before we execute anything
         self.pc += 2;
         // Decode and execute
         self.execute_opcode(opcode);
+        // Addresses are 12 bits, running or skipping past the end of memory wraps around
+        self.pc %= MEMORY_SIZE;
+        hook.after_execute(self, address, opcode);
+        true
     }
 
     pub fn decrement_timers(&mut self) {
@@ -86,11 +333,14 @@ impl Chip8 {
         }
     }
 
-    pub fn handle_sound(&mut self) {
-        if self.sound_timer > 0 {
-            self.speaker.emit_sound();
-        } else {
-            self.speaker.stop_emitting();
+    pub fn handle_sound(&mut self, speaker: &mut Speaker) {
+        // The speaker counts the sound timer down on its own, sample by sample
+        if let Some(ticks) = self.sound_timer_write.take() {
+            speaker.set_sound_timer(ticks);
+        }
+        // The COSMAC VIP also beeps while FX0A holds a pressed key
+        if self.quirks.key_wait_beep && self.keypad.is_waiting_release() {
+            speaker.sustain();
         }
     }
 
@@ -98,7 +348,7 @@ impl Chip8 {
         // Since opcode (instruction) is a group of 2 bytes,
         // we need to fetch each byte from memory according to PC and merge them together.
         // Example: 6A and 12 -> 6A00 | 0012 = 6A12
-        (self.memory[self.pc] as u16) << 8 | (self.memory[self.pc + 1] as u16)
+        (self.memory[self.pc] as u16) << 8 | (self.memory[(self.pc + 1) % MEMORY_SIZE] as u16)
     }
 
     fn execute_opcode(&mut self, opcode: u16) {
@@ -130,12 +380,12 @@ impl Chip8 {
             (0x8, _, _, 0x3) => self.op_8xy3(x, y),
             (0x8, _, _, 0x4) => self.op_8xy4(x, y),
             (0x8, _, _, 0x5) => self.op_8xy5(x, y),
-            (0x8, _, _, 0x6) => self.op_8xy6(x),
+            (0x8, _, _, 0x6) => self.op_8xy6(x, y),
             (0x8, _, _, 0x7) => self.op_8xy7(x, y),
-            (0x8, _, _, 0xe) => self.op_8xye(x),
+            (0x8, _, _, 0xe) => self.op_8xye(x, y),
             (0x9, _, _, 0) => self.op_9xy0(x, y),
             (0xa, _, _, _) => self.op_annn(nnn),
-            (0xb, _, _, _) => self.op_bnnn(nnn),
+            (0xb, _, _, _) => self.op_bnnn(x, nnn),
             (0xc, _, _, _) => self.op_cxkk(x, kk),
             (0xd, _, _, _) => self.op_dxyn(x, y, n),
             (0xe, _, 0x9, 0xe) => self.op_ex9e(x),
@@ -149,17 +399,22 @@ impl Chip8 {
             (0xf, _, 0x3, 0x3) => self.op_fx33(x),
             (0xf, _, 0x5, 0x5) => self.op_fx55(x),
             (0xf, _, 0x6, 0x5) => self.op_fx65(x),
-            _ => panic!("Unrecognized or unsupported opcode: {:#02x}", opcode),
+            // 0NNN machine code routines, and instructions of other platforms, are skipped
+            _ => (),
         };
     }
 
     /// CLS - Clear the display
     fn op_00e0(&mut self) {
-        self.display.clear();
+        self.framebuffer.clear();
     }
 
     /// RET - Return from a subroutine.
+    /// Returning with an empty stack does nothing.
     fn op_00ee(&mut self) {
+        if self.sp == 0 {
+            return;
+        }
         // "Remove" return address from the stack
         self.sp -= 1;
         self.pc = self.stack[self.sp];
@@ -171,7 +426,11 @@ impl Chip8 {
     }
 
     /// CALL - Call subroutine at nnn.
+    /// Calling with a full stack does nothing.
     fn op_2nnn(&mut self, nnn: usize) {
+        if self.sp == self.stack.len() {
+            return;
+        }
         // Save the current PC to go back to where it was when it hit the CALL
         self.stack[self.sp] = self.pc;
         self.sp += 1;
@@ -220,16 +479,26 @@ impl Chip8 {
     /// Set Vx = Vx OR Vy
     fn op_8xy1(&mut self, x: usize, y: usize) {
         self.v[x] |= self.v[y];
+        self.logic_vf_reset();
     }
 
     /// Set Vx = Vx AND Vy
     fn op_8xy2(&mut self, x: usize, y: usize) {
         self.v[x] &= self.v[y];
+        self.logic_vf_reset();
     }
 
     /// Set Vx = Vx XOR Vy
     fn op_8xy3(&mut self, x: usize, y: usize) {
         self.v[x] ^= self.v[y];
+        self.logic_vf_reset();
+    }
+
+    /// The COSMAC VIP resets VF after logic operations
+    fn logic_vf_reset(&mut self) {
+        if self.quirks.vf_reset {
+            self.v[0xf] = 0;
+        }
     }
 
     /// Set Vx = Vx + Vy, set VF = carry
@@ -238,40 +507,49 @@ impl Chip8 {
     fn op_8xy4(&mut self, x: usize, y: usize) {
         // We have a risk of overflow
         let (new_vx, has_overflowed) = self.v[x].overflowing_add(self.v[y]);
-        self.v[0xf] = has_overflowed as u8;
         self.v[x] = new_vx;
+        // The flag is written last, it wins when Vx is VF
+        self.v[0xf] = has_overflowed as u8;
     }
 
     /// Set Vx = Vx - Vy, set VF = NOT borrow.
-    /// If Vx > Vy, then VF is set to 1, otherwise 0. Then Vy is subtracted from Vx, and the results stored in Vx.
+    /// If Vx >= Vy, then VF is set to 1, otherwise 0. Then Vy is subtracted from Vx, and the results stored in Vx.
     fn op_8xy5(&mut self, x: usize, y: usize) {
         // We also have risk of going under 0
-        let (new_vx, has_overflowed) = self.v[x].overflowing_sub(self.v[y]);
-        self.v[0xf] = has_overflowed as u8;
+        let (new_vx, borrowed) = self.v[x].overflowing_sub(self.v[y]);
         self.v[x] = new_vx;
+        self.v[0xf] = !borrowed as u8;
     }
 
     /// Set Vx = Vx SHR 1
     /// If the least-significant bit of Vx is 1, then VF is set to 1, otherwise 0. Then Vx is divided by 2.
-    fn op_8xy6(&mut self, x: usize) {
-        self.v[0xf] = self.v[x] & 0x1; // we only care about last number if it's 1 then 1, else 0
+    fn op_8xy6(&mut self, x: usize, y: usize) {
+        if self.quirks.shift_vy {
+            self.v[x] = self.v[y];
+        }
+        let shifted_out = self.v[x] & 0x1; // we only care about last number if it's 1 then 1, else 0
         self.v[x] >>= 1; // divide by 2
+        self.v[0xf] = shifted_out;
     }
 
     /// Set Vx = Vy - Vx, set VF = NOT borrow
-    /// If Vy > Vx, then VF is set to 1, otherwise 0. Then Vx is subtracted from Vy, and the results stored in Vx.
+    /// If Vy >= Vx, then VF is set to 1, otherwise 0. Then Vx is subtracted from Vy, and the results stored in Vx.
     fn op_8xy7(&mut self, x: usize, y: usize) {
-        let (new_vx, has_overflowed) = self.v[y].overflowing_sub(self.v[x]);
-        self.v[0xf] = has_overflowed as u8;
+        let (new_vx, borrowed) = self.v[y].overflowing_sub(self.v[x]);
         self.v[x] = new_vx;
+        self.v[0xf] = !borrowed as u8;
     }
 
     /// Set Vx = Vx SHL 1
     /// If the most-significant bit of Vx is 1, then VF is set to 1, otherwise to 0.
     /// Then Vx is multiplied by 2.
-    fn op_8xye(&mut self, x: usize) {
-        self.v[0xf] = self.v[x] & 0x80; // 0x80 => 0b10000000
+    fn op_8xye(&mut self, x: usize, y: usize) {
+        if self.quirks.shift_vy {
+            self.v[x] = self.v[y];
+        }
+        let shifted_out = self.v[x] >> 7; // 0b10000000 => 1
         self.v[x] <<= 1; // multiply by 2
+        self.v[0xf] = shifted_out;
     }
 
     /// Skip next instruction if Vx != Vy.
@@ -287,13 +565,19 @@ impl Chip8 {
     }
 
     /// Jump to location nnn + V0.
-    fn op_bnnn(&mut self, nnn: usize) {
-        self.pc = nnn + self.v[0] as usize;
+    /// With the jump quirk, jump to location xnn + Vx instead.
+    fn op_bnnn(&mut self, x: usize, nnn: usize) {
+        let offset = if self.quirks.jump_vx {
+            self.v[x]
+        } else {
+            self.v[0]
+        };
+        self.pc = nnn + offset as usize;
     }
 
     /// Set Vx = random byte AND kk.
     fn op_cxkk(&mut self, x: usize, kk: u8) {
-        let random_byte: u8 = rand::thread_rng().gen();
+        let random_byte = self.rng.next_byte();
         self.v[x] = random_byte & kk;
     }
 
@@ -303,20 +587,27 @@ impl Chip8 {
     /// Sprites are XORed onto the existing screen. If this causes any pixels to be erased, VF is set to 1, otherwise it is set to 0.
     /// If the sprite is positioned so part of it is outside the coordinates of the display, it wraps around to the opposite side of the screen.
     fn op_dxyn(&mut self, x: usize, y: usize, n: usize) {
-        let start = self.i;
-        let end = start + n;
-        let sprite_bytes = &self.memory[start..end];
+        // Sprites at the end of memory continue from its start
+        let mut sprite = [0; 15];
+        for (row, byte) in sprite.iter_mut().enumerate().take(n) {
+            *byte = self.memory[(self.i + row) % MEMORY_SIZE];
+        }
+        let sprite_bytes = &sprite[..n];
 
-        let has_collision = self
-            .display
-            .draw(self.v[x] as usize, self.v[y] as usize, sprite_bytes);
+        let has_collision = self.framebuffer.draw(
+            self.v[x] as usize,
+            self.v[y] as usize,
+            sprite_bytes,
+            self.quirks.clip,
+        );
 
         self.v[0xf] = has_collision as u8;
     }
 
     /// Skip next instruction if key with the value of Vx is pressed.
+    /// Only the low nibble of Vx is used, like the COSMAC VIP.
     fn op_ex9e(&mut self, x: usize) {
-        let key = self.v[x] as usize;
+        let key = (self.v[x] & 0xf) as usize;
         if self.keypad.is_key_pressed(key) {
             self.pc += 2;
         }
@@ -324,7 +615,7 @@ impl Chip8 {
 
     /// Skip next instruction if key with the value of Vx is not pressed.
     fn op_exa1(&mut self, x: usize) {
-        let key = self.v[x] as usize;
+        let key = (self.v[x] & 0xf) as usize;
         if !self.keypad.is_key_pressed(key) {
             self.pc += 2;
         }
@@ -336,16 +627,12 @@ impl Chip8 {
     }
 
     /// Wait for a key press, store the value of the key in Vx.
+    /// Depending on the quirk, the key is registered when pressed or when released (COSMAC VIP).
     fn op_fx0a(&mut self, x: usize) {
-        // Since PC is pre-incremented, here I will only increment PC if I actually have key press
-        self.pc -= 2;
-
-        for i in 0..self.keypad.size() {
-            if self.keypad.is_key_pressed(i) {
-                self.v[x] = i as u8;
-                self.pc += 2;
-                break;
-            }
+        // Since PC is pre-incremented, rewind it so this instruction runs again until we get a key
+        match self.keypad.wait_for_key(self.quirks.key_wait_release) {
+            Some(key) => self.v[x] = key as u8,
+            None => self.pc -= 2,
         }
     }
 
@@ -357,17 +644,19 @@ impl Chip8 {
     /// Set sound timer = Vx.
     fn op_fx18(&mut self, x: usize) {
         self.sound_timer = self.v[x];
+        self.sound_timer_write = Some(self.sound_timer);
     }
 
     /// Set I = I + Vx.
+    /// I is 12 bits wide, it wraps around past the end of memory.
     fn op_fx1e(&mut self, x: usize) {
-        self.i += self.v[x] as usize;
+        self.i = (self.i + self.v[x] as usize) % MEMORY_SIZE;
     }
 
     /// Set I = location of sprite for digit Vx.
     /// We know that each digit takes 5 bytes each
     fn op_fx29(&mut self, x: usize) {
-        let digit = self.v[x] as usize;
+        let digit = (self.v[x] & 0xf) as usize;
         self.i = digit * 5;
     }
 
@@ -376,22 +665,362 @@ impl Chip8 {
     // the tens digit at location I+1, and the ones digit at location I+2.
     fn op_fx33(&mut self, x: usize) {
         let vx = self.v[x];
-        self.memory[self.i] = vx / 100;
-        self.memory[self.i + 1] = (vx / 10) % 10;
-        self.memory[self.i + 2] = vx % 10;
+        let digits = [vx / 100, (vx / 10) % 10, vx % 10];
+        for (offset, digit) in digits.into_iter().enumerate() {
+            self.memory[(self.i + offset) % MEMORY_SIZE] = digit;
+        }
     }
 
     /// Store registers V0 through Vx in memory starting at location I.
     fn op_fx55(&mut self, x: usize) {
         for idx in 0..x + 1 {
-            self.memory[self.i + idx] = self.v[idx];
+            self.memory[(self.i + idx) % MEMORY_SIZE] = self.v[idx];
         }
+        self.memory_increment(x);
     }
 
     /// Read registers V0 through Vx from memory starting at location I.
     fn op_fx65(&mut self, x: usize) {
         for idx in 0..x + 1 {
-            self.v[idx] = self.memory[self.i + idx];
+            self.v[idx] = self.memory[(self.i + idx) % MEMORY_SIZE];
+        }
+        self.memory_increment(x);
+    }
+
+    /// Moves I after FX55 and FX65, depending on the quirks
+    fn memory_increment(&mut self, x: usize) {
+        if self.quirks.memory_increment_by_x {
+            self.i = (self.i + x) % MEMORY_SIZE;
+        } else if self.quirks.memory_increment {
+            self.i = (self.i + x + 1) % MEMORY_SIZE;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{platform::Platform, rng::SeededRandom};
+    use proptest::{collection::vec, prelude::*};
+
+    /// Machine after running the first instructions of the program
+    fn run(program: &[u8], instructions: usize) -> Chip8 {
+        run_with(Quirks::default(), program, instructions)
+    }
+
+    fn run_with(quirks: Quirks, program: &[u8], instructions: usize) -> Chip8 {
+        let mut chip8 = Chip8::from_program(program);
+        chip8.set_quirks(quirks);
+        for _ in 0..instructions {
+            chip8.cycle();
+        }
+        chip8
+    }
+
+    #[test]
+    fn builds_machines_from_programs() {
+        let chip8 = Chip8::from_program(&[0x60, 0x05, 0x70, 0x03]);
+        assert_eq!(chip8.pc(), START_ALLOWED_ADDRESS);
+        assert_eq!(&chip8.memory()[0x200..0x204], [0x60, 0x05, 0x70, 0x03]);
+        assert_eq!(chip8.v()[0], 0);
+        assert_eq!(run(&[0x60, 0x05, 0x70, 0x03], 2).v()[0], 8);
+    }
+
+    #[test]
+    fn clears_the_screen() {
+        // LD I, 0 (the font's 0); DRW V0, V0, 5; CLS
+        let chip8 = run(&[0xa0, 0x00, 0xd0, 0x05, 0x00, 0xe0], 2);
+        assert_eq!(chip8.framebuffer.screen()[0][0], 1);
+        let chip8 = run(&[0xa0, 0x00, 0xd0, 0x05, 0x00, 0xe0], 3);
+        assert!(chip8.framebuffer.screen().iter().flatten().all(|&p| p == 0));
+    }
+
+    #[test]
+    fn jumps_calls_and_returns() {
+        // CALL 0x206; JP 0x202; -; LD V0, 1; RET
+        let program = [0x22, 0x06, 0x12, 0x02, 0x00, 0x00, 0x60, 0x01, 0x00, 0xee];
+        let chip8 = run(&program, 1);
+        assert_eq!(
+            (chip8.pc(), chip8.sp(), chip8.stack()),
+            (0x206, 1, &[0x202][..])
+        );
+        let chip8 = run(&program, 3);
+        assert_eq!((chip8.pc(), chip8.sp(), chip8.v()[0]), (0x202, 0, 1));
+        assert_eq!(run(&program, 5).pc(), 0x202);
+    }
+
+    #[test]
+    fn skips_on_comparisons() {
+        let pc = |program: &[u8]| run(program, program.len() / 2).pc();
+        // SE V0, kk and SNE V0, kk with V0 = 5
+        assert_eq!(pc(&[0x60, 0x05, 0x30, 0x05]), 0x206);
+        assert_eq!(pc(&[0x60, 0x05, 0x30, 0x06]), 0x204);
+        assert_eq!(pc(&[0x60, 0x05, 0x40, 0x06]), 0x206);
+        assert_eq!(pc(&[0x60, 0x05, 0x40, 0x05]), 0x204);
+        // SE V0, V1 and SNE V0, V1
+        assert_eq!(pc(&[0x60, 0x05, 0x61, 0x05, 0x50, 0x10]), 0x208);
+        assert_eq!(pc(&[0x60, 0x05, 0x61, 0x06, 0x50, 0x10]), 0x206);
+        assert_eq!(pc(&[0x60, 0x05, 0x61, 0x06, 0x90, 0x10]), 0x208);
+        assert_eq!(pc(&[0x60, 0x05, 0x61, 0x05, 0x90, 0x10]), 0x206);
+    }
+
+    #[test]
+    fn loads_and_adds_bytes() {
+        // LD VF, 7; LD V0, 0xFF; ADD V0, 2 wraps around without touching VF
+        let chip8 = run(&[0x6f, 0x07, 0x60, 0xff, 0x70, 0x02], 3);
+        assert_eq!((chip8.v()[0], chip8.v()[0xf]), (0x01, 0x07));
+    }
+
+    #[test]
+    fn runs_logic_instructions() {
+        let v0 = |opcode: u8| {
+            // LD V0, 0x0F; LD V1, 0x3C; LD VF, 5; 8 0 1 opcode
+            let program = [0x60, 0x0f, 0x61, 0x3c, 0x6f, 0x05, 0x80, 0x10 | opcode];
+            run(&program, 4).v()[0]
+        };
+        assert_eq!(v0(0x0), 0x3c);
+        assert_eq!(v0(0x1), 0x3f);
+        assert_eq!(v0(0x2), 0x0c);
+        assert_eq!(v0(0x3), 0x33);
+
+        // OR resets VF on the COSMAC VIP only
+        let program = [0x6f, 0x05, 0x80, 0x11];
+        assert_eq!(run(&program, 2).v()[0xf], 5);
+        let vip = Platform::OriginalChip8.quirks();
+        assert_eq!(run_with(vip, &program, 2).v()[0xf], 0);
+    }
+
+    #[test]
+    fn sets_arithmetic_flags() {
+        // Vx and VF after LD V0, a; LD V1, b; 8 0 1 opcode
+        let result = |a: u8, b: u8, opcode: u8| {
+            let chip8 = run(&[0x60, a, 0x61, b, 0x80, 0x10 | opcode], 3);
+            (chip8.v()[0], chip8.v()[0xf])
+        };
+        assert_eq!(result(0x10, 0x20, 0x4), (0x30, 0));
+        assert_eq!(result(0xff, 0x02, 0x4), (0x01, 1));
+        // VF is NOT borrow
+        assert_eq!(result(0x30, 0x10, 0x5), (0x20, 1));
+        assert_eq!(result(0x10, 0x10, 0x5), (0x00, 1));
+        assert_eq!(result(0x10, 0x20, 0x5), (0xf0, 0));
+        assert_eq!(result(0x10, 0x30, 0x7), (0x20, 1));
+        assert_eq!(result(0x30, 0x10, 0x7), (0xe0, 0));
+
+        // VF as Vx ends up with the flag
+        assert_eq!(run(&[0x6f, 0xff, 0x61, 0x02, 0x8f, 0x14], 3).v()[0xf], 1);
+        assert_eq!(run(&[0x6f, 0x05, 0x61, 0x03, 0x8f, 0x15], 3).v()[0xf], 1);
+        assert_eq!(run(&[0x6f, 0x03, 0x61, 0x05, 0x8f, 0x17], 3).v()[0xf], 1);
+        // VF as Vy is read before it is overwritten
+        let chip8 = run(&[0x60, 0xff, 0x6f, 0x01, 0x80, 0xf4], 3);
+        assert_eq!((chip8.v()[0], chip8.v()[0xf]), (0x00, 1));
+    }
+
+    #[test]
+    fn shifts_out_into_vf() {
+        let result = |quirks: Quirks, vx: u8, opcode: u8| {
+            // LD V0, vx; LD V1, 4; 8 0 1 opcode
+            let chip8 = run_with(quirks, &[0x60, vx, 0x61, 0x04, 0x80, 0x10 | opcode], 3);
+            (chip8.v()[0], chip8.v()[0xf])
+        };
+        let modern = Quirks::default();
+        assert_eq!(result(modern, 0x05, 0x6), (0x02, 1));
+        assert_eq!(result(modern, 0x04, 0x6), (0x02, 0));
+        assert_eq!(result(modern, 0x81, 0xe), (0x02, 1));
+        assert_eq!(result(modern, 0x40, 0xe), (0x80, 0));
+        // The COSMAC VIP shifts Vy
+        let vip = Platform::OriginalChip8.quirks();
+        assert_eq!(result(vip, 0x05, 0x6), (0x02, 0));
+        assert_eq!(result(vip, 0x05, 0xe), (0x08, 0));
+        // VF shifted in place
+        assert_eq!(run(&[0x6f, 0x80, 0x8f, 0xfe], 2).v()[0xf], 1);
+    }
+
+    #[test]
+    fn sets_i_and_jumps_with_offset() {
+        assert_eq!(run(&[0xa1, 0x23], 1).i(), 0x123);
+        // LD V0, 4; LD V3, 8; JP V0, 0x300
+        let program = [0x60, 0x04, 0x63, 0x08, 0xb3, 0x00];
+        assert_eq!(run(&program, 3).pc(), 0x304);
+        // CHIP-48 adds V3, from the high nibble of the address
+        let chip48 = Platform::Chip48.quirks();
+        assert_eq!(run_with(chip48, &program, 3).pc(), 0x308);
+    }
+
+    #[test]
+    fn masks_random_bytes() {
+        assert_eq!(run(&[0xc0, 0x00], 1).v()[0], 0);
+        let chip8 = run(&[0xc0, 0x0f, 0xc1, 0xff], 2);
+        assert!(chip8.v()[0] <= 0x0f);
+        // Seeded, so runs are the same
+        assert_eq!(chip8.v(), run(&[0xc0, 0x0f, 0xc1, 0xff], 2).v());
+    }
+
+    #[test]
+    fn draws_sprites_with_collisions() {
+        // LD V0, 62; LD I, 0 (the font's 0, 0xF0 on top); DRW V0, V1, 5; DRW V0, V1, 5
+        let program = [0x60, 62, 0xa0, 0x00, 0xd0, 0x15, 0xd0, 0x15];
+        let chip8 = run(&program, 3);
+        let top = &chip8.framebuffer.screen()[0];
+        // Wraps around by default
+        assert_eq!((top[62], top[63], top[0], top[1], top[2]), (1, 1, 1, 1, 0));
+        assert_eq!(chip8.v()[0xf], 0);
+        let chip8 = run(&program, 4);
+        assert!(chip8.framebuffer.screen().iter().flatten().all(|&p| p == 0));
+        assert_eq!(chip8.v()[0xf], 1);
+
+        let clipped = run_with(Platform::OriginalChip8.quirks(), &program, 3);
+        assert_eq!(clipped.framebuffer.screen()[0][0], 0);
+    }
+
+    #[test]
+    fn skips_on_keys() {
+        // LD V0, 7; SKP V0 / SKNP V0
+        let pc = |opcode: u8, pressed: bool| {
+            let mut chip8 = Chip8::from_program(&[0x60, 0x07, 0xe0, opcode]);
+            chip8.keypad.set_key(7, pressed);
+            chip8.cycle();
+            chip8.cycle();
+            chip8.pc()
+        };
+        assert_eq!(pc(0x9e, true), 0x206);
+        assert_eq!(pc(0x9e, false), 0x204);
+        assert_eq!(pc(0xa1, false), 0x206);
+        assert_eq!(pc(0xa1, true), 0x204);
+    }
+
+    #[test]
+    fn waits_for_keys() {
+        // LD V3, K
+        let mut chip8 = Chip8::from_program(&[0xf3, 0x0a]);
+        chip8.cycle();
+        assert_eq!(chip8.pc(), 0x200);
+        // Registered once released, by default
+        chip8.keypad.set_key(7, true);
+        chip8.cycle();
+        assert_eq!(chip8.pc(), 0x200);
+        chip8.keypad.set_key(7, false);
+        chip8.cycle();
+        assert_eq!((chip8.pc(), chip8.v()[3]), (0x202, 7));
+    }
+
+    #[test]
+    fn sets_and_reads_timers() {
+        // LD V0, 10; LD DT, V0; LD ST, V0; LD V1, DT
+        let mut chip8 = run(&[0x60, 0x0a, 0xf0, 0x15, 0xf0, 0x18, 0xf1, 0x07], 4);
+        assert_eq!(chip8.v()[1], 10);
+        chip8.decrement_timers();
+        assert_eq!((chip8.delay_timer(), chip8.sound_timer()), (9, 9));
+    }
+
+    #[test]
+    fn adds_to_i_within_memory() {
+        // LD I, nnn; LD V0, 0x10; ADD I, V0
+        assert_eq!(run(&[0xa2, 0x00, 0x60, 0x10, 0xf0, 0x1e], 3).i(), 0x210);
+        assert_eq!(run(&[0xaf, 0xff, 0x60, 0x02, 0xf0, 0x1e], 3).i(), 0x001);
+    }
+
+    #[test]
+    fn points_at_font_digits() {
+        // LD V0, 0xA; LD F, V0
+        let chip8 = run(&[0x60, 0x0a, 0xf0, 0x29], 2);
+        assert_eq!(chip8.i(), 50);
+        assert_eq!(chip8.memory()[chip8.i()], FONT_SET[50]);
+    }
+
+    #[test]
+    fn stores_decimal_digits() {
+        // LD V0, 137; LD I, 0x300; LD B, V0
+        let chip8 = run(&[0x60, 137, 0xa3, 0x00, 0xf0, 0x33], 3);
+        assert_eq!(&chip8.memory()[0x300..0x303], [1, 3, 7]);
+    }
+
+    #[test]
+    fn stores_and_reads_registers() {
+        // LD V0, 1; LD V1, 2; LD V2, 3; LD I, 0x300; LD [I], V2
+        let program = [0x60, 1, 0x61, 2, 0x62, 3, 0xa3, 0x00, 0xf2, 0x55];
+        let chip8 = run(&program, 5);
+        assert_eq!(&chip8.memory()[0x300..0x304], [1, 2, 3, 0]);
+        assert_eq!(chip8.i(), 0x300);
+        let vip = Platform::OriginalChip8.quirks();
+        assert_eq!(run_with(vip, &program, 5).i(), 0x303);
+        assert_eq!(run_with(Platform::Chip48.quirks(), &program, 5).i(), 0x302);
+
+        // LD I, 0x300; LD V1, [I]
+        let mut chip8 = Chip8::from_program(&[0xa3, 0x00, 0xf1, 0x65]);
+        chip8.write_memory(0x300, &[9, 8, 7]);
+        chip8.set_v(2, 0x55);
+        chip8.cycle();
+        chip8.cycle();
+        assert_eq!(&chip8.v()[..3], [9, 8, 0x55]);
+    }
+
+    /// One quirk per bit
+    fn quirks(bits: u8) -> Quirks {
+        let bit = |n: u8| bits >> n & 1 == 1;
+        Quirks {
+            key_wait_release: bit(0),
+            key_wait_beep: bit(1),
+            vf_reset: bit(2),
+            memory_increment: bit(3),
+            memory_increment_by_x: bit(4),
+            shift_vy: bit(5),
+            jump_vx: bit(6),
+            clip: bit(7),
+        }
+    }
+
+    proptest! {
+        // Out of memory reads and stack overflows would panic
+        #[test]
+        fn runs_random_programs(
+            program in vec(any::<u8>(), 1..=MEMORY_SIZE - START_ALLOWED_ADDRESS),
+            // Keys held down, one bit per key, for each frame
+            keys in vec(any::<u16>(), 0..60),
+            quirk_bits in any::<u8>(),
+            seed in any::<u64>(),
+        ) {
+            let mut chip8 = Chip8::new(quirks(quirk_bits), Box::new(SeededRandom::new(seed)));
+            chip8.load_rom(program, START_ALLOWED_ADDRESS).unwrap();
+            for frame in 0..60 {
+                let held = keys.get(frame).copied().unwrap_or(0);
+                for key in 0..16 {
+                    chip8.keypad.set_key(key, held >> key & 1 == 1);
+                }
+                for _ in 0..20 {
+                    chip8.cycle();
+                    prop_assert!(chip8.pc() < MEMORY_SIZE);
+                    prop_assert!(chip8.i() < MEMORY_SIZE);
+                    prop_assert!(chip8.sp() <= chip8.stack.len());
+                }
+                chip8.decrement_timers();
+            }
         }
     }
+
+    #[test]
+    fn stays_within_memory_and_stack() {
+        // DRW and LD B at the end of memory wrap around to its start
+        let mut chip8 = Chip8::from_program(&[0xaf, 0xfe, 0xd0, 0x05, 0xf0, 0x33]);
+        chip8.cycle();
+        chip8.cycle();
+        assert_eq!(chip8.v()[0xf], 0);
+        chip8.cycle();
+        assert_eq!((chip8.memory()[0xffe], chip8.memory()[0]), (0, 0));
+
+        // Running off the end of memory
+        let mut chip8 = Chip8::from_program(&[0x1f, 0xfe]);
+        chip8.write_memory(0xffe, &[0x60, 0x01]);
+        chip8.cycle();
+        chip8.cycle();
+        assert_eq!((chip8.pc(), chip8.v()[0]), (0x000, 1));
+
+        // Returning from nowhere and calling into a full stack do nothing
+        let chip8 = run(&[0x00, 0xee], 1);
+        assert_eq!((chip8.pc(), chip8.sp()), (0x202, 0));
+        let chip8 = run(&[0x22, 0x00], 17);
+        assert_eq!((chip8.pc(), chip8.sp()), (0x202, 16));
+
+        // Unknown instructions are skipped
+        assert_eq!(run(&[0x51, 0x23, 0xff, 0xff], 2).pc(), 0x204);
+    }
 }
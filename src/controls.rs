@@ -0,0 +1,250 @@
+// Emulator hotkeys: reset, pause, frame advance, instruction step, fast-forward, slow motion and speed.
+
+use crate::session::Session;
+use sdl2::keyboard::Keycode;
+use std::time::Duration;
+
+// Time spent running frames between two screen refreshes while fast-forwarding
+pub const FAST_FORWARD_TIME: Duration = Duration::from_millis(12);
+// Slow motion runs one frame out of this many
+const SLOW_MOTION_FACTOR: u32 = 4;
+// Instructions per frame the speed keys go through
+const SPEEDS: [u32; 16] = [
+    1, 2, 3, 5, 7, 10, 12, 15, 20, 30, 50, 100, 200, 500, 1000, 2000,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hotkey {
+    // Restart the ROM on a fresh machine
+    Reset,
+    Pause,
+    // Run one frame while paused
+    AdvanceFrame,
+    // Run one instruction, pausing first
+    Step,
+    // Run as fast as possible while held
+    FastForward,
+    SlowMotion,
+    Faster,
+    Slower,
+}
+
+impl Hotkey {
+    pub fn from_keycode(key: Keycode) -> Option<Self> {
+        match key {
+            Keycode::F2 => Some(Hotkey::Reset),
+            Keycode::P | Keycode::Pause => Some(Hotkey::Pause),
+            Keycode::N => Some(Hotkey::AdvanceFrame),
+            Keycode::F10 => Some(Hotkey::Step),
+            Keycode::Tab => Some(Hotkey::FastForward),
+            Keycode::F3 => Some(Hotkey::SlowMotion),
+            Keycode::Equals | Keycode::Plus | Keycode::KpPlus => Some(Hotkey::Faster),
+            Keycode::Minus | Keycode::KpMinus => Some(Hotkey::Slower),
+            _ => None,
+        }
+    }
+}
+
+/// How many frames to run before the next screen refresh
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pace {
+    Frames(u32),
+    // As many as FAST_FORWARD_TIME allows
+    Unthrottled,
+}
+
+#[derive(Default)]
+pub struct Controls {
+    paused: bool,
+    fast_forward: bool,
+    slow_motion: bool,
+    // A frame to run while paused
+    advance: bool,
+    // Screen refreshes since the last frame run in slow motion
+    slow_motion_wait: u32,
+}
+
+impl Controls {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies a hotkey press, returns a message telling what changed.
+    /// Keys held down repeat only for the speed and stepping keys.
+    /// Movies can't hold resets, steps nor speed changes, so these keys do nothing with one.
+    pub fn press(&mut self, hotkey: Hotkey, repeat: bool, session: &mut Session) -> Option<String> {
+        match hotkey {
+            Hotkey::Reset | Hotkey::Step | Hotkey::Faster | Hotkey::Slower
+                if session.has_movie() =>
+            {
+                let message = "Not while recording or replaying a movie";
+                return (!repeat).then(|| String::from(message));
+            }
+            Hotkey::Reset if !repeat => {
+                session.chip8.reset();
+                return Some(String::from("Reset"));
+            }
+            Hotkey::Pause if !repeat => {
+                self.paused = !self.paused;
+                return Some(String::from(if self.paused { "Paused" } else { "Resumed" }));
+            }
+            Hotkey::AdvanceFrame => {
+                self.paused = true;
+                self.advance = true;
+            }
+            Hotkey::Step => {
+                self.paused = true;
+                session.step();
+            }
+            Hotkey::FastForward => self.fast_forward = true,
+            Hotkey::SlowMotion if !repeat => {
+                self.slow_motion = !self.slow_motion;
+                let state = if self.slow_motion { "on" } else { "off" };
+                return Some(format!("Slow motion {}", state));
+            }
+            Hotkey::Faster | Hotkey::Slower => {
+                session.cycles_per_frame = match hotkey {
+                    Hotkey::Faster => faster(session.cycles_per_frame),
+                    _ => slower(session.cycles_per_frame),
+                };
+                return Some(format!("{} instructions/frame", session.cycles_per_frame));
+            }
+            _ => (),
+        }
+        None
+    }
+
+    /// Pauses without a hotkey, e.g. at a breakpoint
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn release(&mut self, hotkey: Hotkey) {
+        if hotkey == Hotkey::FastForward {
+            self.fast_forward = false;
+        }
+    }
+
+    /// Called once per screen refresh
+    pub fn pace(&mut self) -> Pace {
+        if self.paused {
+            return Pace::Frames(std::mem::take(&mut self.advance) as u32);
+        }
+        if self.fast_forward {
+            return Pace::Unthrottled;
+        }
+        if self.slow_motion {
+            self.slow_motion_wait = (self.slow_motion_wait + 1) % SLOW_MOTION_FACTOR;
+            return Pace::Frames((self.slow_motion_wait == 0) as u32);
+        }
+        Pace::Frames(1)
+    }
+
+    /// Speed and state, such as `15 instructions/frame, paused`
+    pub fn status(&self, cycles_per_frame: u32) -> String {
+        let mut status = format!("{} instructions/frame", cycles_per_frame);
+        if self.paused {
+            status.push_str(", paused");
+        } else if self.fast_forward {
+            status.push_str(", fast-forward");
+        } else if self.slow_motion {
+            status.push_str(", slow motion");
+        }
+        status
+    }
+}
+
+fn faster(cycles_per_frame: u32) -> u32 {
+    let next = SPEEDS.iter().find(|&&speed| speed > cycles_per_frame);
+    *next.unwrap_or(&cycles_per_frame)
+}
+
+fn slower(cycles_per_frame: u32) -> u32 {
+    let next = SPEEDS.iter().rev().find(|&&speed| speed < cycles_per_frame);
+    *next.unwrap_or(&cycles_per_frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chip8::Chip8,
+        debugger::Breakpoints,
+        movie::Movie,
+        speaker::{Speaker, ToneConfig},
+        sprites::SpriteRecorder,
+    };
+
+    fn session(program: &[u8]) -> Session {
+        Session {
+            chip8: Chip8::from_program(program),
+            speaker: Speaker::silent(ToneConfig::default()),
+            replay: None,
+            recorder: None,
+            frame: 0,
+            instructions: 0,
+            cycles_per_frame: 10,
+            breakpoints: Breakpoints::new(),
+            tracer: None,
+            sprites: SpriteRecorder::new(),
+        }
+    }
+
+    #[test]
+    fn changes_speed_in_steps() {
+        assert_eq!(faster(15), 20);
+        assert_eq!(faster(16), 20);
+        assert_eq!(faster(2000), 2000);
+        assert_eq!(slower(15), 12);
+        assert_eq!(slower(1), 1);
+    }
+
+    #[test]
+    fn paces_frames() {
+        let mut controls = Controls::new();
+        assert_eq!(controls.pace(), Pace::Frames(1));
+
+        controls.slow_motion = true;
+        let frames: u32 = (0..8)
+            .map(|_| match controls.pace() {
+                Pace::Frames(frames) => frames,
+                Pace::Unthrottled => panic!("slow motion is throttled"),
+            })
+            .sum();
+        assert_eq!(frames, 2);
+
+        controls.paused = true;
+        controls.advance = true;
+        assert_eq!(controls.pace(), Pace::Frames(1));
+        assert_eq!(controls.pace(), Pace::Frames(0));
+        assert_eq!(controls.status(15), "15 instructions/frame, paused");
+    }
+
+    #[test]
+    fn steps_through_the_hooks() {
+        // LD I, 0x204; DRW V0, V1, 1
+        let mut session = session(&[0xa2, 0x04, 0xd0, 0x11]);
+        session.breakpoints.insert(0x202);
+        let mut controls = Controls::new();
+        controls.press(Hotkey::Step, false, &mut session);
+        // Stepping onto the breakpoint doesn't stop there
+        controls.press(Hotkey::Step, false, &mut session);
+        assert_eq!(session.chip8.pc(), 0x204);
+        assert_eq!(session.instructions, 2);
+        assert_eq!(session.sprites.drawn().len(), 1);
+    }
+
+    #[test]
+    fn keeps_movies_in_sync() {
+        let mut session = session(&[0x70, 0x01]);
+        let movie = "CHIP8-MOVIE 1\nrom ab\nseed 1\n";
+        session.replay = Some(Movie::parse(movie.as_bytes()).unwrap());
+        let mut controls = Controls::new();
+        for hotkey in [Hotkey::Reset, Hotkey::Step, Hotkey::Faster, Hotkey::Slower] {
+            let message = controls.press(hotkey, false, &mut session);
+            assert_eq!(message.as_deref(), Some("Not while recording or replaying a movie"));
+        }
+        assert_eq!(session.chip8.pc(), 0x200);
+        assert_eq!(session.cycles_per_frame, 10);
+    }
+}
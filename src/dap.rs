@@ -0,0 +1,667 @@
+// Debug Adapter Protocol server (`chip8 dap`), for debugging ROMs from editors, over stdin & stdout:
+// https://microsoft.github.io/debug-adapter-protocol/specification
+//
+// The ROM runs headless at 60 frames per second. Breakpoints are set on lines of the Octo source
+// the ROM is assembled from, with the source map of the built-in assembler.
+
+use crate::{
+    chip8::{Chip8, Hook, START_ALLOWED_ADDRESS},
+    debugger::Breakpoints,
+    disassembler::disassemble,
+    launcher,
+    metadata::Database,
+    octo::{self, Program},
+    options::parse_address,
+    rng::{self, SeededRandom},
+    rom, session,
+};
+use serde_json::{json, Value};
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, TryRecvError},
+    thread,
+    time::{Duration, Instant},
+};
+
+const FRAME_TIME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+// The only thread
+const THREAD_ID: u64 = 1;
+// Variables references of the scopes
+const REGISTERS: u64 = 1;
+const TIMERS: u64 = 2;
+const STACK: u64 = 3;
+
+/// Serves one debugging session on stdin & stdout
+pub fn run() -> io::Result<()> {
+    // Requests are read on their own thread, so that a running machine can be paused
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut input = io::stdin().lock();
+        while let Ok(Some(message)) = read_message(&mut input) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut adapter = Adapter::new(io::stdout());
+    loop {
+        let start = Instant::now();
+        let message = if adapter.running {
+            match receiver.try_recv() {
+                Ok(message) => Some(message),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => break,
+            }
+        } else {
+            match receiver.recv() {
+                Ok(message) => Some(message),
+                Err(_) => break,
+            }
+        };
+        if let Some(message) = message {
+            if !adapter.handle(&message)? {
+                break;
+            }
+        }
+        if adapter.running {
+            adapter.run_frame()?;
+            thread::sleep(FRAME_TIME.saturating_sub(start.elapsed()));
+        }
+    }
+    Ok(())
+}
+
+/// Reads a message, None at the end of the input
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+    let Some(length) = length else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "missing Content-Length",
+        ));
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+/// Where the machine runs to, besides breakpoints
+#[derive(Clone, Copy)]
+enum Run {
+    Continue,
+    // Back at the address with no deeper stack, to step over a subroutine call
+    Over { address: usize, sp: usize },
+    // Returned from the current subroutine
+    Out { sp: usize },
+}
+
+/// Stops the machine at breakpoints and at the end of steps
+struct Stops<'a> {
+    breakpoints: &'a mut Breakpoints,
+    run: Run,
+    reason: &'static str,
+}
+
+impl Hook for Stops<'_> {
+    fn before_execute(&mut self, chip8: &Chip8, address: usize, _opcode: u16) -> bool {
+        if self.breakpoints.should_stop(address) {
+            self.reason = "breakpoint";
+            return false;
+        }
+        let arrived = match self.run {
+            Run::Continue => false,
+            Run::Over {
+                address: target,
+                sp,
+            } => address == target && chip8.sp() <= sp,
+            Run::Out { sp } => chip8.sp() < sp,
+        };
+        if arrived {
+            self.reason = "step";
+        }
+        !arrived
+    }
+}
+
+/// The ROM being debugged
+struct Machine {
+    chip8: Chip8,
+    cycles_per_frame: u32,
+    breakpoints: Breakpoints,
+    // Octo source and its map, for source breakpoints and stack frame lines
+    source: Option<(PathBuf, Program)>,
+    // Addresses of the source breakpoints, replaced by each setBreakpoints
+    source_breakpoints: Vec<usize>,
+    stop_on_entry: bool,
+    run: Run,
+}
+
+struct Adapter<W: Write> {
+    output: W,
+    // Sequence number of the next message sent
+    seq: u64,
+    machine: Option<Machine>,
+    running: bool,
+}
+
+impl<W: Write> Adapter<W> {
+    fn new(output: W) -> Self {
+        Adapter {
+            output,
+            seq: 1,
+            machine: None,
+            running: false,
+        }
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        let body = message.to_string();
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        self.output.flush()
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        let mut message = json!({ "type": "event", "event": event });
+        if !body.is_null() {
+            message["body"] = body;
+        }
+        self.send(message)
+    }
+
+    fn stopped(&mut self, reason: &str) -> io::Result<()> {
+        self.running = false;
+        self.event(
+            "stopped",
+            json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+        )
+    }
+
+    /// Handles a request, returns false once the session is over
+    fn handle(&mut self, message: &Value) -> io::Result<bool> {
+        let command = message["command"].as_str().unwrap_or_default();
+        let arguments = &message["arguments"];
+        let result = match (command, self.machine.as_mut()) {
+            ("initialize", _) => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsReadMemoryRequest": true,
+            })),
+            ("launch", _) => self.launch(arguments),
+            ("disconnect" | "terminate", _) => {
+                self.respond(message, Ok(Value::Null))?;
+                if command == "terminate" {
+                    self.event("terminated", Value::Null)?;
+                }
+                return Ok(false);
+            }
+            (_, None) => Err(String::from("No ROM launched")),
+            ("setBreakpoints", Some(machine)) => Ok(machine.set_breakpoints(arguments)),
+            ("setExceptionBreakpoints", _) => Ok(json!({ "breakpoints": [] })),
+            ("threads", _) => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }] })),
+            ("stackTrace", Some(machine)) => Ok(machine.stack_trace()),
+            ("scopes", _) => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS, "expensive": false },
+                { "name": "Timers", "variablesReference": TIMERS, "expensive": false },
+                { "name": "Stack", "variablesReference": STACK, "expensive": false },
+            ] })),
+            ("variables", Some(machine)) => Ok(machine.variables(arguments)),
+            ("readMemory", Some(machine)) => machine.read_memory(arguments),
+            ("configurationDone", Some(machine)) => {
+                let stop = machine.stop_on_entry;
+                self.respond(message, Ok(Value::Null))?;
+                return self.resume(Run::Continue, stop.then_some("entry"));
+            }
+            ("continue", Some(machine)) => {
+                machine.run = Run::Continue;
+                self.respond(message, Ok(json!({ "allThreadsContinued": true })))?;
+                self.running = true;
+                return Ok(true);
+            }
+            ("next" | "stepIn" | "stepOut", Some(machine)) => {
+                let chip8 = &machine.chip8;
+                let (pc, sp) = (chip8.pc(), chip8.sp());
+                let call = chip8
+                    .opcode_at(pc)
+                    .is_some_and(|opcode| opcode >> 12 == 0x2);
+                let run = match command {
+                    "next" if call => Some(Run::Over {
+                        address: pc + 2,
+                        sp,
+                    }),
+                    "stepOut" if sp > 0 => Some(Run::Out { sp }),
+                    // One instruction
+                    _ => None,
+                };
+                self.respond(message, Ok(Value::Null))?;
+                return match run {
+                    Some(run) => self.resume(run, None),
+                    None => {
+                        machine_mut(&mut self.machine).chip8.cycle();
+                        self.stopped("step")?;
+                        Ok(true)
+                    }
+                };
+            }
+            ("pause", _) => {
+                self.respond(message, Ok(Value::Null))?;
+                self.stopped("pause")?;
+                return Ok(true);
+            }
+            _ => Err(format!("Unsupported request: {}", command)),
+        };
+        self.respond(message, result)?;
+        Ok(true)
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(Value::Null) => (),
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)
+    }
+
+    /// Lets the machine run, or stops it right away for the reason
+    fn resume(&mut self, run: Run, stop: Option<&str>) -> io::Result<bool> {
+        match stop {
+            Some(reason) => self.stopped(reason)?,
+            None => {
+                machine_mut(&mut self.machine).run = run;
+                self.running = true;
+            }
+        }
+        Ok(true)
+    }
+
+    fn launch(&mut self, arguments: &Value) -> Result<Value, String> {
+        let program = arguments["program"]
+            .as_str()
+            .ok_or("launch needs the path of the ROM as program")?;
+        let path = Path::new(program);
+        let source = arguments["source"].as_str().map(PathBuf::from);
+        let machine = Machine::load(path, source, arguments)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let warning = match (&machine.source, arguments["source"].as_str()) {
+            (None, Some(source)) => Some(format!(
+                "{} does not assemble to {}, source breakpoints are off\n",
+                source,
+                path.display()
+            )),
+            _ => None,
+        };
+        self.machine = Some(machine);
+        if let Some(warning) = warning {
+            self.event(
+                "output",
+                json!({ "category": "console", "output": warning }),
+            )
+            .map_err(|e| e.to_string())?;
+        }
+        self.event("initialized", Value::Null)
+            .map_err(|e| e.to_string())?;
+        Ok(Value::Null)
+    }
+
+    /// Runs one frame, until a breakpoint or the end of a step
+    fn run_frame(&mut self) -> io::Result<()> {
+        let machine = machine_mut(&mut self.machine);
+        let mut stops = Stops {
+            breakpoints: &mut machine.breakpoints,
+            run: machine.run,
+            reason: "",
+        };
+        for _ in 0..machine.cycles_per_frame {
+            if !machine.chip8.cycle_with(&mut stops) {
+                let reason = stops.reason;
+                return self.stopped(reason);
+            }
+        }
+        machine.chip8.decrement_timers();
+        Ok(())
+    }
+}
+
+fn machine_mut(machine: &mut Option<Machine>) -> &mut Machine {
+    machine.as_mut().expect("no ROM launched")
+}
+
+impl Machine {
+    /// Loads the ROM, with the source map of its Octo source, given or being the ROM itself
+    fn load(path: &Path, source: Option<PathBuf>, arguments: &Value) -> Result<Self, String> {
+        let source = source.or_else(|| {
+            let is_octo = path
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("8o"));
+            is_octo.then(|| path.to_path_buf())
+        });
+        let file = rom::read(path).map_err(|e| e.to_string())?;
+        let source = match source {
+            Some(source) => {
+                let text = fs::read_to_string(&source).map_err(|e| e.to_string())?;
+                let program = octo::assemble_program(&text).map_err(|e| e.to_string())?;
+                (program.bytes == file.program).then_some((source, program))
+            }
+            None => None,
+        };
+
+        let info = Database::load().lookup_file(&file, &launcher::rom_name(path));
+        let rng = Box::new(SeededRandom::new(rng::random_seed()));
+        let mut chip8 = Chip8::new(info.quirks.unwrap_or_default(), rng);
+        chip8
+            .load_rom(file.program, START_ALLOWED_ADDRESS)
+            .map_err(|e| e.to_string())?;
+        let tickrate = arguments["tickrate"]
+            .as_u64()
+            .map(|tickrate| tickrate as u32);
+        Ok(Machine {
+            chip8,
+            cycles_per_frame: tickrate
+                .or(info.tickrate)
+                .unwrap_or(session::CYCLES_PER_FRAME),
+            breakpoints: Breakpoints::new(),
+            source,
+            source_breakpoints: Vec::new(),
+            stop_on_entry: arguments["stopOnEntry"].as_bool().unwrap_or(false),
+            run: Run::Continue,
+        })
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Value) -> Value {
+        for address in self.source_breakpoints.drain(..) {
+            self.breakpoints.remove(address);
+        }
+        let lines = arguments["breakpoints"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        let path = arguments["source"]["path"].as_str().map(Path::new);
+        let program = match &self.source {
+            Some((source, program)) if path.is_some_and(|path| same_file(path, source)) => {
+                Some(program)
+            }
+            _ => None,
+        };
+        let mut breakpoints = Vec::new();
+        for breakpoint in lines {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as usize;
+            let found = program.and_then(|program| program.address_of(line));
+            breakpoints.push(match found {
+                Some((address, line)) => {
+                    self.breakpoints.insert(address as usize);
+                    self.source_breakpoints.push(address as usize);
+                    json!({ "verified": true, "line": line })
+                }
+                None => json!({ "verified": false, "message": "No instruction there" }),
+            });
+        }
+        json!({ "breakpoints": breakpoints })
+    }
+
+    /// Frame 0 is the next instruction, then the calls of the subroutines being run, innermost first
+    fn stack_trace(&self) -> Value {
+        let calls = self
+            .chip8
+            .stack()
+            .iter()
+            .rev()
+            .map(|&address| address.saturating_sub(2));
+        let frames: Vec<Value> = std::iter::once(self.chip8.pc())
+            .chain(calls)
+            .enumerate()
+            .map(|(id, address)| {
+                let opcode = self.chip8.opcode_at(address).unwrap_or_default();
+                let mut frame = json!({
+                    "id": id,
+                    "name": format!("0x{:03X} {}", address, disassemble(opcode)),
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("0x{:03X}", address),
+                });
+                let line = self.source.as_ref().and_then(|(path, program)| {
+                    Some((path, program.line_at(u16::try_from(address).ok()?)?))
+                });
+                if let Some((path, line)) = line {
+                    frame["source"] = json!({ "path": path });
+                    frame["line"] = json!(line);
+                    frame["column"] = json!(1);
+                }
+                frame
+            })
+            .collect();
+        json!({ "stackFrames": frames, "totalFrames": frames.len() })
+    }
+
+    fn variables(&self, arguments: &Value) -> Value {
+        let chip8 = &self.chip8;
+        let address = |name: String, value: usize| {
+            let value = format!("0x{:03X}", value);
+            json!({ "name": name, "value": value, "memoryReference": value, "variablesReference": 0 })
+        };
+        let number = |name: String, value: u8| json!({ "name": name, "value": format!("0x{:02X} ({})", value, value), "variablesReference": 0 });
+        let variables: Vec<Value> = match arguments["variablesReference"].as_u64() {
+            Some(REGISTERS) => {
+                let mut variables: Vec<Value> = (0..16)
+                    .map(|x| number(format!("V{:X}", x), chip8.v()[x]))
+                    .collect();
+                variables.push(address(String::from("I"), chip8.i()));
+                variables.push(address(String::from("PC"), chip8.pc()));
+                variables.push(number(String::from("SP"), chip8.sp() as u8));
+                variables
+            }
+            Some(TIMERS) => vec![
+                number(String::from("DT"), chip8.delay_timer()),
+                number(String::from("ST"), chip8.sound_timer()),
+            ],
+            // Outermost call first, like the stack in memory
+            Some(STACK) => (chip8.stack().iter().enumerate())
+                .map(|(level, &return_address)| address(level.to_string(), return_address))
+                .collect(),
+            _ => Vec::new(),
+        };
+        json!({ "variables": variables })
+    }
+
+    fn read_memory(&self, arguments: &Value) -> Result<Value, String> {
+        let reference = arguments["memoryReference"].as_str().unwrap_or_default();
+        let offset = arguments["offset"].as_i64().unwrap_or(0);
+        let start = i64::try_from(parse_address(reference)?)
+            .ok()
+            .and_then(|address| address.checked_add(offset));
+        // Clients may ask for anything, no more than a 32-bit count is answered
+        let count = arguments["count"].as_u64().unwrap_or(0).min(u32::MAX as u64) as usize;
+        let memory = self.chip8.memory();
+        let Some(start) = start
+            .and_then(|start| usize::try_from(start).ok())
+            .filter(|&start| start < memory.len())
+        else {
+            let address = start.map_or(reference.to_string(), |start| format!("0x{:X}", start));
+            return Ok(json!({ "address": address, "unreadableBytes": count }));
+        };
+        let end = memory.len().min(start.saturating_add(count));
+        Ok(json!({
+            "address": format!("0x{:03X}", start),
+            "data": base64(&memory[start..end]),
+            "unreadableBytes": count - (end - start),
+        }))
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::new();
+    for chunk in bytes.chunks(3) {
+        let group = chunk
+            .iter()
+            .fold(0u32, |group, &byte| group << 8 | byte as u32)
+            << (8 * (3 - chunk.len()));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    struct Client {
+        adapter: Adapter<Vec<u8>>,
+        seq: u64,
+    }
+
+    impl Client {
+        fn new() -> Self {
+            Client {
+                adapter: Adapter::new(Vec::new()),
+                seq: 1,
+            }
+        }
+
+        /// Sends a request, returns the messages sent back
+        fn request(&mut self, command: &str, arguments: Value) -> Vec<Value> {
+            let request = json!({ "seq": self.seq, "type": "request", "command": command, "arguments": arguments });
+            self.seq += 1;
+            assert!(self.adapter.handle(&request).unwrap());
+            self.messages()
+        }
+
+        fn messages(&mut self) -> Vec<Value> {
+            let output = std::mem::take(&mut self.adapter.output);
+            let mut input = io::Cursor::new(output);
+            std::iter::from_fn(|| read_message(&mut input).unwrap()).collect()
+        }
+
+        /// Body of the response to the request, which must succeed
+        fn body(&mut self, command: &str, arguments: Value) -> Value {
+            let response = self.request(command, arguments).remove(0);
+            assert_eq!(response["success"], true, "{}", response);
+            response["body"].clone()
+        }
+    }
+
+    #[test]
+    fn reads_messages_and_encodes_memory() {
+        let mut input = io::Cursor::new(
+            b"Content-Length: 10\r\n\r\n{\"seq\": 1}Content-Length: 2\r\n\r\n{]".to_vec(),
+        );
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!({ "seq": 1 })));
+        assert!(read_message(&mut input).is_err());
+        assert_eq!(base64(b"CHIP-8"), "Q0hJUC04");
+        assert_eq!(base64(&[0xff, 0]), "/wA=");
+        assert_eq!(base64(&[0xff]), "/w==");
+    }
+
+    #[test]
+    fn debugs_octo_sources() {
+        let path = env::temp_dir().join(format!("chip8-dap-{}.8o", process::id()));
+        let source =
+            ": main\n  v0 := 1\n  sub\n  v2 := 3\n  loop again\n: sub\n  v1 := 2\n  return\n";
+        fs::write(&path, source).unwrap();
+        let mut client = Client::new();
+        client.body("initialize", json!({ "adapterID": "chip8" }));
+        let messages = client.request("launch", json!({ "program": path, "stopOnEntry": true }));
+        assert_eq!(messages[0]["event"], "initialized");
+
+        let body = client.body(
+            "setBreakpoints",
+            json!({ "source": { "path": path }, "breakpoints": [{ "line": 6 }, { "line": 20 }] }),
+        );
+        assert_eq!(
+            body["breakpoints"][0],
+            json!({ "verified": true, "line": 7 })
+        );
+        assert_eq!(body["breakpoints"][1]["verified"], false);
+        let messages = client.request("configurationDone", Value::Null);
+        assert_eq!(messages[1]["body"]["reason"], "entry");
+
+        // Runs into the subroutine
+        client.body("continue", Value::Null);
+        client.adapter.run_frame().unwrap();
+        let stopped = client.messages();
+        assert_eq!(stopped[0]["body"]["reason"], "breakpoint");
+        let frames =
+            client.body("stackTrace", json!({ "threadId": THREAD_ID }))["stackFrames"].clone();
+        assert_eq!(frames[0]["line"], 7);
+        assert_eq!(frames[0]["name"], "0x208 LD V1, 0x02");
+        assert_eq!(frames[1]["line"], 3);
+
+        // Out of it, then over nothing but the next instruction
+        client.request("stepOut", Value::Null);
+        client.adapter.run_frame().unwrap();
+        client.request("next", Value::Null);
+        let registers = client.body("variables", json!({ "variablesReference": REGISTERS }));
+        let value = |name: &str| {
+            let variables = registers["variables"].as_array().unwrap();
+            let variable = variables.iter().find(|v| v["name"] == name).unwrap();
+            variable["value"].as_str().unwrap().to_string()
+        };
+        assert_eq!(value("V1"), "0x02 (2)");
+        assert_eq!(value("V2"), "0x03 (3)");
+        assert_eq!(value("PC"), "0x206");
+
+        let memory = client.body(
+            "readMemory",
+            json!({ "memoryReference": "0x200", "count": 4 }),
+        );
+        assert_eq!(memory["data"], base64(&[0x60, 0x01, 0x22, 0x08]));
+        let memory = client.body(
+            "readMemory",
+            json!({ "memoryReference": "0xffe", "count": 4 }),
+        );
+        assert_eq!(memory["unreadableBytes"], 2);
+        // Counts and offsets past what addresses can hold
+        let memory = client.body(
+            "readMemory",
+            json!({ "memoryReference": "0xffe", "count": u64::MAX }),
+        );
+        assert_eq!(memory["unreadableBytes"], u32::MAX as u64 - 2);
+        let memory = client.body(
+            "readMemory",
+            json!({ "memoryReference": "0x200", "offset": i64::MAX, "count": 1 }),
+        );
+        assert_eq!(memory["unreadableBytes"], 1);
+        fs::remove_file(&path).unwrap();
+    }
+}
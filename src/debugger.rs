@@ -0,0 +1,389 @@
+// Breakpoints, and the debugger side panel: registers, timers, stack, disassembly around PC and memory,
+// or the memory as sprites and the sprites drawn so far.
+
+use crate::{
+    chip8::{Chip8, Hook},
+    disassembler::disassemble,
+    sprites::{Image, Shape, Sprite, SpriteRecorder},
+};
+use std::{collections::BTreeSet, iter};
+
+// Instructions shown before and after the next one
+const DISASSEMBLY_CONTEXT: usize = 4;
+// Rows of bytes in the memory view
+const MEMORY_ROWS: usize = 8;
+const MEMORY_ROW_SIZE: usize = 8;
+// Sprite pixel rows per panel line, as many as text font pixels
+const PIXEL_ROWS_PER_LINE: usize = 9;
+// Sprites drawn so far shown at once
+const DRAWN_SPRITES: usize = 12;
+
+/// Instruction addresses to stop at
+#[derive(Default)]
+pub struct Breakpoints {
+    addresses: BTreeSet<usize>,
+    // Breakpoint the machine stopped at, passed over when it runs again
+    stopped_at: Option<usize>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds or removes a breakpoint, returns whether it is set
+    pub fn toggle(&mut self, address: usize) -> bool {
+        !self.remove(address) && self.insert(address)
+    }
+
+    /// Sets a breakpoint, returns whether it was not set already
+    pub fn insert(&mut self, address: usize) -> bool {
+        self.addresses.insert(address)
+    }
+
+    /// Clears a breakpoint, returns whether it was set
+    pub fn remove(&mut self, address: usize) -> bool {
+        self.addresses.remove(&address)
+    }
+
+    pub fn contains(&self, address: usize) -> bool {
+        self.addresses.contains(&address)
+    }
+
+    /// Whether to stop before running the instruction at the address.
+    /// Right after stopping there, the instruction runs.
+    pub fn should_stop(&mut self, pc: usize) -> bool {
+        if self.stopped_at.take() == Some(pc) || !self.addresses.contains(&pc) {
+            return false;
+        }
+        self.stopped_at = Some(pc);
+        true
+    }
+}
+
+impl Hook for Breakpoints {
+    fn before_execute(&mut self, _chip8: &Chip8, address: usize, _opcode: u16) -> bool {
+        !self.should_stop(address)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Style {
+    Normal,
+    // Register names and addresses
+    Label,
+    // The next instruction
+    Current,
+    // Memory the next instruction reads or writes through I
+    Index,
+    Breakpoint,
+}
+
+pub struct Span {
+    pub text: String,
+    pub style: Style,
+}
+
+impl Span {
+    fn new(text: String, style: Style) -> Self {
+        Span { text, style }
+    }
+}
+
+pub struct PanelLine {
+    pub spans: Vec<Span>,
+    // Instruction on the line, clicking on it toggles its breakpoint
+    pub address: Option<usize>,
+    // Sprites drawn after the text, over the following lines if they are taller
+    pub image: Option<Image>,
+}
+
+impl PanelLine {
+    fn new(spans: Vec<Span>) -> Self {
+        PanelLine {
+            spans,
+            address: None,
+            image: None,
+        }
+    }
+
+    /// Line with the image, followed by empty lines to make room for it
+    fn with_image(spans: Vec<Span>, image: Image) -> Vec<Self> {
+        let lines = image.height.div_ceil(PIXEL_ROWS_PER_LINE).max(1);
+        let mut line = PanelLine::new(spans);
+        line.image = Some(image);
+        iter::once(line)
+            .chain((1..lines).map(|_| PanelLine::new(Vec::new())))
+            .collect()
+    }
+}
+
+/// Machine state drawn next to the screen
+#[derive(Default)]
+pub struct Panel {
+    // First memory row shown, None to follow I
+    memory_top: Option<usize>,
+    // Showing sprites instead of the machine state
+    sprites: bool,
+}
+
+impl Panel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Scrolls the memory view by rows, it stops following I
+    pub fn scroll(&mut self, rows: isize, chip8: &Chip8) {
+        let top = self
+            .memory_top
+            .unwrap_or_else(|| Self::following_top(chip8));
+        let last = Self::last_top(chip8);
+        self.memory_top = Some(top.saturating_add_signed(rows).min(last));
+    }
+
+    /// Switches between the machine state and the sprites, returns whether the sprites are shown
+    pub fn toggle_sprites(&mut self) -> bool {
+        self.sprites = !self.sprites;
+        self.sprites
+    }
+
+    /// Makes the memory view follow I again
+    pub fn follow_index(&mut self) {
+        self.memory_top = None;
+    }
+
+    fn last_top(chip8: &Chip8) -> usize {
+        chip8.memory().len() / MEMORY_ROW_SIZE - MEMORY_ROWS
+    }
+
+    // Shows a couple of rows before I
+    fn following_top(chip8: &Chip8) -> usize {
+        let row = chip8.i() / MEMORY_ROW_SIZE;
+        row.saturating_sub(2).min(Self::last_top(chip8))
+    }
+
+    pub fn lines(
+        &self,
+        chip8: &Chip8,
+        breakpoints: &Breakpoints,
+        drawn: &SpriteRecorder,
+    ) -> Vec<PanelLine> {
+        if self.sprites {
+            return self.sprite_lines(chip8, drawn);
+        }
+        let label = |text: &str| Span::new(String::from(text), Style::Label);
+        let normal = |text: String| Span::new(text, Style::Normal);
+        let mut lines = vec![
+            PanelLine::new(vec![
+                label("PC "),
+                normal(format!("0x{:03X}  ", chip8.pc())),
+                label("I "),
+                normal(format!("0x{:03X}  ", chip8.i())),
+                label("SP "),
+                normal(chip8.sp().to_string()),
+            ]),
+            PanelLine::new(vec![
+                label("DT "),
+                normal(format!("{:<3}  ", chip8.delay_timer())),
+                label("ST "),
+                normal(chip8.sound_timer().to_string()),
+            ]),
+        ];
+        for (row, values) in chip8.v().chunks(4).enumerate() {
+            let mut spans = Vec::new();
+            for (column, value) in values.iter().enumerate() {
+                spans.push(Span::new(format!("V{:X} ", row * 4 + column), Style::Label));
+                spans.push(normal(format!("{:02X}  ", value)));
+            }
+            lines.push(PanelLine::new(spans));
+        }
+        // Innermost calls first, as many as fit
+        let stack: Vec<String> = chip8
+            .stack()
+            .iter()
+            .rev()
+            .take(4)
+            .map(|address| format!("0x{:03X}", address))
+            .collect();
+        lines.push(PanelLine::new(vec![
+            label("Stack "),
+            normal(stack.join(" ")),
+        ]));
+        lines.push(PanelLine::new(Vec::new()));
+
+        let pc = chip8.pc();
+        let first = pc.saturating_sub(2 * DISASSEMBLY_CONTEXT);
+        for address in (first..).step_by(2).take(2 * DISASSEMBLY_CONTEXT + 1) {
+            let Some(opcode) = chip8.opcode_at(address) else {
+                break;
+            };
+            let marker = if breakpoints.contains(address) {
+                Span::new(String::from("* "), Style::Breakpoint)
+            } else {
+                normal(String::from("  "))
+            };
+            let style = if address == pc {
+                Style::Current
+            } else {
+                Style::Normal
+            };
+            let text = format!("0x{:03X} {:04X} {}", address, opcode, disassemble(opcode));
+            lines.push(PanelLine {
+                spans: vec![marker, Span::new(text, style)],
+                address: Some(address),
+                image: None,
+            });
+        }
+        lines.push(PanelLine::new(Vec::new()));
+
+        let index = chip8.i()..chip8.i() + index_length(chip8.opcode_at(pc));
+        let top = self
+            .memory_top
+            .unwrap_or_else(|| Self::following_top(chip8));
+        for row in top..top + MEMORY_ROWS {
+            let start = row * MEMORY_ROW_SIZE;
+            let mut spans = vec![Span::new(format!("0x{:03X}", start), Style::Label)];
+            let bytes = chip8.memory().iter().enumerate().skip(start);
+            for (address, byte) in bytes.take(MEMORY_ROW_SIZE) {
+                let style = if address == pc || address == pc + 1 {
+                    Style::Current
+                } else if index.contains(&address) {
+                    Style::Index
+                } else {
+                    Style::Normal
+                };
+                spans.push(normal(String::from(" ")));
+                spans.push(Span::new(format!("{:02X}", byte), style));
+            }
+            lines.push(PanelLine::new(spans));
+        }
+        lines
+    }
+
+    /// The memory view as 8x8 sprites, then the sprites drawn so far with how many times,
+    /// the one I points to highlighted
+    fn sprite_lines(&self, chip8: &Chip8, drawn: &SpriteRecorder) -> Vec<PanelLine> {
+        let label = |text: &str| Span::new(String::from(text), Style::Label);
+        let mut lines = vec![PanelLine::new(vec![label("Memory")])];
+        let top = self
+            .memory_top
+            .unwrap_or_else(|| Self::following_top(chip8));
+        let memory = chip8.memory();
+        for row in (top..top + MEMORY_ROWS).step_by(MEMORY_ROWS / 2) {
+            let start = row * MEMORY_ROW_SIZE;
+            let sprites = Sprite::tile(start, start + 4 * MEMORY_ROW_SIZE, Shape::Narrow(8));
+            let spans = vec![label(&format!("0x{:03X} ", start))];
+            lines.extend(PanelLine::with_image(spans, Image::grid(memory, &sprites, 4)));
+        }
+        lines.push(PanelLine::new(Vec::new()));
+
+        lines.push(PanelLine::new(vec![label("Drawn")]));
+        for (sprite, draws) in drawn.drawn().into_iter().take(DRAWN_SPRITES) {
+            let style = match sprite.address == chip8.i() {
+                true => Style::Index,
+                false => Style::Normal,
+            };
+            let size = format!("{}x{}", sprite.shape.width(), sprite.shape.height());
+            let text = format!("0x{:03X} {:<5} {:<6} ", sprite.address, size, draws);
+            let image = Image::grid(memory, &[sprite], 1);
+            lines.extend(PanelLine::with_image(vec![Span::new(text, style)], image));
+        }
+        lines
+    }
+}
+
+/// Bytes from I the instruction uses, at least one to show where I points
+fn index_length(opcode: Option<u16>) -> usize {
+    let Some(opcode) = opcode else {
+        return 1;
+    };
+    let x = ((opcode >> 8) & 0xf) as usize;
+    match (opcode >> 12, opcode & 0xff) {
+        (0xd, _) => ((opcode & 0xf) as usize).max(1),
+        (0xf, 0x33) => 3,
+        (0xf, 0x55 | 0x65) => x + 1,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(line: &PanelLine) -> String {
+        line.spans.iter().map(|span| span.text.as_str()).collect()
+    }
+
+    #[test]
+    fn stops_once_at_breakpoints() {
+        let mut breakpoints = Breakpoints::new();
+        assert!(breakpoints.toggle(0x204));
+        assert!(!breakpoints.should_stop(0x202));
+        assert!(breakpoints.should_stop(0x204));
+        // Running again from there
+        assert!(!breakpoints.should_stop(0x204));
+        assert!(breakpoints.should_stop(0x204));
+        assert!(!breakpoints.toggle(0x204));
+        assert!(!breakpoints.should_stop(0x204));
+    }
+
+    #[test]
+    fn shows_machine_state() {
+        // LD I, 0x20A; DRW V0, V1, 3
+        let mut chip8 = Chip8::from_program(&[0xa2, 0x0a, 0xd0, 0x13]);
+        chip8.cycle();
+        let mut breakpoints = Breakpoints::new();
+        breakpoints.toggle(0x200);
+        let lines = Panel::new().lines(&chip8, &breakpoints, &SpriteRecorder::new());
+        assert_eq!(text(&lines[0]), "PC 0x202  I 0x20A  SP 0");
+
+        let disassembly: Vec<&PanelLine> = lines.iter().filter(|l| l.address.is_some()).collect();
+        assert_eq!(text(disassembly[3]), "* 0x200 A20A LD I, 0x20A");
+        assert_eq!(text(disassembly[4]), "  0x202 D013 DRW V0, V1, 3");
+        assert_eq!(disassembly[4].spans[1].style, Style::Current);
+
+        // Two rows before I, with the next instruction and the sprite highlighted
+        let memory = &lines[lines.len() - MEMORY_ROWS..];
+        assert_eq!(text(&memory[1]), "0x200 A2 0A D0 13 00 00 00 00");
+        let styles = |line: &PanelLine| -> Vec<Style> {
+            line.spans
+                .iter()
+                .skip(2)
+                .step_by(2)
+                .map(|span| span.style)
+                .collect()
+        };
+        use Style::*;
+        assert_eq!(
+            styles(&memory[1]),
+            [Normal, Normal, Current, Current, Normal, Normal, Normal, Normal]
+        );
+        assert_eq!(
+            styles(&memory[2]),
+            [Normal, Normal, Index, Index, Index, Normal, Normal, Normal]
+        );
+    }
+
+    #[test]
+    fn shows_sprites() {
+        // LD I, 0x206; DRW V0, V1, 2; sprite 0x3C 0x42
+        let mut chip8 = Chip8::from_program(&[0xa2, 0x06, 0xd0, 0x12, 0x00, 0x00, 0x3c, 0x42]);
+        let mut drawn = SpriteRecorder::new();
+        chip8.cycle_with(&mut drawn);
+        chip8.cycle_with(&mut drawn);
+        let mut panel = Panel::new();
+        assert!(panel.toggle_sprites());
+        let lines = panel.lines(&chip8, &Breakpoints::new(), &drawn);
+
+        // Two rows before I, as 8x8 sprites
+        assert_eq!(text(&lines[1]), "0x1F0 ");
+        let memory = lines[1].image.as_ref().unwrap();
+        assert_eq!((memory.width, memory.height), (35, 8));
+
+        let sprite = lines.last().unwrap();
+        assert_eq!(text(sprite), "0x206 8x2   1      ");
+        assert_eq!(sprite.spans[0].style, Style::Index);
+        let image = sprite.image.as_ref().unwrap().to_text();
+        assert_eq!(image, "..####..\n.#....#.\n");
+    }
+}
@@ -0,0 +1,610 @@
+// Decompiler (`chip8 decompile`): lifts the code found by the analysis into structured pseudocode, with
+// loops from backward jumps, if/else from skips followed by jumps and a function per subroutine, then
+// shows sprites as pixels. It can also write Octo source that assembles back to the same bytes.
+
+use crate::{
+    analysis::{self, Analysis, Kind},
+    chip8::START_ALLOWED_ADDRESS,
+    disassembler::disassemble,
+    octo,
+};
+use std::collections::BTreeSet;
+
+const INDENT: &str = "    ";
+// Bytes per line of data
+const DATA_ROW_SIZE: usize = 8;
+
+/// Conditions under which a skip instruction skips, and doesn't
+fn skip_conditions(opcode: u16) -> Option<(String, String)> {
+    let x = (opcode >> 8) & 0xf;
+    let y = (opcode >> 4) & 0xf;
+    let kk = opcode & 0xff;
+    let compare = |operand: String, skips_if_equal: bool| {
+        let equal = format!("V{:X} == {}", x, operand);
+        let different = format!("V{:X} != {}", x, operand);
+        if skips_if_equal {
+            (equal, different)
+        } else {
+            (different, equal)
+        }
+    };
+    match (opcode >> 12, opcode & 0xf, kk) {
+        (0x3, _, _) => Some(compare(format!("0x{:02X}", kk), true)),
+        (0x4, _, _) => Some(compare(format!("0x{:02X}", kk), false)),
+        (0x5, 0, _) => Some(compare(format!("V{:X}", y), true)),
+        (0x9, 0, _) => Some(compare(format!("V{:X}", y), false)),
+        (0xe, _, 0x9e) => Some((format!("key(V{:X})", x), format!("!key(V{:X})", x))),
+        (0xe, _, 0xa1) => Some((format!("!key(V{:X})", x), format!("key(V{:X})", x))),
+        _ => None,
+    }
+}
+
+/// Pseudocode of an instruction that doesn't change the flow
+fn statement(opcode: u16, analysis: &Analysis, address: usize) -> String {
+    let x = (opcode >> 8) & 0xf;
+    let y = (opcode >> 4) & 0xf;
+    let n = opcode & 0xf;
+    let kk = opcode & 0xff;
+    let nnn = (opcode & 0xfff) as usize;
+    let (vx, vy) = (format!("V{:X}", x), format!("V{:X}", y));
+    match (opcode >> 12, n, kk) {
+        (0x0, _, 0xe0) => String::from("clear()"),
+        (0x0, _, 0xfd) => String::from("exit()"),
+        (0x0, _, 0xfe) => String::from("lores()"),
+        (0x0, _, 0xff) => String::from("hires()"),
+        (0x2, _, _) => format!("{}()", function_name(analysis, nnn)),
+        (0x6, _, _) => format!("{} = 0x{:02X}", vx, kk),
+        (0x7, _, _) => format!("{} += 0x{:02X}", vx, kk),
+        (0x8, 0x0, _) => format!("{} = {}", vx, vy),
+        (0x8, 0x1, _) => format!("{} |= {}", vx, vy),
+        (0x8, 0x2, _) => format!("{} &= {}", vx, vy),
+        (0x8, 0x3, _) => format!("{} ^= {}", vx, vy),
+        (0x8, 0x4, _) => format!("{} += {}, VF = carry", vx, vy),
+        (0x8, 0x5, _) => format!("{} -= {}, VF = no borrow", vx, vy),
+        (0x8, 0x6, _) => format!("{} = {} >> 1, VF = bit out", vx, vy),
+        (0x8, 0x7, _) => format!("{} = {} - {}, VF = no borrow", vx, vy, vx),
+        (0x8, 0xe, _) => format!("{} = {} << 1, VF = bit out", vx, vy),
+        (0xa, _, _) => format!("I = {}", data_name(analysis, nnn)),
+        (0xc, _, _) => format!("{} = random() & 0x{:02X}", vx, kk),
+        (0xd, _, _) => format!("VF = draw({}, {}, {})", vx, vy, n),
+        (0xf, _, 0x07) => format!("{} = delay", vx),
+        (0xf, _, 0x0a) => format!("{} = wait_key()", vx),
+        (0xf, _, 0x15) => format!("delay = {}", vx),
+        (0xf, _, 0x18) => format!("sound = {}", vx),
+        (0xf, _, 0x1e) => format!("I += {}", vx),
+        (0xf, _, 0x29) => format!("I = font({})", vx),
+        (0xf, _, 0x33) => format!("memory[I..I + 3] = bcd({})", vx),
+        (0xf, _, 0x55) if x == 0 => String::from("memory[I] = V0"),
+        (0xf, _, 0x55) => format!("memory[I..I + {}] = V0..={}", x + 1, vx),
+        (0xf, _, 0x65) if x == 0 => String::from("V0 = memory[I]"),
+        (0xf, _, 0x65) => format!("V0..={} = memory[I..I + {}]", vx, x + 1),
+        _ if opcode == 0xf000 => {
+            let target = analysis.opcode_at(address + 2) as usize;
+            format!("I = {}", data_name(analysis, target))
+        }
+        _ => disassemble(opcode),
+    }
+}
+
+fn bytes(count: usize) -> String {
+    match count {
+        1 => String::from("1 byte"),
+        _ => format!("{} bytes", count),
+    }
+}
+
+fn function_name(analysis: &Analysis, address: usize) -> String {
+    if address == analysis.start() {
+        String::from("main")
+    } else {
+        format!("sub_{:03X}", address)
+    }
+}
+
+fn data_name(analysis: &Analysis, address: usize) -> String {
+    match analysis.kind(address) {
+        Some(Kind::Sprite) => format!("sprite_{:03X}", address),
+        Some(Kind::Data) => format!("data_{:03X}", address),
+        _ => format!("0x{:03X}", address),
+    }
+}
+
+/// Loop being written, `continue` jumps to its header and `break` to its exit
+#[derive(Clone, Copy)]
+struct Loop {
+    header: usize,
+    exit: usize,
+}
+
+/// Writes a function, walking its instructions in address order
+struct Writer<'a> {
+    analysis: &'a Analysis,
+    // Instructions of the function, sorted
+    instructions: Vec<usize>,
+    // Targets of gotos, labelled in the output
+    labels: BTreeSet<usize>,
+    gotos: BTreeSet<usize>,
+    out: String,
+}
+
+impl Writer<'_> {
+    fn line(&mut self, depth: usize, text: &str) {
+        self.out += &INDENT.repeat(depth);
+        self.out += text;
+        self.out.push('\n');
+    }
+
+    fn opcode(&self, address: usize) -> u16 {
+        self.analysis.opcode_at(address)
+    }
+
+    fn next(&self, address: usize) -> Option<usize> {
+        let index = self.instructions.partition_point(|&a| a <= address);
+        self.instructions.get(index).copied()
+    }
+
+    fn contains(&self, address: usize) -> bool {
+        self.instructions.binary_search(&address).is_ok()
+    }
+
+    /// Last jump back to the address, which makes it a loop header
+    fn back_jump(&self, header: usize, end: usize) -> Option<usize> {
+        let jumps = self.instructions.iter().rev().filter(|&&a| a >= header && a < end);
+        jumps
+            .copied()
+            .find(|&a| self.opcode(a) & 0xf000 == 0x1000 && (self.opcode(a) & 0xfff) as usize == header)
+    }
+
+    fn goto(&mut self, target: usize, within: Option<Loop>) -> String {
+        match within {
+            Some(l) if target == l.header => String::from("continue"),
+            Some(l) if target == l.exit => String::from("break"),
+            _ => {
+                self.gotos.insert(target);
+                format!("goto L_{:03X}", target)
+            }
+        }
+    }
+
+    /// Writes the instructions from start to end
+    fn range(&mut self, start: usize, end: usize, depth: usize, within: Option<Loop>) {
+        let mut address = start;
+        let mut header_done = false;
+        while address < end && self.contains(address) {
+            if self.labels.contains(&address) {
+                self.line(depth.saturating_sub(1), &format!("L_{:03X}:", address));
+            }
+            let opcode = self.opcode(address);
+            let next = self.next(address).unwrap_or(address + analysis::size(opcode));
+
+            let is_header = within.is_none_or(|l| l.header != address) || header_done;
+            if let Some(jump) = self.back_jump(address, end).filter(|_| is_header) {
+                let exit = jump + 2;
+                self.line(depth, "loop {");
+                let inner = Loop {
+                    header: address,
+                    exit,
+                };
+                self.range(address, jump, depth + 1, Some(inner));
+                self.line(depth, "}");
+                address = exit;
+                continue;
+            }
+            header_done = true;
+
+            if let Some((skips, runs)) = skip_conditions(opcode) {
+                address = self.skip(address, end, depth, within, (skips, runs));
+                continue;
+            }
+            match opcode >> 12 {
+                0x1 => {
+                    let text = self.goto((opcode & 0xfff) as usize, within);
+                    self.line(depth, &text);
+                }
+                0xb => {
+                    let text = format!("goto V0 + 0x{:03X}", opcode & 0xfff);
+                    self.line(depth, &text);
+                }
+                0x0 if opcode == 0x00ee => self.line(depth, "return"),
+                _ => {
+                    let text = statement(opcode, self.analysis, address);
+                    self.line(depth, &text);
+                }
+            }
+            address = next;
+        }
+    }
+
+    /// Writes a skip and what it guards, returns where to go on
+    fn skip(
+        &mut self,
+        address: usize,
+        end: usize,
+        depth: usize,
+        within: Option<Loop>,
+        (skips, runs): (String, String),
+    ) -> usize {
+        let guarded = address + 2;
+        let opcode = self.opcode(guarded);
+        let after = guarded + analysis::size(opcode);
+        if !self.contains(guarded) {
+            self.line(depth, &format!("if ({}) skip", skips));
+            return guarded;
+        }
+        if opcode >> 12 == 0x1 {
+            let target = (opcode & 0xfff) as usize;
+            let forward = target > after && target <= end && self.contains(after);
+            let in_loop = within.is_some_and(|l| target == l.header || target == l.exit);
+            if forward && !in_loop {
+                // if (skips) { after..target } else { target..exit }
+                self.line(depth, &format!("if ({}) {{", skips));
+                let before_target = self.instructions.iter().rev().find(|&&a| a < target);
+                let otherwise = before_target.copied().and_then(|a| {
+                    let opcode = self.opcode(a);
+                    let exit = (opcode & 0xfff) as usize;
+                    let jumps_over = opcode >> 12 == 0x1 && exit > target && exit <= end;
+                    (a >= after && jumps_over).then_some((a, exit))
+                });
+                match otherwise {
+                    Some((jump, exit)) => {
+                        self.range(after, jump, depth + 1, within);
+                        self.line(depth, "} else {");
+                        self.range(target, exit, depth + 1, within);
+                        self.line(depth, "}");
+                        return exit;
+                    }
+                    None => {
+                        self.range(after, target, depth + 1, within);
+                        self.line(depth, "}");
+                        return target;
+                    }
+                }
+            }
+            let text = self.goto(target, within);
+            self.line(depth, &format!("if ({}) {}", runs, text));
+            return after;
+        }
+        let text = match opcode {
+            0x00ee => String::from("return"),
+            _ => statement(opcode, self.analysis, guarded),
+        };
+        self.line(depth, &format!("if ({}) {}", runs, text));
+        after
+    }
+}
+
+/// Structured pseudocode of the ROM, then its sprites and data
+pub fn decompile(analysis: &Analysis) -> String {
+    let mut out = String::new();
+    let start = Some(analysis.start()).filter(|start| analysis.blocks.contains_key(start));
+    let entries = start
+        .into_iter()
+        .chain(analysis.subroutines.iter().copied().filter(|&s| s != analysis.start()));
+    for entry in entries {
+        let mut instructions: Vec<usize> = analysis
+            .body(entry)
+            .iter()
+            .flat_map(|start| analysis.blocks[start].instructions.clone())
+            .collect();
+        instructions.sort();
+        instructions.dedup();
+        let end = instructions.last().map_or(entry, |&a| a + 2);
+        let mut writer = Writer {
+            analysis,
+            instructions,
+            labels: BTreeSet::new(),
+            gotos: BTreeSet::new(),
+            out: String::new(),
+        };
+        // Once to find the labels, once with them
+        writer.range(entry, end, 1, None);
+        if !writer.contains(entry) || writer.instructions[0] != entry {
+            // Code before the entry is reached through gotos
+            writer.gotos.insert(entry);
+        }
+        writer.labels = std::mem::take(&mut writer.gotos);
+        writer.out.clear();
+        let first = writer.instructions[0];
+        writer.range(first, end, 1, None);
+        // Blocks the walk left out, reached through gotos from further down
+        let mut address = first;
+        while let Some(next) = writer.next(address) {
+            if !writer.out.contains(&format!("L_{:03X}:", next)) && writer.labels.contains(&next) {
+                writer.range(next, end, 1, None);
+            }
+            address = next;
+        }
+
+        out += &format!("fn {}() {{  // 0x{:03X}\n", function_name(analysis, entry), entry);
+        out += &writer.out;
+        out += "}\n\n";
+    }
+
+    for range in analysis.ranges(Some(Kind::Sprite)) {
+        out += &format!("sprite_{:03X}:  // {}\n", range.start, bytes(range.len()));
+        for address in range {
+            let byte = analysis.opcode_at(address) >> 8;
+            let pixels: String = (0..8)
+                .map(|bit| if byte & (0x80 >> bit) != 0 { '#' } else { '.' })
+                .collect();
+            out += &format!("{}{}  0x{:02X}\n", INDENT, pixels, byte);
+        }
+    }
+    for range in analysis.ranges(Some(Kind::Data)) {
+        out += &format!("data_{:03X}:  // {}\n", range.start, bytes(range.len()));
+        let values: Vec<String> = range
+            .map(|address| format!("0x{:02X}", analysis.opcode_at(address) >> 8))
+            .collect();
+        for row in values.chunks(DATA_ROW_SIZE) {
+            out += &format!("{}{}\n", INDENT, row.join(" "));
+        }
+    }
+    out
+}
+
+/// Octo statement assembling to the instruction, None if there is none
+fn octo_statement(opcode: u16, target: &dyn Fn(usize) -> String) -> Option<String> {
+    let x = (opcode >> 8) & 0xf;
+    let y = (opcode >> 4) & 0xf;
+    let n = opcode & 0xf;
+    let kk = opcode & 0xff;
+    let nnn = (opcode & 0xfff) as usize;
+    let (vx, vy) = (format!("v{:X}", x), format!("v{:X}", y));
+    let statement = match (opcode >> 12, n, kk) {
+        (0x0, _, _) => match opcode {
+            0x00e0 => String::from("clear"),
+            0x00ee => String::from("return"),
+            0x00fb => String::from("scroll-right"),
+            0x00fc => String::from("scroll-left"),
+            0x00fd => String::from("exit"),
+            0x00fe => String::from("lores"),
+            0x00ff => String::from("hires"),
+            _ if opcode & 0xfff0 == 0x00c0 => format!("scroll-down {}", n),
+            _ if opcode & 0xfff0 == 0x00d0 => format!("scroll-up {}", n),
+            _ => return None,
+        },
+        (0x1, _, _) => format!("jump {}", target(nnn)),
+        (0x2, _, _) => {
+            let name = target(nnn);
+            if name.starts_with("0x") {
+                format!(":call {}", name)
+            } else {
+                name
+            }
+        }
+        (0x3, _, _) => format!("if {} != 0x{:02X} then", vx, kk),
+        (0x4, _, _) => format!("if {} == 0x{:02X} then", vx, kk),
+        (0x5, 0, _) => format!("if {} != {} then", vx, vy),
+        (0x5, 2, _) => format!("save {} - {}", vx, vy),
+        (0x5, 3, _) => format!("load {} - {}", vx, vy),
+        (0x6, _, _) => format!("{} := 0x{:02X}", vx, kk),
+        (0x7, _, _) => format!("{} += 0x{:02X}", vx, kk),
+        (0x8, _, _) => {
+            let operator = match n {
+                0x0 => ":=",
+                0x1 => "|=",
+                0x2 => "&=",
+                0x3 => "^=",
+                0x4 => "+=",
+                0x5 => "-=",
+                0x6 => ">>=",
+                0x7 => "=-",
+                0xe => "<<=",
+                _ => return None,
+            };
+            format!("{} {} {}", vx, operator, vy)
+        }
+        (0x9, 0, _) => format!("if {} == {} then", vx, vy),
+        (0xa, _, _) => format!("i := {}", target(nnn)),
+        (0xb, _, _) => format!("jump0 {}", target(nnn)),
+        (0xc, _, _) => format!("{} := random 0x{:02X}", vx, kk),
+        (0xd, _, _) => format!("sprite {} {} {}", vx, vy, n),
+        (0xe, _, 0x9e) => format!("if {} -key then", vx),
+        (0xe, _, 0xa1) => format!("if {} key then", vx),
+        (0xf, _, _) => match kk {
+            0x01 => format!("plane {}", x),
+            0x02 if x == 0 => String::from("audio"),
+            0x07 => format!("{} := delay", vx),
+            0x0a => format!("{} := key", vx),
+            0x15 => format!("delay := {}", vx),
+            0x18 => format!("buzzer := {}", vx),
+            0x1e => format!("i += {}", vx),
+            0x29 => format!("i := hex {}", vx),
+            0x30 => format!("i := bighex {}", vx),
+            0x33 => format!("bcd {}", vx),
+            0x3a => format!("pitch := {}", vx),
+            0x55 => format!("save {}", vx),
+            0x65 => format!("load {}", vx),
+            0x75 => format!("saveflags {}", vx),
+            0x85 => format!("loadflags {}", vx),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(statement)
+}
+
+/// Octo source of the ROM: instructions where the analysis found code, bytes everywhere else.
+/// It assembles to the same bytes, which is checked.
+pub fn to_octo(analysis: &Analysis, rom: &[u8]) -> Result<String, String> {
+    if analysis.start() != START_ALLOWED_ADDRESS {
+        return Err(String::from("Octo programs start at 0x200"));
+    }
+    let end = analysis.start() + rom.len();
+    let instructions: BTreeSet<usize> = analysis
+        .blocks
+        .values()
+        .flat_map(|block| block.instructions.iter().copied())
+        .collect();
+
+    // What goes at each address: an instruction with its size, or a byte
+    let no_target = |_: usize| String::new();
+    let mut units = Vec::new();
+    let mut address = analysis.start();
+    while address < end {
+        let opcode = analysis.opcode_at(address);
+        let size = analysis::size(opcode);
+        let is_instruction = instructions.contains(&address)
+            && address + size <= end
+            && (opcode == 0xf000 || octo_statement(opcode, &no_target).is_some());
+        let size = if is_instruction { size } else { 1 };
+        units.push((address, is_instruction));
+        address += size;
+    }
+    let starts: BTreeSet<usize> = units.iter().map(|&(address, _)| address).collect();
+
+    let target_of = |address: usize| {
+        let opcode = analysis.opcode_at(address);
+        match opcode >> 12 {
+            _ if opcode == 0xf000 => Some(analysis.opcode_at(address + 2) as usize),
+            0x1 | 0x2 | 0xa | 0xb => Some((opcode & 0xfff) as usize),
+            _ => None,
+        }
+    };
+    let labels: BTreeSet<usize> = units
+        .iter()
+        .filter(|&&(_, is_instruction)| is_instruction)
+        .filter_map(|&(address, _)| target_of(address))
+        .filter(|target| starts.contains(target))
+        .chain([analysis.start()])
+        .collect();
+    let name = |address: usize| -> String {
+        if !labels.contains(&address) {
+            return format!("0x{:03X}", address);
+        }
+        if address == analysis.start() {
+            return String::from("main");
+        }
+        if analysis.subroutines.contains(&address) {
+            return format!("sub-{:03X}", address);
+        }
+        match analysis.kind(address) {
+            Some(Kind::Sprite) => format!("sprite-{:03X}", address),
+            Some(Kind::Data) => format!("data-{:03X}", address),
+            _ => format!("label-{:03X}", address),
+        }
+    };
+
+    let mut source = String::from("# Decompiled, assembles back to the same bytes\n\n");
+    let mut bytes: Vec<String> = Vec::new();
+    let flush = |source: &mut String, bytes: &mut Vec<String>| {
+        if !bytes.is_empty() {
+            *source += &format!("\t{}\n", bytes.join(" "));
+            bytes.clear();
+        }
+    };
+    for &(address, is_instruction) in &units {
+        if labels.contains(&address) {
+            flush(&mut source, &mut bytes);
+            source += &format!(": {}\n", name(address));
+        }
+        if !is_instruction {
+            bytes.push(format!("0x{:02X}", rom[address - analysis.start()]));
+            if bytes.len() == DATA_ROW_SIZE {
+                flush(&mut source, &mut bytes);
+            }
+            continue;
+        }
+        flush(&mut source, &mut bytes);
+        let opcode = analysis.opcode_at(address);
+        let statement = match opcode {
+            0xf000 => format!("i := long {}", name(analysis.opcode_at(address + 2) as usize)),
+            _ => octo_statement(opcode, &name).unwrap_or_default(),
+        };
+        source += &format!("\t{}\n", statement);
+    }
+    flush(&mut source, &mut bytes);
+
+    match octo::assemble(&source) {
+        Ok(assembled) if assembled == rom => Ok(source),
+        Ok(_) => Err(String::from("The Octo source doesn't assemble to the same bytes")),
+        Err(e) => Err(format!("The Octo source doesn't assemble: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::analyze;
+    use std::fs;
+
+    #[test]
+    fn writes_structured_pseudocode() {
+        let rom = octo::assemble(
+            ": main
+                i := ball
+                v0 := 0
+                loop
+                    sprite v0 v0 3
+                    if v0 == 8 begin
+                        v1 := key
+                    else
+                        v1 := 0
+                    end
+                    if v1 == 5 then wait
+                    v0 += 1
+                again
+            : wait
+                delay := v1
+                ;
+            : ball  0x40 0xE0 0x40",
+        )
+        .unwrap();
+        let analysis = analyze(&rom, 0x200);
+        assert_eq!(
+            decompile(&analysis),
+            "fn main() {  // 0x200
+    I = sprite_21C
+    V0 = 0x00
+    loop {
+        VF = draw(V0, V0, 3)
+        if (V0 == 0x08) {
+            V1 = wait_key()
+        } else {
+            V1 = 0x00
+        }
+        if (V1 == 0x05) sub_218()
+        V0 += 0x01
+    }
+}
+
+fn sub_218() {  // 0x218
+    delay = V1
+    return
+}
+
+sprite_21C:  // 3 bytes
+    .#......  0x40
+    ###.....  0xE0
+    .#......  0x40
+"
+        );
+    }
+
+    #[test]
+    fn writes_octo_that_assembles_back() {
+        let mut roms: Vec<_> = fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/roms"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        roms.sort();
+        for path in roms {
+            let rom = fs::read(&path).unwrap();
+            let analysis = analyze(&rom, 0x200);
+            if let Err(e) = to_octo(&analysis, &rom) {
+                panic!("{}: {}", path.display(), e);
+            }
+            // Decompiling doesn't get stuck either
+            decompile(&analysis);
+        }
+    }
+
+    #[test]
+    fn decompiles_roms_without_instructions() {
+        for rom in [&[][..], &[0x00]] {
+            let analysis = analyze(rom, 0x200);
+            assert_eq!(decompile(&analysis), "");
+            assert!(to_octo(&analysis, rom).is_ok());
+        }
+    }
+}
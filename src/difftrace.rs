@@ -0,0 +1,190 @@
+// Runs a ROM along a trace from another emulator (`chip8 diff-trace`), to find the first instruction
+// where the two disagree on the machine state.
+
+use crate::{
+    chip8::{Chip8, Hook},
+    trace::{self, TraceEntry},
+};
+use std::fmt;
+
+/// First difference with the reference trace
+#[derive(Debug, PartialEq)]
+pub struct Divergence {
+    // Instructions that matched before it
+    pub index: usize,
+    pub frame: u64,
+    pub field: String,
+    pub expected: TraceEntry,
+    pub actual: TraceEntry,
+    // Instruction before, likely the culprit
+    pub previous: Option<TraceEntry>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Diverged at instruction {} (frame {}) on {}",
+            self.index, self.frame, self.field
+        )?;
+        if let Some(previous) = &self.previous {
+            writeln!(f, "  after     {}", previous.to_text())?;
+        }
+        writeln!(f, "  expected  {}", self.expected.to_text())?;
+        write!(f, "  actual    {}", self.actual.to_text())
+    }
+}
+
+/// Compares every instruction with the next reference entry, stops at the first difference
+struct Comparison<'a> {
+    reference: &'a [TraceEntry],
+    frame: u64,
+    divergence: Option<Divergence>,
+}
+
+impl Hook for Comparison<'_> {
+    fn before_execute(&mut self, chip8: &Chip8, address: usize, opcode: u16) -> bool {
+        let Some(expected) = self.reference.first() else {
+            return false;
+        };
+        let mut actual = TraceEntry::new(chip8, address, opcode);
+        if expected.screen.is_some() {
+            actual.screen = Some(trace::pack_screen(chip8.framebuffer.screen()));
+        }
+        let Some(field) = difference(expected, &actual) else {
+            self.reference = &self.reference[1..];
+            return true;
+        };
+        self.divergence = Some(Divergence {
+            index: 0,
+            frame: self.frame,
+            field,
+            expected: expected.clone(),
+            actual,
+            previous: None,
+        });
+        false
+    }
+}
+
+/// Name of the first field that differs
+fn difference(expected: &TraceEntry, actual: &TraceEntry) -> Option<String> {
+    if expected.pc != actual.pc {
+        return Some(String::from("PC"));
+    }
+    if expected.opcode != actual.opcode {
+        return Some(String::from("opcode"));
+    }
+    if let Some(x) = (0..16).find(|&x| expected.v[x] != actual.v[x]) {
+        return Some(format!("V{:X}", x));
+    }
+    let fields = [
+        ("I", expected.i == actual.i),
+        ("SP", expected.sp == actual.sp),
+        ("DT", expected.delay_timer == actual.delay_timer),
+        ("ST", expected.sound_timer == actual.sound_timer),
+        ("screen", expected.screen == actual.screen),
+    ];
+    let (field, _) = fields.iter().find(|(_, same)| !same)?;
+    Some(field.to_string())
+}
+
+/// Runs the machine along the reference trace, which must start from the beginning of the ROM,
+/// with the same number of instructions per frame.
+/// Returns the number of instructions that matched, or where they stopped matching.
+pub fn compare(
+    mut chip8: Chip8,
+    cycles_per_frame: u32,
+    reference: &[TraceEntry],
+) -> Result<usize, Box<Divergence>> {
+    let mut comparison = Comparison {
+        reference,
+        frame: 0,
+        divergence: None,
+    };
+    'frames: while !comparison.reference.is_empty() {
+        for _ in 0..cycles_per_frame {
+            if !chip8.cycle_with(&mut comparison) {
+                break 'frames;
+            }
+        }
+        chip8.decrement_timers();
+        comparison.frame += 1;
+    }
+
+    match comparison.divergence {
+        Some(mut divergence) => {
+            divergence.index = reference.len() - comparison.reference.len();
+            divergence.previous = divergence
+                .index
+                .checked_sub(1)
+                .map(|i| reference[i].clone());
+            Err(Box::new(divergence))
+        }
+        None => Ok(reference.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine() -> Chip8 {
+        // LD V0, 5; LD DT, V0; loop: ADD V1, 1; JP loop
+        Chip8::from_program(&[0x60, 0x05, 0xf0, 0x15, 0x71, 0x01, 0x12, 0x04])
+    }
+
+    /// Trace of this emulator
+    fn reference(instructions: usize) -> Vec<TraceEntry> {
+        struct Recorder(Vec<TraceEntry>);
+        impl Hook for Recorder {
+            fn before_execute(&mut self, chip8: &Chip8, address: usize, opcode: u16) -> bool {
+                self.0.push(TraceEntry::new(chip8, address, opcode));
+                true
+            }
+        }
+        let mut chip8 = machine();
+        let mut recorder = Recorder(Vec::new());
+        while recorder.0.len() < instructions {
+            for _ in 0..4 {
+                chip8.cycle_with(&mut recorder);
+            }
+            chip8.decrement_timers();
+        }
+        recorder.0.truncate(instructions);
+        recorder.0
+    }
+
+    #[test]
+    fn finds_the_first_divergence() {
+        let mut reference = reference(20);
+        assert_eq!(compare(machine(), 4, &reference), Ok(20));
+
+        // The other emulator counts down faster
+        for entry in &mut reference[12..] {
+            entry.delay_timer -= 1;
+        }
+        let divergence = compare(machine(), 4, &reference).unwrap_err();
+        assert_eq!((divergence.index, divergence.frame), (12, 3));
+        assert_eq!(divergence.field, "DT");
+        assert_eq!(divergence.previous.unwrap().pc, 0x206);
+
+        // Blank screens match
+        let mut reference = reference_with_screen();
+        assert_eq!(compare(machine(), 4, &reference), Ok(1));
+        reference[0].screen.as_mut().unwrap()[0] = 0x80;
+        assert_eq!(
+            compare(machine(), 4, &reference).unwrap_err().field,
+            "screen"
+        );
+    }
+
+    fn reference_with_screen() -> Vec<TraceEntry> {
+        let line = format!(
+            "0200 6005 LD V0, 0x05 | {} 0000 00 00 00 {}",
+            ["00"; 16].join(" "),
+            "0".repeat(512)
+        );
+        vec![TraceEntry::parse(&line).unwrap()]
+    }
+}
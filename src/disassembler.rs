@@ -0,0 +1,102 @@
+// Instruction mnemonics, in the syntax of Cowgod's Chip-8 Technical Reference:
+// http://devernay.free.fr/hacks/chip8/C8TECH10.HTM
+// SUPER-CHIP and XO-CHIP instructions are named too, even though they are not emulated.
+
+/// Mnemonic of the instruction, e.g. `LD V1, 0x0A`, or `DW 0x1234` for data
+pub fn disassemble(opcode: u16) -> String {
+    let x = (opcode >> 8) & 0xf;
+    let y = (opcode >> 4) & 0xf;
+    let n = opcode & 0xf;
+    let kk = opcode & 0xff;
+    let nnn = opcode & 0xfff;
+    match opcode >> 12 {
+        0x0 => match opcode {
+            0x00e0 => String::from("CLS"),
+            0x00ee => String::from("RET"),
+            0x00fb => String::from("SCR"),
+            0x00fc => String::from("SCL"),
+            0x00fd => String::from("EXIT"),
+            0x00fe => String::from("LOW"),
+            0x00ff => String::from("HIGH"),
+            _ if opcode & 0xfff0 == 0x00c0 => format!("SCD {}", n),
+            _ if opcode & 0xfff0 == 0x00d0 => format!("SCU {}", n),
+            _ => format!("SYS 0x{:03X}", nnn),
+        },
+        0x1 => format!("JP 0x{:03X}", nnn),
+        0x2 => format!("CALL 0x{:03X}", nnn),
+        0x3 => format!("SE V{:X}, 0x{:02X}", x, kk),
+        0x4 => format!("SNE V{:X}, 0x{:02X}", x, kk),
+        0x5 => match n {
+            0 => format!("SE V{:X}, V{:X}", x, y),
+            2 => format!("SAVE V{:X}-V{:X}", x, y),
+            3 => format!("LOAD V{:X}-V{:X}", x, y),
+            _ => data(opcode),
+        },
+        0x6 => format!("LD V{:X}, 0x{:02X}", x, kk),
+        0x7 => format!("ADD V{:X}, 0x{:02X}", x, kk),
+        0x8 => {
+            let operation = match n {
+                0x0 => "LD",
+                0x1 => "OR",
+                0x2 => "AND",
+                0x3 => "XOR",
+                0x4 => "ADD",
+                0x5 => "SUB",
+                0x6 => "SHR",
+                0x7 => "SUBN",
+                0xe => "SHL",
+                _ => return data(opcode),
+            };
+            format!("{} V{:X}, V{:X}", operation, x, y)
+        }
+        0x9 if n == 0 => format!("SNE V{:X}, V{:X}", x, y),
+        0xa => format!("LD I, 0x{:03X}", nnn),
+        0xb => format!("JP V0, 0x{:03X}", nnn),
+        0xc => format!("RND V{:X}, 0x{:02X}", x, kk),
+        0xd => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        0xe if kk == 0x9e => format!("SKP V{:X}", x),
+        0xe if kk == 0xa1 => format!("SKNP V{:X}", x),
+        0xf => match kk {
+            0x00 if x == 0 => String::from("LD I, long"),
+            0x01 => format!("PLANE {}", x),
+            0x02 if x == 0 => String::from("AUDIO"),
+            0x07 => format!("LD V{:X}, DT", x),
+            0x0a => format!("LD V{:X}, K", x),
+            0x15 => format!("LD DT, V{:X}", x),
+            0x18 => format!("LD ST, V{:X}", x),
+            0x1e => format!("ADD I, V{:X}", x),
+            0x29 => format!("LD F, V{:X}", x),
+            0x30 => format!("LD HF, V{:X}", x),
+            0x33 => format!("LD B, V{:X}", x),
+            0x3a => format!("PITCH V{:X}", x),
+            0x55 => format!("LD [I], V{:X}", x),
+            0x65 => format!("LD V{:X}, [I]", x),
+            0x75 => format!("LD R, V{:X}", x),
+            0x85 => format!("LD V{:X}, R", x),
+            _ => data(opcode),
+        },
+        _ => data(opcode),
+    }
+}
+
+fn data(opcode: u16) -> String {
+    format!("DW 0x{:04X}", opcode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_instructions() {
+        assert_eq!(disassemble(0x00e0), "CLS");
+        assert_eq!(disassemble(0x12a4), "JP 0x2A4");
+        assert_eq!(disassemble(0x6a0f), "LD VA, 0x0F");
+        assert_eq!(disassemble(0x8ce6), "SHR VC, VE");
+        assert_eq!(disassemble(0xd125), "DRW V1, V2, 5");
+        assert_eq!(disassemble(0xfb65), "LD VB, [I]");
+        assert_eq!(disassemble(0x00c4), "SCD 4");
+        assert_eq!(disassemble(0x8008), "DW 0x8008");
+        assert_eq!(disassemble(0xe1ff), "DW 0xE1FF");
+    }
+}
@@ -1,26 +1,51 @@
+use crate::{
+    debugger::{PanelLine, Style},
+    font::text_glyph,
+    framebuffer::{Framebuffer, HEIGHT, WIDTH},
+    launcher::TextLine,
+    metadata::Colors,
+    sprites::{Image, Pixel},
+};
 use sdl2::{pixels::Color, rect::Rect, render::Canvas, video::Window, Sdl};
 
-// The original implementation of the Chip-8 language used a 64x32 monochrome pixels
-const WIDTH: usize = 64;
-const HEIGHT: usize = 32;
 const SCALE: usize = 15;
 
+// Size of a text font pixel, and of a character including spacing, in window pixels
+const TEXT_SCALE: usize = 2;
+const CHAR_WIDTH: usize = 6 * TEXT_SCALE;
+const CHAR_HEIGHT: usize = 9 * TEXT_SCALE;
+// Number of lines of text fitting in the window
+pub const TEXT_ROWS: usize = HEIGHT * SCALE / CHAR_HEIGHT;
+// Debugger panel on the right of the screen, in characters
+const PANEL_COLUMNS: usize = 32;
+const PANEL_MARGIN: usize = CHAR_WIDTH / 2;
+
 // Colors
 const OFF_COLOR: Color = Color::RGB(248, 171, 18);
 const ON_COLOR: Color = Color::RGB(22, 22, 22);
+const PANEL_COLOR: Color = Color::RGB(40, 40, 40);
 
+/// Window showing the framebuffer, scaled up
 pub struct Display {
-    memory: [[u8; WIDTH]; HEIGHT],
     canvas: Canvas<Window>,
+    on_color: Color,
+    off_color: Color,
+    panel: bool,
 }
 
 impl Display {
-    pub fn new(sdl: &Sdl, window_title: &str) -> Self {
+    /// Opens the window, wider when it has room for the debugger panel
+    pub fn new(sdl: &Sdl, window_title: &str, panel: bool) -> Self {
         let video_subsystem = sdl.video().unwrap();
+        let panel_width = if panel {
+            PANEL_COLUMNS * CHAR_WIDTH + 2 * PANEL_MARGIN
+        } else {
+            0
+        };
         let window = video_subsystem
             .window(
                 window_title,
-                (WIDTH * SCALE) as u32,
+                (WIDTH * SCALE + panel_width) as u32,
                 (HEIGHT * SCALE) as u32,
             )
             .position_centered()
@@ -33,52 +58,45 @@ impl Display {
         canvas.present();
 
         Display {
-            memory: [[0; WIDTH]; HEIGHT],
             canvas,
+            on_color: ON_COLOR,
+            off_color: OFF_COLOR,
+            panel,
         }
     }
 
-    pub fn clear(&mut self) {
-        self.memory = [[0; WIDTH]; HEIGHT];
+    /// Uses the given colors, or the default ones
+    pub fn set_colors(&mut self, colors: Option<Colors>) {
+        (self.on_color, self.off_color) = match colors {
+            Some(Colors { on, off }) => (
+                Color::RGB(on[0], on[1], on[2]),
+                Color::RGB(off[0], off[1], off[2]),
+            ),
+            None => (ON_COLOR, OFF_COLOR),
+        };
     }
 
-    /// Draws all pixels from sprite into memory buffer and returns true if collision
-    /// Collision means we already have a pixel ON (1) in the memory and the sprite pixel is trying to override it with a 1 value.
-    pub fn draw(&mut self, x: usize, y: usize, sprite: &[u8]) -> bool {
-        let mut collision = false;
-
-        let width = 8; // Sprite always take 8 pixels
-        let height = sprite.len();
-        for row in 0..height {
-            // Loop through each pixel from that row and check if pixel is ON, one by one
-            for col in 0..width {
-                let pixel = sprite[row] & (0x80 >> col);
-                // Do we have pixel on?
-                if pixel != 0 {
-                    let x_norm = (x + col) % WIDTH;
-                    let y_norm = (y + row) % HEIGHT;
-                    // And is memory pixel also on? => collision!
-                    if self.memory[y_norm][x_norm] == 1 {
-                        collision = true;
-                    }
-                    // XOR memory pixel and sprite pixel
-                    self.memory[y_norm][x_norm] ^= 1;
-                }
-            }
-        }
-
-        collision
+    pub fn set_title(&mut self, title: &str) {
+        self.canvas.window_mut().set_title(title).unwrap();
     }
 
-    pub fn draw_screen(&mut self) {
+    /// Draws the framebuffer, with lines of text on top such as the overlay messages,
+    /// and the debugger panel next to it if the window has room for it
+    pub fn draw_screen(
+        &mut self,
+        framebuffer: &Framebuffer,
+        overlay: &[String],
+        panel: &[PanelLine],
+    ) {
+        let screen = framebuffer.screen();
         // Clear previous canvas
-        self.canvas.set_draw_color(OFF_COLOR);
+        self.canvas.set_draw_color(self.off_color);
         self.canvas.clear();
         // Draw pixel any time we have a pixel at true
-        self.canvas.set_draw_color(ON_COLOR);
-        for x in 0..WIDTH {
-            for y in 0..HEIGHT {
-                if self.memory[y][x] == 1 {
+        self.canvas.set_draw_color(self.on_color);
+        for (y, row) in screen.iter().enumerate() {
+            for (x, &pixel) in row.iter().enumerate() {
+                if pixel == 1 {
                     let rect = Rect::new(
                         (x * SCALE) as i32,
                         (y * SCALE) as i32,
@@ -91,6 +109,121 @@ impl Display {
             }
         }
 
+        // Text in the game colors, inverted so that it stands out from the game
+        for (row, text) in overlay.iter().enumerate() {
+            let width = (text.chars().count() + 1) * CHAR_WIDTH;
+            let band = Rect::new(
+                0,
+                (row * CHAR_HEIGHT) as i32,
+                width as u32,
+                CHAR_HEIGHT as u32,
+            );
+            self.canvas.set_draw_color(self.on_color);
+            self.canvas.fill_rect(band).unwrap();
+            self.canvas.set_draw_color(self.off_color);
+            self.draw_chars(text, CHAR_WIDTH / 2, row * CHAR_HEIGHT);
+        }
+
+        if self.panel {
+            self.draw_panel(panel);
+        }
         self.canvas.present();
     }
+
+    fn draw_panel(&mut self, lines: &[PanelLine]) {
+        let (width, height) = self.canvas.output_size().unwrap();
+        let left = WIDTH * SCALE;
+        let background = Rect::new(left as i32, 0, width - left as u32, height);
+        self.canvas.set_draw_color(PANEL_COLOR);
+        self.canvas.fill_rect(background).unwrap();
+
+        for (row, line) in lines.iter().enumerate() {
+            let mut x = left + PANEL_MARGIN;
+            for span in &line.spans {
+                let color = match span.style {
+                    Style::Normal => Color::RGB(210, 210, 210),
+                    Style::Label => OFF_COLOR,
+                    Style::Current => Color::RGB(120, 230, 120),
+                    Style::Index => Color::RGB(110, 180, 255),
+                    Style::Breakpoint => Color::RGB(240, 80, 80),
+                };
+                self.canvas.set_draw_color(color);
+                self.draw_chars(&span.text, x, row * CHAR_HEIGHT);
+                x += span.text.chars().count() * CHAR_WIDTH;
+            }
+            if let Some(image) = &line.image {
+                self.draw_image(image, x, row * CHAR_HEIGHT);
+            }
+        }
+    }
+
+    /// Draws sprites in the game colors, a pixel the size of a text font pixel
+    fn draw_image(&mut self, image: &Image, x: usize, y: usize) {
+        for dy in 0..image.height {
+            for dx in 0..image.width {
+                let color = match image.pixel(dx, dy) {
+                    Pixel::Off => self.off_color,
+                    Pixel::On => self.on_color,
+                    Pixel::Gap => continue,
+                };
+                let rect = Rect::new(
+                    (x + dx * TEXT_SCALE) as i32,
+                    (y + dy * TEXT_SCALE) as i32,
+                    TEXT_SCALE as u32,
+                    TEXT_SCALE as u32,
+                );
+                self.canvas.set_draw_color(color);
+                self.canvas.fill_rect(rect).unwrap();
+            }
+        }
+    }
+
+    /// Index of the debugger panel line at the window position, if any
+    pub fn panel_line_at(&self, x: i32, y: i32) -> Option<usize> {
+        if !self.panel || x < (WIDTH * SCALE) as i32 || y < 0 {
+            return None;
+        }
+        Some(y as usize / CHAR_HEIGHT)
+    }
+
+    /// Draws lines of text instead of the framebuffer, e.g. for the launcher
+    pub fn draw_text(&mut self, lines: &[TextLine]) {
+        self.canvas.set_draw_color(OFF_COLOR);
+        self.canvas.clear();
+        for (row, line) in lines.iter().enumerate() {
+            let y = row * CHAR_HEIGHT;
+            let (foreground, background) = if line.highlight {
+                (OFF_COLOR, ON_COLOR)
+            } else {
+                (ON_COLOR, OFF_COLOR)
+            };
+            self.canvas.set_draw_color(background);
+            let band = Rect::new(0, y as i32, (WIDTH * SCALE) as u32, CHAR_HEIGHT as u32);
+            self.canvas.fill_rect(band).unwrap();
+
+            self.canvas.set_draw_color(foreground);
+            self.draw_chars(&line.text, 0, y);
+        }
+
+        self.canvas.present();
+    }
+
+    /// Draws text in the current color from the given window position, with the built-in font
+    fn draw_chars(&mut self, text: &str, x: usize, y: usize) {
+        for (column, c) in text.chars().enumerate() {
+            for (dx, bits) in text_glyph(c).iter().enumerate() {
+                for dy in 0..8 {
+                    if bits >> dy & 1 == 1 {
+                        let rect = Rect::new(
+                            (x + column * CHAR_WIDTH + dx * TEXT_SCALE) as i32,
+                            (y + dy * TEXT_SCALE) as i32,
+                            TEXT_SCALE as u32,
+                            TEXT_SCALE as u32,
+                        );
+                        self.canvas.fill_rect(rect).unwrap();
+                    }
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,86 @@
+// The original implementation of the Chip-8 language used a 64x32 monochrome pixels
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
+
+// One byte per pixel, 1 when the pixel is on
+pub type Screen = [[u8; WIDTH]; HEIGHT];
+
+pub struct Framebuffer {
+    memory: Screen,
+}
+
+impl Default for Framebuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Framebuffer {
+    pub fn new() -> Self {
+        Framebuffer {
+            memory: [[0; WIDTH]; HEIGHT],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.memory = [[0; WIDTH]; HEIGHT];
+    }
+
+    pub fn screen(&self) -> &Screen {
+        &self.memory
+    }
+
+    pub fn set_screen(&mut self, screen: Screen) {
+        self.memory = screen;
+    }
+
+    /// Draws all pixels from sprite into memory buffer and returns true if collision
+    /// Collision means we already have a pixel ON (1) in the memory and the sprite pixel is trying to override it with a 1 value.
+    /// The sprite starts on screen, its pixels going past the edges either wrap around or are clipped.
+    pub fn draw(&mut self, x: usize, y: usize, sprite: &[u8], clip: bool) -> bool {
+        let mut collision = false;
+        let (x, y) = (x % WIDTH, y % HEIGHT);
+
+        let width = 8; // Sprite always take 8 pixels
+        for (row, byte) in sprite.iter().enumerate() {
+            // Loop through each pixel from that row and check if pixel is ON, one by one
+            for col in 0..width {
+                let pixel = byte & (0x80 >> col);
+                // Do we have pixel on?
+                let outside = x + col >= WIDTH || y + row >= HEIGHT;
+                if pixel != 0 && !(clip && outside) {
+                    let x_norm = (x + col) % WIDTH;
+                    let y_norm = (y + row) % HEIGHT;
+                    // And is memory pixel also on? => collision!
+                    if self.memory[y_norm][x_norm] == 1 {
+                        collision = true;
+                    }
+                    // XOR memory pixel and sprite pixel
+                    self.memory[y_norm][x_norm] ^= 1;
+                }
+            }
+        }
+
+        collision
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sprites_wrap_or_clip() {
+        let mut framebuffer = Framebuffer::new();
+        assert!(!framebuffer.draw(WIDTH + 62, 31, &[0xe0, 0xe0], false));
+        assert_eq!(framebuffer.screen()[31][63], 1);
+        assert_eq!(framebuffer.screen()[31][0], 1);
+        assert_eq!(framebuffer.screen()[0][62], 1);
+
+        framebuffer.clear();
+        framebuffer.draw(62, 31, &[0xff, 0xff], true);
+        assert_eq!(framebuffer.screen()[31][62..], [1, 1]);
+        assert_eq!(framebuffer.screen()[0][0], 0);
+        assert_eq!(framebuffer.screen()[31][0], 0);
+    }
+}
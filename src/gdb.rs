@@ -0,0 +1,496 @@
+// GDB Remote Serial Protocol stub (--gdb), enough for GDB and other RSP clients to inspect and drive the machine:
+// https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+//
+// Registers, numbered as GDB asks for them, in CHIP-8 byte order (big-endian):
+// 0-15 are V0-VF (1 byte), 16 is I (2 bytes), 17 is PC (2 bytes) and 18 is SP (1 byte).
+// GDB reads this layout from the target description, target.xml.
+
+use crate::{chip8::Chip8, session::Session};
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+};
+
+const REGISTERS: usize = 19;
+// Reply sent whenever the machine stops: SIGTRAP
+const STOP_REPLY: &str = "S05";
+// Sent by GDB to interrupt a running machine
+const INTERRUPT: u8 = 0x03;
+
+pub struct GdbStub {
+    stream: TcpStream,
+    // Bytes received but not handled yet, such as the start of a packet
+    received: Vec<u8>,
+    // Whether GDB let the machine run, until it stops at a breakpoint or is interrupted
+    running: bool,
+}
+
+impl GdbStub {
+    /// Waits on localhost for GDB to connect, the machine is halted until it says to continue
+    pub fn listen(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        println!("Waiting for GDB on {}", listener.local_addr()?);
+        let (stream, address) = listener.accept()?;
+        println!("GDB connected from {}", address);
+        Self::new(stream)
+    }
+
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(GdbStub {
+            stream,
+            received: Vec::new(),
+            running: false,
+        })
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Halts the machine after it stopped at a breakpoint, and tells GDB
+    pub fn stop(&mut self) -> io::Result<()> {
+        self.running = false;
+        self.send(STOP_REPLY)
+    }
+
+    /// Handles what GDB sent since the last call, without waiting.
+    /// Called every frame, returns false once GDB is gone.
+    pub fn poll(&mut self, session: &mut Session) -> io::Result<bool> {
+        let mut buffer = [0; 1024];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Ok(false),
+                Ok(length) => self.received.extend_from_slice(&buffer[..length]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        while let Some(packet) = self.next_packet()? {
+            let Some(packet) = packet else {
+                // Interrupted
+                if self.running {
+                    self.stop()?;
+                }
+                continue;
+            };
+            match packet.as_str() {
+                // Kill
+                "k" => return Ok(false),
+                // Detach, the machine runs freely from now on
+                "D" => {
+                    self.send("OK")?;
+                    return Ok(false);
+                }
+                _ => (),
+            }
+            if let Some(reply) = self.handle(&packet, session) {
+                self.send(&reply)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Takes the next packet out of the received bytes, acknowledging it.
+    /// Returns Some(None) for an interrupt.
+    fn next_packet(&mut self) -> io::Result<Option<Option<String>>> {
+        loop {
+            let Some(&first) = self.received.first() else {
+                return Ok(None);
+            };
+            if first == INTERRUPT {
+                self.received.remove(0);
+                return Ok(Some(None));
+            }
+            if first != b'$' {
+                // Acknowledgements of our replies, and noise
+                self.received.remove(0);
+                continue;
+            }
+            let Some(end) = self.received.iter().position(|&byte| byte == b'#') else {
+                return Ok(None);
+            };
+            if self.received.len() < end + 3 {
+                return Ok(None);
+            }
+            let packet: Vec<u8> = self.received.drain(..end + 3).collect();
+            let data = &packet[1..end];
+            let checksum = std::str::from_utf8(&packet[end + 1..])
+                .ok()
+                .and_then(|text| u8::from_str_radix(text, 16).ok());
+            if checksum != Some(checksum_of(data)) {
+                // Ask GDB to send it again
+                self.write(b"-")?;
+                continue;
+            }
+            self.write(b"+")?;
+            return Ok(Some(Some(String::from_utf8_lossy(data).into_owned())));
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.write(packet.as_bytes())
+    }
+
+    /// Writes everything, waiting for room in the socket buffer
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.stream.set_nonblocking(false)?;
+        let written = self.stream.write_all(bytes);
+        self.stream.set_nonblocking(true)?;
+        written
+    }
+
+    /// Reply to a packet, None to reply later (continue)
+    fn handle(&mut self, packet: &str, session: &mut Session) -> Option<String> {
+        let Some(command) = packet.get(..1) else {
+            return Some(String::new());
+        };
+        let arguments = &packet[1..];
+        let reply = match command {
+            "?" => String::from(STOP_REPLY),
+            "g" => (0..REGISTERS)
+                .map(|register| encode(&read_register(&session.chip8, register)))
+                .collect(),
+            "G" => reply(write_registers(&mut session.chip8, arguments)),
+            "p" => usize::from_str_radix(arguments, 16)
+                .ok()
+                .filter(|&register| register < REGISTERS)
+                .map_or_else(error, |register| encode(&read_register(&session.chip8, register))),
+            "P" => reply(arguments.split_once('=').and_then(|(register, value)| {
+                let register = usize::from_str_radix(register, 16).ok()?;
+                write_register(&mut session.chip8, register, &decode(value)?)
+            })),
+            "m" => read_memory(&session.chip8, arguments).map_or_else(error, |bytes| encode(&bytes)),
+            "M" => reply(write_memory(&mut session.chip8, arguments)),
+            // With the hooks, for the breakpoints, the trace and the sprites to see it
+            "s" => {
+                session.step();
+                String::from(STOP_REPLY)
+            }
+            "c" => {
+                self.running = true;
+                return None;
+            }
+            // Software and hardware breakpoints are the same thing here
+            "Z" | "z" => match parse_breakpoint(arguments) {
+                Some(address) => {
+                    if command == "Z" {
+                        session.breakpoints.insert(address);
+                    } else {
+                        session.breakpoints.remove(address);
+                    }
+                    String::from("OK")
+                }
+                None => String::new(),
+            },
+            // Threads: there is only one
+            "H" | "T" => String::from("OK"),
+            "q" if packet.starts_with("qSupported") => {
+                String::from("PacketSize=1000;qXfer:features:read+")
+            }
+            "q" if packet.starts_with("qXfer:features:read:") => {
+                read_target_description(&packet["qXfer:features:read:".len()..])
+                    .unwrap_or_else(error)
+            }
+            "q" if packet == "qAttached" => String::from("1"),
+            "q" if packet == "qC" => String::from("QC1"),
+            "q" if packet == "qfThreadInfo" => String::from("m1"),
+            "q" if packet == "qsThreadInfo" => String::from("l"),
+            // Not supported
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+}
+
+/// Layout of the registers for GDB, which knows no CHIP-8 architecture
+fn target_description() -> String {
+    let register = |name: String, bits: usize| {
+        format!("    <reg name=\"{}\" bitsize=\"{}\" type=\"uint{}\"/>\n", name, bits, bits)
+    };
+    let registers: String = (0..16)
+        .map(|x| register(format!("v{:x}", x), 8))
+        .chain([
+            register(String::from("i"), 16),
+            register(String::from("pc"), 16),
+            register(String::from("sp"), 8),
+        ])
+        .collect();
+    format!(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n  <feature name=\"org.chip8.core\">\n{}  </feature>\n</target>\n",
+        registers
+    )
+}
+
+/// Part of target.xml, `target.xml:OFFSET,LENGTH`: `m` and the data while there is more, `l` for the end
+fn read_target_description(arguments: &str) -> Option<String> {
+    let (offset, length) = arguments.strip_prefix("target.xml:")?.split_once(',')?;
+    let offset = usize::from_str_radix(offset, 16).ok()?;
+    let length = usize::from_str_radix(length, 16).ok()?;
+    let description = target_description();
+    let part = description.get(offset.min(description.len())..)?;
+    match part.len() > length {
+        true => Some(format!("m{}", &part[..length])),
+        false => Some(format!("l{}", part)),
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum: u8, &byte| sum.wrapping_add(byte))
+}
+
+fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|start| u8::from_str_radix(hex.get(start..start + 2)?, 16).ok())
+        .collect()
+}
+
+fn reply(done: Option<()>) -> String {
+    done.map_or_else(error, |_| String::from("OK"))
+}
+
+fn error() -> String {
+    String::from("E01")
+}
+
+fn read_register(chip8: &Chip8, register: usize) -> Vec<u8> {
+    match register {
+        0..=15 => vec![chip8.v()[register]],
+        16 => (chip8.i() as u16).to_be_bytes().to_vec(),
+        17 => (chip8.pc() as u16).to_be_bytes().to_vec(),
+        _ => vec![chip8.sp() as u8],
+    }
+}
+
+fn write_register(chip8: &mut Chip8, register: usize, value: &[u8]) -> Option<()> {
+    let word = || -> Option<u16> { Some(u16::from_be_bytes(value.try_into().ok()?)) };
+    match (register, value) {
+        (0..=15, &[byte]) => chip8.set_v(register, byte),
+        (16, _) => chip8.set_i(word()?),
+        (17, _) => chip8.set_pc(word()? as usize).then_some(())?,
+        (18, &[sp]) => chip8.set_sp(sp as usize).then_some(())?,
+        _ => return None,
+    }
+    Some(())
+}
+
+fn write_registers(chip8: &mut Chip8, hex: &str) -> Option<()> {
+    let mut values = decode(hex)?;
+    for register in 0..REGISTERS {
+        let length = read_register(chip8, register).len();
+        if values.len() < length {
+            return None;
+        }
+        let rest = values.split_off(length);
+        write_register(chip8, register, &values)?;
+        values = rest;
+    }
+    Some(())
+}
+
+/// `ADDR,LENGTH` in hex
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let (address, length) = text.split_once(',')?;
+    Some((
+        usize::from_str_radix(address, 16).ok()?,
+        usize::from_str_radix(length, 16).ok()?,
+    ))
+}
+
+fn read_memory(chip8: &Chip8, arguments: &str) -> Option<Vec<u8>> {
+    let (address, length) = parse_range(arguments)?;
+    let memory = chip8.memory();
+    // Reads may run past the end of memory, what is there is returned
+    let end = address.checked_add(length)?.min(memory.len());
+    let bytes = memory.get(address..end)?;
+    (!bytes.is_empty() || length == 0).then(|| bytes.to_vec())
+}
+
+fn write_memory(chip8: &mut Chip8, arguments: &str) -> Option<()> {
+    let (range, data) = arguments.split_once(':')?;
+    let (address, length) = parse_range(range)?;
+    let bytes = decode(data).filter(|bytes| bytes.len() == length)?;
+    chip8.write_memory(address, &bytes).then_some(())
+}
+
+/// `TYPE,ADDR,KIND` for software (0) and hardware (1) breakpoints
+fn parse_breakpoint(arguments: &str) -> Option<usize> {
+    let mut fields = arguments.split(',');
+    let kind = fields.next()?;
+    let address = usize::from_str_radix(fields.next()?, 16).ok()?;
+    matches!(kind, "0" | "1").then_some(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        debugger::Breakpoints,
+        speaker::{Speaker, ToneConfig},
+        sprites::SpriteRecorder,
+    };
+    use std::{thread, time::Duration};
+
+    struct Client {
+        stream: TcpStream,
+        stub: GdbStub,
+        session: Session,
+    }
+
+    impl Client {
+        fn connect() -> Self {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_millis(10)))
+                .unwrap();
+            let stub = GdbStub::new(listener.accept().unwrap().0).unwrap();
+            // LD V1, 0x42; ADD V1, 1; JP 0x202
+            let chip8 = Chip8::from_program(&[0x61, 0x42, 0x71, 0x01, 0x12, 0x02]);
+            let session = Session {
+                chip8,
+                speaker: Speaker::silent(ToneConfig::default()),
+                replay: None,
+                recorder: None,
+                frame: 0,
+                instructions: 0,
+                cycles_per_frame: 10,
+                breakpoints: Breakpoints::new(),
+                tracer: None,
+                sprites: SpriteRecorder::new(),
+            };
+            Client {
+                stream,
+                stub,
+                session,
+            }
+        }
+
+        fn send(&mut self, bytes: &[u8]) {
+            self.stream.write_all(bytes).unwrap();
+            thread::sleep(Duration::from_millis(5));
+            let connected = self.stub.poll(&mut self.session).unwrap();
+            assert!(connected);
+        }
+
+        /// Sends the packet, returns the reply after the acknowledgement
+        fn ask(&mut self, packet: &str) -> String {
+            self.send(format!("${}#{:02x}", packet, checksum_of(packet.as_bytes())).as_bytes());
+            let reply = self.receive();
+            assert!(reply.starts_with('+'), "{}", reply);
+            reply[1..].to_string()
+        }
+
+        fn receive(&mut self) -> String {
+            let mut received = Vec::new();
+            let mut buffer = [0; 256];
+            while let Ok(length) = self.stream.read(&mut buffer) {
+                received.extend_from_slice(&buffer[..length]);
+            }
+            let text = String::from_utf8(received).unwrap();
+            // Drop the packet framing
+            match (text.find('$'), text.rfind('#')) {
+                (Some(start), Some(end)) => format!("{}{}", &text[..start], &text[start + 1..end]),
+                _ => text,
+            }
+        }
+    }
+
+    #[test]
+    fn reads_and_writes_the_machine() {
+        let mut client = Client::connect();
+        assert_eq!(
+            client.ask("qSupported:swbreak+"),
+            "PacketSize=1000;qXfer:features:read+"
+        );
+        assert_eq!(client.ask("?"), STOP_REPLY);
+
+        assert_eq!(client.ask("s"), STOP_REPLY);
+        // V1 = 0x42, I = 0, PC = 0x202, SP = 0
+        let registers = format!("00{}{}0000020200", "42", "00".repeat(14));
+        assert_eq!(client.ask("g"), registers);
+        assert_eq!(client.ask("p11"), "0202");
+
+        assert_eq!(client.ask("P10=0a2b"), "OK");
+        assert_eq!(client.session.chip8.i(), 0xa2b);
+        assert_eq!(client.ask("P12=11"), "E01");
+        assert_eq!(client.ask("M300,2:beef"), "OK");
+        assert_eq!(client.ask("m2fe,4"), "0000beef");
+        assert_eq!(client.ask("mffe,4"), "0000");
+        assert_eq!(client.ask("m1000,1"), "E01");
+        // Ranges past the end of the address space
+        assert_eq!(client.ask("mffffffffffffffff,1"), "E01");
+        assert_eq!(client.ask("Mffffffffffffffff,1:00"), "E01");
+        assert_eq!(client.ask("vMustReplyEmpty"), "");
+        assert_eq!(client.ask(""), "");
+
+        // A bad checksum is refused
+        client.send(b"$g#00");
+        assert_eq!(client.receive(), "-");
+    }
+
+    #[test]
+    fn describes_the_registers() {
+        let mut client = Client::connect();
+        let mut description = String::new();
+        loop {
+            let packet = format!("qXfer:features:read:target.xml:{:x},40", description.len());
+            let reply = client.ask(&packet);
+            description += &reply[1..];
+            if reply.starts_with('l') {
+                break;
+            }
+            assert!(reply.starts_with('m'), "{}", reply);
+        }
+        assert_eq!(description, target_description());
+        assert_eq!(description.matches("<reg ").count(), REGISTERS);
+        assert!(description.contains("<reg name=\"vf\" bitsize=\"8\" type=\"uint8\"/>"));
+        assert!(description.contains("<reg name=\"pc\" bitsize=\"16\" type=\"uint16\"/>"));
+        assert_eq!(client.ask("qXfer:features:read:other.xml:0,40"), "E01");
+    }
+
+    #[test]
+    fn continues_until_breakpoints_or_interrupts() {
+        let mut client = Client::connect();
+        assert_eq!(client.ask("Z0,204,2"), "OK");
+        assert!(client.session.breakpoints.contains(0x204));
+
+        // Replies once the machine stops
+        assert_eq!(client.ask("c"), "");
+        assert!(client.stub.is_running());
+        while !client.session.breakpoints.should_stop(client.session.chip8.pc()) {
+            client.session.chip8.cycle();
+        }
+        client.stub.stop().unwrap();
+        assert_eq!(client.receive(), STOP_REPLY);
+        assert_eq!(client.session.chip8.pc(), 0x204);
+
+        // Stepping runs the instruction under the breakpoint, through the hooks
+        assert_eq!(client.ask("s"), STOP_REPLY);
+        assert_eq!(client.session.chip8.pc(), 0x202);
+        assert_eq!(client.session.instructions, 1);
+
+        assert_eq!(client.ask("z0,204,2"), "OK");
+        assert_eq!(client.ask("c"), "");
+        client.send(&[INTERRUPT]);
+        assert!(!client.stub.is_running());
+        assert_eq!(client.receive(), STOP_REPLY);
+
+        client.stream.write_all(b"$D#44").unwrap();
+        thread::sleep(Duration::from_millis(5));
+        let connected = client.stub.poll(&mut client.session).unwrap();
+        assert!(!connected);
+    }
+}
@@ -3,14 +3,47 @@ use sdl2::keyboard::Keycode;
 // 16 keys from 0 to F
 const SIZE: usize = 16;
 
+/// Progress of a FX0A key wait
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum KeyWait {
+    Idle,
+    // Waiting for a key to go down
+    Press,
+    // A key went down, waiting for it to be released
+    Release(usize),
+}
+
+/// A key going down or up
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyChange {
+    pub key: usize,
+    pub state: bool,
+}
+
 pub struct Keypad {
     keys: [bool; SIZE],
+    // State changes since they were last taken, e.g. to be recorded in a movie
+    changes: Vec<KeyChange>,
+    // Edges latched since they were last taken or cleared
+    pressed: [bool; SIZE],
+    released: [bool; SIZE],
+    wait: KeyWait,
+}
+
+impl Default for Keypad {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Keypad {
     pub fn new() -> Self {
         Keypad {
             keys: [false; SIZE],
+            changes: Vec::new(),
+            pressed: [false; SIZE],
+            released: [false; SIZE],
+            wait: KeyWait::Idle,
         }
     }
 
@@ -18,6 +51,80 @@ impl Keypad {
         self.keys[index]
     }
 
+    /// Updates the state of a key, latching a press or release edge if the state changed.
+    /// Repeated events with the same state (e.g. keyboard auto-repeat) are ignored.
+    pub fn set_key(&mut self, index: usize, state: bool) {
+        if self.keys[index] == state {
+            return;
+        }
+
+        self.keys[index] = state;
+        self.changes.push(KeyChange { key: index, state });
+        if state {
+            self.pressed[index] = true;
+        } else {
+            self.released[index] = true;
+        }
+    }
+
+    /// Returns every state change since the last call, oldest first
+    pub fn take_changes(&mut self) -> Vec<KeyChange> {
+        std::mem::take(&mut self.changes)
+    }
+
+    /// Returns the lowest key that went down since the last call, and consumes its edge
+    pub fn take_pressed(&mut self) -> Option<usize> {
+        let index = self.pressed.iter().position(|&edge| edge)?;
+        self.pressed[index] = false;
+        Some(index)
+    }
+
+    /// Forgets every latched press and release edge
+    pub fn clear_edges(&mut self) {
+        self.pressed = [false; SIZE];
+        self.released = [false; SIZE];
+    }
+
+    /// Polled by FX0A until it returns the key to store.
+    /// Only keys pressed after the wait started are registered, so a key still held from before is ignored.
+    /// With `on_release`, the key is registered once it goes back up, like the COSMAC VIP.
+    pub fn wait_for_key(&mut self, on_release: bool) -> Option<usize> {
+        match self.wait {
+            KeyWait::Idle => {
+                self.clear_edges();
+                self.wait = KeyWait::Press;
+                None
+            }
+            KeyWait::Press => {
+                let index = self.take_pressed()?;
+                if on_release {
+                    // A release latched before this press is stale
+                    if self.keys[index] {
+                        self.released[index] = false;
+                    }
+                    self.wait = KeyWait::Release(index);
+                    None
+                } else {
+                    self.wait = KeyWait::Idle;
+                    Some(index)
+                }
+            }
+            KeyWait::Release(index) => {
+                if !self.released[index] {
+                    return None;
+                }
+                self.released[index] = false;
+                self.wait = KeyWait::Idle;
+                Some(index)
+            }
+        }
+    }
+
+    /// True while FX0A holds a pressed key and waits for its release
+    pub fn is_waiting_release(&self) -> bool {
+        matches!(self.wait, KeyWait::Release(_))
+    }
+
     pub fn handle_key(&mut self, key: Keycode, state: bool) {
         /*
         Keypad       Keyboard
@@ -52,12 +159,111 @@ impl Keypad {
             _ => None,
         };
 
-        if index.is_some() {
-            self.keys[index.unwrap()] = state;
+        if let Some(index) = index {
+            self.set_key(index, state);
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Polls the wait a few times, like FX0A being executed on consecutive cycles
+    fn poll(keypad: &mut Keypad, on_release: bool) -> Option<usize> {
+        (0..3).find_map(|_| keypad.wait_for_key(on_release))
+    }
+
+    #[test]
+    fn edges_are_latched_once() {
+        let mut keypad = Keypad::new();
+        keypad.set_key(0x5, true);
+        keypad.set_key(0x5, true); // auto-repeat
+        assert_eq!(keypad.take_pressed(), Some(0x5));
+        assert_eq!(keypad.take_pressed(), None);
+
+        keypad.set_key(0x5, false);
+        assert_eq!(keypad.take_pressed(), None);
+        assert_eq!(
+            keypad.take_changes(),
+            vec![
+                KeyChange {
+                    key: 0x5,
+                    state: true
+                },
+                KeyChange {
+                    key: 0x5,
+                    state: false
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn held_key_is_ignored_until_pressed_again() {
+        let mut keypad = Keypad::new();
+        keypad.set_key(0xa, true);
+        assert_eq!(poll(&mut keypad, false), None);
+
+        keypad.set_key(0xa, false);
+        assert_eq!(poll(&mut keypad, false), None);
+        keypad.set_key(0xa, true);
+        assert_eq!(poll(&mut keypad, false), Some(0xa));
+    }
+
+    #[test]
+    fn release_mode_waits_for_key_up() {
+        let mut keypad = Keypad::new();
+        assert_eq!(keypad.wait_for_key(true), None);
+
+        keypad.set_key(0x3, true);
+        assert_eq!(poll(&mut keypad, true), None);
+        assert!(keypad.is_waiting_release());
+
+        keypad.set_key(0x3, false);
+        assert_eq!(poll(&mut keypad, true), Some(0x3));
+        assert!(!keypad.is_waiting_release());
+    }
+
+    #[test]
+    fn release_before_wait_is_ignored() {
+        let mut keypad = Keypad::new();
+        keypad.set_key(0x1, true);
+        assert_eq!(keypad.wait_for_key(true), None);
+
+        // Held from before the wait: releasing it must not count, pressing it again must
+        keypad.set_key(0x1, false);
+        keypad.set_key(0x1, true);
+        assert_eq!(poll(&mut keypad, true), None);
+        keypad.set_key(0x1, false);
+        assert_eq!(poll(&mut keypad, true), Some(0x1));
+    }
+
+    #[test]
+    fn press_and_release_between_polls_is_registered() {
+        let mut keypad = Keypad::new();
+        assert_eq!(keypad.wait_for_key(true), None);
+
+        keypad.set_key(0x7, true);
+        keypad.set_key(0x7, false);
+        assert_eq!(poll(&mut keypad, true), Some(0x7));
+    }
+
+    #[test]
+    fn repeated_key_registers_once_per_press() {
+        let mut keypad = Keypad::new();
+        assert_eq!(keypad.wait_for_key(false), None);
+        keypad.set_key(0x2, true);
+        assert_eq!(poll(&mut keypad, false), Some(0x2));
+
+        // Next FX0A while the key is still held, with auto-repeat events
+        keypad.set_key(0x2, true);
+        assert_eq!(poll(&mut keypad, false), None);
+        keypad.set_key(0x2, true);
+        assert_eq!(poll(&mut keypad, false), None);
 
-    pub fn size(&mut self) -> usize {
-        SIZE
+        keypad.set_key(0x2, false);
+        keypad.set_key(0x2, true);
+        assert_eq!(poll(&mut keypad, false), Some(0x2));
     }
 }
@@ -0,0 +1,293 @@
+// In-window ROM picker, shown when the emulator starts without a ROM or when switching games.
+
+use crate::{
+    metadata::{Database, RomInfo},
+    rom,
+};
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+// Number of recently played ROMs remembered
+const RECENT_COUNT: usize = 8;
+
+/// Finds a ROM given on the command line: either a path to a file, or a file name in the ROM directory
+pub fn find_rom(rom: &str, rom_dir: &Path) -> Option<PathBuf> {
+    [PathBuf::from(rom), rom_dir.join(rom)]
+        .into_iter()
+        .find(|path| path.is_file())
+}
+
+/// Name of the ROM file, used as window title and to look up its metadata
+pub fn rom_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+pub struct RomEntry {
+    pub path: PathBuf,
+    pub name: String,
+    pub info: RomInfo,
+}
+
+impl RomEntry {
+    pub fn new(path: PathBuf, database: &Database) -> Self {
+        let name = rom_name(&path);
+        let info = rom::read(&path)
+            .map(|file| database.lookup_file(&file, &name))
+            .unwrap_or_else(|_| RomInfo::unknown(&name));
+        RomEntry { path, name, info }
+    }
+
+    fn matches(&self, filter: &str) -> bool {
+        let filter = filter.to_lowercase();
+        [
+            Some(&self.name),
+            Some(&self.info.title),
+            self.info.author.as_ref(),
+        ]
+        .iter()
+        .flatten()
+        .any(|text| text.to_lowercase().contains(&filter))
+    }
+}
+
+/// Recently played ROMs, most recent first, kept in a file between runs
+pub struct Recent {
+    // None when there is nowhere to keep the list
+    file: Option<PathBuf>,
+    pub paths: Vec<PathBuf>,
+}
+
+impl Recent {
+    /// Loads the list from ~/.chip8_recent
+    pub fn load() -> Self {
+        let file = env::var_os("HOME").map(|home| Path::new(&home).join(".chip8_recent"));
+        Self::load_from(file)
+    }
+
+    fn load_from(file: Option<PathBuf>) -> Self {
+        let paths = file
+            .as_ref()
+            .and_then(|file| fs::read_to_string(file).ok())
+            .map(|text| text.lines().map(PathBuf::from).collect())
+            .unwrap_or_default();
+        Recent { file, paths }
+    }
+
+    /// Moves the ROM to the top of the list and saves it
+    pub fn add(&mut self, path: &Path) -> io::Result<()> {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        self.paths.retain(|recent| *recent != path);
+        self.paths.insert(0, path);
+        self.paths.truncate(RECENT_COUNT);
+
+        match &self.file {
+            Some(file) => {
+                let lines: Vec<String> = self
+                    .paths
+                    .iter()
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .collect();
+                fs::write(file, lines.join("\n") + "\n")
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// A line of text of the launcher screen
+pub struct TextLine {
+    pub text: String,
+    // Drawn with inverted colors
+    pub highlight: bool,
+}
+
+impl TextLine {
+    fn new(text: String) -> Self {
+        TextLine {
+            text,
+            highlight: false,
+        }
+    }
+}
+
+pub struct Launcher {
+    // Recently played ROMs first, then the ROM directory in alphabetical order
+    entries: Vec<RomEntry>,
+    // Number of entries that are recently played ROMs
+    recent_count: usize,
+    filter: String,
+    // Index in the filtered entries
+    selected: usize,
+    // Shown instead of the help line, e.g. when a ROM failed to load
+    message: Option<String>,
+}
+
+impl Launcher {
+    /// Lists the ROMs of the directory, after the recently played ones
+    pub fn new(rom_dir: &Path, recent: &Recent, database: &Database) -> Self {
+        let mut entries: Vec<RomEntry> = recent
+            .paths
+            .iter()
+            .filter(|path| path.is_file())
+            .map(|path| RomEntry::new(path.clone(), database))
+            .collect();
+        let recent_count = entries.len();
+
+        let mut paths: Vec<PathBuf> = fs::read_dir(rom_dir)
+            .map(|dir| {
+                dir.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                    .filter(|path| path.is_file())
+                    .collect()
+            })
+            .unwrap_or_default();
+        paths.sort();
+        entries.extend(paths.into_iter().map(|path| RomEntry::new(path, database)));
+
+        Launcher {
+            entries,
+            recent_count,
+            filter: String::new(),
+            selected: 0,
+            message: None,
+        }
+    }
+
+    /// Entries matching the filter, with whether they were recently played
+    fn visible(&self) -> Vec<(&RomEntry, bool)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.matches(&self.filter))
+            .map(|(index, entry)| (entry, index < self.recent_count))
+            .collect()
+    }
+
+    pub fn type_text(&mut self, text: &str) {
+        self.filter.push_str(text);
+        self.selected = 0;
+    }
+
+    pub fn erase(&mut self) {
+        self.filter.pop();
+        self.selected = 0;
+    }
+
+    /// Moves the selection up (negative) or down, staying within the list
+    pub fn move_selection(&mut self, delta: isize) {
+        let count = self.visible().len();
+        if count > 0 {
+            self.selected = self.selected.saturating_add_signed(delta).min(count - 1);
+        }
+    }
+
+    pub fn set_message(&mut self, message: String) {
+        self.message = Some(message);
+    }
+
+    pub fn selected(&self) -> Option<&RomEntry> {
+        self.visible().get(self.selected).map(|&(entry, _)| entry)
+    }
+
+    /// Lays out the launcher screen as the given number of lines of text
+    pub fn lines(&self, rows: usize) -> Vec<TextLine> {
+        let visible = self.visible();
+        let mut lines = vec![
+            TextLine::new(self.message.clone().unwrap_or_else(|| {
+                String::from("Type to filter, Up/Down to choose, Enter to play, Escape to quit")
+            })),
+            TextLine::new(format!("> {}_", self.filter)),
+            TextLine::new(String::new()),
+        ];
+
+        // Keep the selection in view, leaving room for the details below
+        let list_rows = rows.saturating_sub(lines.len() + 5).max(1);
+        let first = self.selected.saturating_sub(list_rows - 1);
+        for (index, (entry, recent)) in visible.iter().enumerate().skip(first).take(list_rows) {
+            let marker = if *recent { '*' } else { ' ' };
+            lines.push(TextLine {
+                text: format!("{} {:<16} {}", marker, entry.name, entry.info.title),
+                highlight: index == self.selected,
+            });
+        }
+        if visible.is_empty() {
+            lines.push(TextLine::new(String::from("  No ROM found")));
+        }
+        lines.resize_with(rows.saturating_sub(4), || TextLine::new(String::new()));
+
+        if let Some(entry) = self.selected() {
+            let info = &entry.info;
+            let author = info.author.as_deref().unwrap_or("unknown author");
+            let platform = info.platform.map_or("unknown platform", |p| p.name());
+            // Only list the quirks that are on
+            let quirks = match info.quirks {
+                Some(quirks) => quirks.enabled(),
+                None => String::from("defaults"),
+            };
+            lines.push(TextLine::new(format!("{} by {}", info.title, author)));
+            lines.push(TextLine::new(format!("{}, quirks: {}", platform, quirks)));
+            lines.push(TextLine::new(format!("Keys: {}", info.key_hints())));
+            lines.push(TextLine::new(entry.path.to_string_lossy().into_owned()));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn filters_and_selects() {
+        let database = Database::bundled();
+        let mut launcher = Launcher::new(Path::new("roms"), &Recent::load_from(None), &database);
+        assert_eq!(launcher.visible().len(), 24);
+        assert_eq!(launcher.selected().unwrap().name, "15PUZZLE");
+
+        // Matches names, titles and authors
+        launcher.type_text("winter");
+        assert!(launcher.visible().len() > 5);
+        launcher.type_text("x");
+        assert!(launcher.selected().is_none());
+        for _ in 0.."winterx".len() {
+            launcher.erase();
+        }
+        launcher.type_text("pong");
+        launcher.move_selection(5);
+        assert_eq!(launcher.selected().unwrap().name, "PONG2");
+        launcher.move_selection(-5);
+        assert_eq!(launcher.selected().unwrap().name, "PONG");
+    }
+
+    #[test]
+    fn recent_roms_come_first() {
+        let file = env::temp_dir().join(format!("chip8-recent-{}", process::id()));
+        let _ = fs::remove_file(&file);
+        let mut recent = Recent::load_from(Some(file.clone()));
+        recent.add(Path::new("roms/TETRIS")).unwrap();
+        recent.add(Path::new("roms/PONG")).unwrap();
+        recent.add(Path::new("roms/TETRIS")).unwrap();
+
+        let recent = Recent::load_from(Some(file));
+        assert_eq!(recent.paths.len(), 2);
+        let launcher = Launcher::new(Path::new("roms"), &recent, &Database::bundled());
+        let names: Vec<&str> = launcher.visible()[..3]
+            .iter()
+            .map(|(entry, _)| entry.name.as_str())
+            .collect();
+        assert_eq!(names, ["TETRIS", "PONG", "15PUZZLE"]);
+        assert!(launcher.lines(30)[3].text.starts_with("* TETRIS"));
+    }
+
+    #[test]
+    fn finds_roms_by_path_or_name() {
+        let dir = Path::new("roms");
+        assert_eq!(find_rom("PONG", dir), Some(dir.join("PONG")));
+        assert_eq!(find_rom("roms/PONG", dir), Some(PathBuf::from("roms/PONG")));
+        assert_eq!(find_rom("NOPE", dir), None);
+    }
+}
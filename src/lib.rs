@@ -0,0 +1,33 @@
+// The emulator as a library, for the binary, the fuzz targets and tests.
+
+pub mod analysis;
+pub mod audio;
+pub mod chip8;
+pub mod controls;
+pub mod dap;
+pub mod debugger;
+pub mod decompiler;
+pub mod difftrace;
+pub mod disassembler;
+pub mod display;
+pub mod font;
+pub mod framebuffer;
+pub mod gdb;
+pub mod keypad;
+pub mod launcher;
+pub mod metadata;
+pub mod movie;
+pub mod octo;
+pub mod options;
+pub mod overlay;
+pub mod platform;
+pub mod quirks;
+pub mod rng;
+pub mod rom;
+pub mod session;
+pub mod speaker;
+pub mod sprites;
+pub mod state;
+pub mod trace;
+pub mod watch;
+pub mod wav;
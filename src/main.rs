@@ -1,37 +1,646 @@
 extern crate sdl2;
 
-use std::env;
+use std::{
+    env, fs, iter,
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, Instant},
+};
 
-use crate::chip8::Chip8;
-use sdl2::{event::Event, keyboard::Keycode};
+use chip8::{
+    analysis,
+    chip8::Chip8,
+    controls::{Controls, Hotkey, Pace, FAST_FORWARD_TIME},
+    dap,
+    debugger::{Breakpoints, Panel},
+    decompiler,
+    difftrace,
+    display::{Display, TEXT_ROWS},
+    gdb::GdbStub,
+    launcher::{self, Launcher, Recent},
+    metadata::{Database, RomInfo},
+    movie::{self, Movie, MovieHeader, MovieRecorder},
+    options::{self, Options},
+    overlay::Overlay,
+    quirks::Quirks,
+    rng,
+    rom::{self, RomError, RomFormat},
+    session::{self, Session},
+    speaker::Speaker,
+    sprites::{self, Image, Shape, Sprite, SpriteRecorder},
+    state::SaveState,
+    trace::{self, Tracer},
+    watch::{Resume, Watcher},
+};
+use sdl2::{event::Event, keyboard::Keycode, mouse::MouseButton, Sdl};
 
-mod chip8;
-mod display;
-mod font;
-mod keypad;
-mod speaker;
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        // Debug Adapter Protocol server for editors, on stdin & stdout
+        Some("dap") => {
+            if let Err(e) = dap::run() {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+            return;
+        }
+        Some("analyze") => return analyze(&args[1..]),
+        Some("decompile") => return decompile(&args[1..]),
+        Some("sprites") => return show_sprites(&args[1..]),
+        Some("diff-trace") => return diff_trace(&args[1..]),
+        _ => (),
+    }
+
+    let options = Options::parse(args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let rom_dir = PathBuf::from(&options.rom_dir);
+    let rom_path = options.rom.as_ref().map(|rom| {
+        launcher::find_rom(rom, &rom_dir).unwrap_or_else(|| {
+            eprintln!("ROM not found: {}", rom);
+            process::exit(1);
+        })
+    });
+    if rom_path.is_none()
+        && (options.headless || options.record.is_some() || options.replay.is_some())
+    {
+        eprintln!("A ROM is needed to run headless or to record or replay a movie");
+        process::exit(1);
+    }
+    let rom = rom_path.as_ref().map(|path| {
+        rom::read_as(path, options.format).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path.display(), e);
+            process::exit(1);
+        })
+    });
+    let database = Database::load();
+    let info = match (&rom_path, &rom) {
+        (Some(path), Some(rom)) => database.lookup_file(rom, &launcher::rom_name(path)),
+        _ => RomInfo::unknown("CHIP8"),
+    };
+    let (mut quirks, mut tickrate) = game_settings(&info, &options);
+    let mut random = options.random.clone();
 
-const CYCLES_PER_SEC: u8 = 10; // Sweet spot?
+    // A replay must start exactly like the recorded session
+    let replay = options.replay.as_ref().map(|path| {
+        Movie::load(path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        })
+    });
+    let seed = match &replay {
+        Some(movie) => {
+            quirks = movie.header.quirks;
+            tickrate = movie.header.tickrate;
+            random = movie.header.random.clone();
+            movie.header.seed
+        }
+        None => options.seed.unwrap_or_else(rng::random_seed),
+    };
 
-fn main() {
-    // 2nd arg is the rom name to load, default to TEST rom
-    let args: Vec<String> = env::args().collect();
-    let mut rom = "TEST";
-    if args.len() == 2 {
-        rom = &args[1];
+    let mut chip8 = Chip8::new(quirks, random.build(seed));
+    if let (Some(path), Some(rom)) = (&rom_path, rom) {
+        if let Err(e) = chip8.load_rom(rom.program, options.load_address) {
+            eprintln!("{}: {}", path.display(), e);
+            process::exit(1);
+        }
+        print_rom_info(&info, quirks);
+    }
+
+    let header = MovieHeader {
+        rom_hash: movie::rom_hash(chip8.rom()),
+        seed,
+        random,
+        quirks,
+        tickrate,
+    };
+    if let Some(movie) = &replay {
+        if movie.header.rom_hash != header.rom_hash {
+            eprintln!("The movie was recorded with a different ROM");
+            process::exit(1);
+        }
+    }
+    let recorder = options.record.as_ref().map(|path| {
+        MovieRecorder::create(path, &header).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        })
+    });
+
+    let mut tracer = options.trace.as_ref().map(|path| {
+        let filter = options.trace_filter.clone();
+        Tracer::create(path, options.trace_format, filter).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        })
+    });
+
+    if options.headless {
+        let mut speaker = Speaker::silent(options.tone);
+        if let Some(path) = &options.audio_out {
+            speaker
+                .record_to(path)
+                .expect("Could not create audio file");
+        }
+        let session = Session {
+            chip8,
+            speaker,
+            replay,
+            recorder,
+            frame: 0,
+            instructions: 0,
+            cycles_per_frame: tickrate,
+            breakpoints: Breakpoints::new(),
+            tracer: tracer.take(),
+            sprites: SpriteRecorder::new(),
+        };
+        let gdb = options.gdb.map(connect_gdb);
+        run_headless(session, options.frames, gdb);
+    } else {
+        let gdb = options.gdb.map(connect_gdb);
+        let sdl_context = sdl2::init().unwrap();
+        let window_title = rom_path
+            .as_deref()
+            .map_or(String::from("CHIP8"), game_title);
+        let mut display = Display::new(&sdl_context, &window_title, options.debugger);
+        display.set_colors(info.colors);
+        let mut speaker = Speaker::open(&sdl_context, options.tone);
+        if let Some(path) = &options.audio_out {
+            speaker
+                .record_to(path)
+                .expect("Could not create audio file");
+        }
+        let session = Session {
+            chip8,
+            speaker,
+            replay,
+            recorder,
+            frame: 0,
+            instructions: 0,
+            cycles_per_frame: tickrate,
+            breakpoints: Breakpoints::new(),
+            tracer: tracer.take(),
+            sprites: SpriteRecorder::new(),
+        };
+        run_window(
+            session,
+            &sdl_context,
+            display,
+            rom_path.as_deref().map(|path| (path, &info)),
+            gdb,
+            &database,
+            &options,
+        );
+    }
+}
+
+// Frames run to reach the --watch-resume address before giving up
+const BREAKPOINT_FRAMES: u64 = 60 * 60;
+// Time between two looks at the GDB connection while GDB halts the machine headless
+const GDB_POLL_TIME: Duration = Duration::from_millis(10);
+// Characters per line of `chip8 sprites`, and window pixels per sprite pixel in its PNG images
+const SPRITE_COLUMNS_WIDTH: usize = 72;
+const PNG_SCALE: usize = 4;
+
+fn game_title(rom_path: &Path) -> String {
+    format!("{} - CHIP8", launcher::rom_name(rom_path))
+}
+
+/// Window title with the speed and pause state
+fn status_title(title: &str, controls: &Controls, session: &Session) -> String {
+    format!("{} | {}", title, controls.status(session.cycles_per_frame))
+}
+
+fn add_recent(recent: &mut Recent, rom_path: &Path) {
+    if let Err(e) = recent.add(rom_path) {
+        eprintln!("Could not save recently played ROMs: {}", e);
+    }
+}
+
+/// Quirks and instructions per frame for a ROM: what the database says, unless the command line says otherwise
+fn game_settings(info: &RomInfo, options: &Options) -> (Quirks, u32) {
+    let quirks = match options.platform {
+        Some(platform) => platform.quirks(),
+        None => info.quirks.unwrap_or_default(),
+    };
+    let tickrate = options
+        .tickrate
+        .or(info.tickrate)
+        .unwrap_or(session::CYCLES_PER_FRAME);
+    (quirks.with(&options.quirks), tickrate)
+}
+
+/// What the game is and how it runs, told when it starts
+fn rom_info_lines(info: &RomInfo, quirks: Quirks) -> Vec<String> {
+    let platform = info.platform.map_or("unknown platform", |p| p.name());
+    let mut lines = vec![format!("{} ({})", info.title, platform)];
+    let enabled = quirks.enabled();
+    lines.push(format!(
+        "Quirks: {}",
+        if enabled.is_empty() { "none" } else { &enabled }
+    ));
+    if !info.keys.is_empty() {
+        lines.push(format!("Keys: {}", info.key_hints()));
+    }
+    lines
+}
+
+fn print_rom_info(info: &RomInfo, quirks: Quirks) {
+    for line in rom_info_lines(info, quirks) {
+        println!("{}", line);
+    }
+}
+
+/// Shows the message in the window, and in the terminal for the record
+fn notify(overlay: &mut Overlay, message: String) {
+    println!("{}", message);
+    overlay.message(message);
+}
+
+/// Creates a machine running the ROM, for games started from the launcher.
+/// Returns it with its number of instructions per frame.
+fn new_game(rom_path: &Path, info: &RomInfo, options: &Options) -> Result<(Chip8, u32), RomError> {
+    let seed = options.seed.unwrap_or_else(rng::random_seed);
+    let (quirks, tickrate) = game_settings(info, options);
+    let mut chip8 = Chip8::new(quirks, options.random.build(seed));
+    chip8.load_rom(rom::read(rom_path)?.program, options.load_address)?;
+    print_rom_info(info, quirks);
+    Ok((chip8, tickrate))
+}
+
+fn connect_gdb(port: u16) -> GdbStub {
+    GdbStub::listen(port).unwrap_or_else(|e| {
+        eprintln!("Could not wait for GDB on port {}: {}", port, e);
+        process::exit(1);
+    })
+}
+
+/// Lets GDB look at and drive the machine, forgetting it once it is gone.
+/// Returns whether the machine may run.
+fn poll_gdb(gdb: &mut Option<GdbStub>, session: &mut Session) -> bool {
+    let Some(stub) = gdb else {
+        return true;
+    };
+    match stub.poll(session) {
+        Ok(true) => return stub.is_running(),
+        Ok(false) => println!("GDB disconnected"),
+        Err(e) => eprintln!("GDB connection lost: {}", e),
+    }
+    *gdb = None;
+    true
+}
+
+/// `chip8 diff-trace ROM REFERENCE [OPTIONS]`: runs the ROM along the trace of another emulator,
+/// and tells where they disagree first. The seed is 0 unless given, for CXKK to be repeatable.
+fn diff_trace(args: &[String]) {
+    let [rom, reference, rest @ ..] = args else {
+        eprintln!("Usage: chip8 diff-trace ROM REFERENCE [OPTIONS]");
+        process::exit(1);
+    };
+    let exit = |message: String| -> ! {
+        eprintln!("{}", message);
+        process::exit(1);
+    };
+    let args = iter::once(rom.clone()).chain(rest.iter().cloned());
+    let options = Options::parse(args).unwrap_or_else(|e| exit(e));
+    let rom_path = launcher::find_rom(rom, Path::new(&options.rom_dir))
+        .unwrap_or_else(|| exit(format!("ROM not found: {}", rom)));
+    let file = rom::read_as(&rom_path, options.format)
+        .unwrap_or_else(|e| exit(format!("{}: {}", rom, e)));
+    let reference =
+        trace::read(reference).unwrap_or_else(|e| exit(format!("{}: {}", reference, e)));
+
+    let info = Database::load().lookup_file(&file, &launcher::rom_name(&rom_path));
+    let (quirks, tickrate) = game_settings(&info, &options);
+    let mut chip8 = Chip8::new(quirks, options.random.build(options.seed.unwrap_or(0)));
+    if let Err(e) = chip8.load_rom(file.program, options.load_address) {
+        exit(format!("{}: {}", rom, e));
+    }
+    match difftrace::compare(chip8, tickrate, &reference) {
+        Ok(instructions) => println!("All {} instructions match", instructions),
+        Err(divergence) => exit(divergence.to_string()),
     }
+}
+
+/// Prints what static analysis finds in the ROM, and writes its control-flow graph with --dot
+fn analyze(args: &[String]) {
+    let exit = |message: String| -> ! {
+        eprintln!("{}", message);
+        process::exit(1);
+    };
+    let mut args = args.to_vec();
+    let dot = take_value(&mut args, "--dot").unwrap_or_else(|e| exit(e));
+    let Some(rom) = args.first().cloned() else {
+        exit(String::from(
+            "Usage: chip8 analyze ROM [--dot FILE] [OPTIONS]",
+        ));
+    };
+    let options = Options::parse(args).unwrap_or_else(|e| exit(e));
+    let rom_path = launcher::find_rom(&rom, Path::new(&options.rom_dir))
+        .unwrap_or_else(|| exit(format!("ROM not found: {}", rom)));
+    let file = rom::read_as(&rom_path, options.format)
+        .unwrap_or_else(|e| exit(format!("{}: {}", rom, e)));
+
+    let analysis = analysis::analyze(&file.program, options.load_address);
+    println!(
+        "{}: {} bytes at 0x{:03X}",
+        launcher::rom_name(&rom_path),
+        file.program.len(),
+        options.load_address
+    );
+    println!("{}", analysis);
+    if let Some(path) = dot {
+        fs::write(&path, analysis.to_dot()).unwrap_or_else(|e| exit(format!("{}: {}", path, e)));
+    }
+}
+
+fn decompile(args: &[String]) {
+    let exit = |message: String| -> ! {
+        eprintln!("{}", message);
+        process::exit(1);
+    };
+    let mut args = args.to_vec();
+    let octo = args.iter().any(|arg| arg == "--octo");
+    args.retain(|arg| arg != "--octo");
+    let Some(rom) = args.first().cloned() else {
+        exit(String::from("Usage: chip8 decompile ROM [--octo] [OPTIONS]"));
+    };
+    let options = Options::parse(args).unwrap_or_else(|e| exit(e));
+    let rom_path = launcher::find_rom(&rom, Path::new(&options.rom_dir))
+        .unwrap_or_else(|| exit(format!("ROM not found: {}", rom)));
+    let file = rom::read_as(&rom_path, options.format)
+        .unwrap_or_else(|e| exit(format!("{}: {}", rom, e)));
+
+    let analysis = analysis::analyze(&file.program, options.load_address);
+    if octo {
+        let source = decompiler::to_octo(&analysis, &file.program)
+            .unwrap_or_else(|e| exit(format!("{}: {}", rom, e)));
+        print!("{}", source);
+    } else {
+        print!("{}", decompiler::decompile(&analysis));
+    }
+}
 
-    let rom_path = format!("roms/{}", rom);
-    let sdl_context = sdl2::init().unwrap();
-    let window_title = format!("{} - CHIP8", rom);
-    let mut chip8 = Chip8::new(&sdl_context, &window_title);
+/// Prints a memory region as sprites in a grid, by default the whole ROM as 8x8 sprites.
+/// With --frames, runs the ROM without input and prints the sprites DXYN drew instead.
+fn show_sprites(args: &[String]) {
+    let exit = |message: String| -> ! {
+        eprintln!("{}", message);
+        process::exit(1);
+    };
+    let mut args = args.to_vec();
+    let mut value = |flag| take_value(&mut args, flag).unwrap_or_else(|e| exit(e));
+    let (start, end, height, png) = (
+        value("--start"),
+        value("--end"),
+        value("--height"),
+        value("--png"),
+    );
+    let wide = args.iter().any(|arg| arg == "--wide");
+    args.retain(|arg| arg != "--wide");
+    let Some(rom) = args.first().cloned() else {
+        exit(String::from(
+            "Usage: chip8 sprites ROM [--start ADDR] [--end ADDR] [--height N | --wide] [--png FILE] [OPTIONS]",
+        ));
+    };
+    let options = Options::parse(args).unwrap_or_else(|e| exit(e));
+    let rom_path = launcher::find_rom(&rom, Path::new(&options.rom_dir))
+        .unwrap_or_else(|| exit(format!("ROM not found: {}", rom)));
+    let file = rom::read_as(&rom_path, options.format)
+        .unwrap_or_else(|e| exit(format!("{}: {}", rom, e)));
+    let address = |value: Option<String>, default| {
+        value.map_or(Ok(default), |value| options::parse_address(&value))
+    };
+    let start = address(start, options.load_address).unwrap_or_else(|e| exit(e));
+    let end = address(end, options.load_address + file.program.len()).unwrap_or_else(|e| exit(e));
+    let shape = match height.map(|height| height.parse()) {
+        _ if wide => Shape::Wide,
+        None => Shape::Narrow(8),
+        Some(Ok(height @ 1..=15)) => Shape::Narrow(height),
+        Some(_) => exit(String::from("--height must be between 1 and 15")),
+    };
 
-    chip8.load_rom(&rom_path);
+    let info = Database::load().lookup_file(&file, &launcher::rom_name(&rom_path));
+    let (quirks, tickrate) = game_settings(&info, &options);
+    let mut chip8 = Chip8::new(quirks, options.random.build(options.seed.unwrap_or(0)));
+    if let Err(e) = chip8.load_rom(file.program, options.load_address) {
+        exit(format!("{}: {}", rom, e));
+    }
+    let sprites = match options.frames {
+        None => Sprite::tile(start, end.min(chip8.memory().len()), shape),
+        Some(frames) => {
+            let mut recorder = SpriteRecorder::new();
+            for _ in 0..frames {
+                for _ in 0..tickrate {
+                    chip8.cycle_with(&mut recorder);
+                }
+                chip8.decrement_timers();
+            }
+            let drawn = recorder.drawn();
+            println!("Drawn in {} frames:", frames);
+            for (sprite, draws) in &drawn {
+                let (width, height) = (sprite.shape.width(), sprite.shape.height());
+                let times = if *draws == 1 { "time" } else { "times" };
+                let address = sprite.address;
+                println!("  0x{:03X} {}x{}, {} {}", address, width, height, draws, times);
+            }
+            println!();
+            drawn.into_iter().map(|(sprite, _)| sprite).collect()
+        }
+    };
+    if sprites.is_empty() {
+        exit(String::from("No sprites"));
+    }
+    let width = sprites.iter().map(|s| s.shape.width()).max().unwrap_or(8);
+    let columns = SPRITE_COLUMNS_WIDTH / (width + 1);
+    print!("{}", sprites::to_text(chip8.memory(), &sprites, columns));
+    if let Some(path) = png {
+        let image = Image::grid(chip8.memory(), &sprites, columns);
+        fs::write(&path, image.to_png(PNG_SCALE)).unwrap_or_else(|e| exit(format!("{}: {}", path, e)));
+    }
+}
+
+/// Removes the flag and its value from the arguments, returns the value
+fn take_value(args: &mut Vec<String>, flag: &str) -> Result<Option<String>, String> {
+    let Some(position) = args.iter().position(|arg| arg == flag) else {
+        return Ok(None);
+    };
+    let Some(value) = args.get(position + 1).cloned() else {
+        return Err(format!("Missing value for {}", flag));
+    };
+    args.drain(position..position + 2);
+    Ok(Some(value))
+}
+
+/// Runs as fast as possible without window nor audio device,
+/// until the frame limit, the end of the replay or GDB disconnecting
+fn run_headless(mut session: Session, frames: Option<u64>, mut gdb: Option<GdbStub>) {
+    if frames.is_none() && session.replay.is_none() && gdb.is_none() {
+        eprintln!("Running headless needs --frames, --replay or --gdb to know when to stop");
+        process::exit(1);
+    }
+
+    loop {
+        let limit_reached = frames.is_some_and(|frames| session.frame >= frames);
+        let nothing_left = frames.is_none() && session.replay.is_none() && gdb.is_none();
+        if limit_reached || nothing_left {
+            break;
+        }
+        if !poll_gdb(&mut gdb, &mut session) {
+            thread::sleep(GDB_POLL_TIME);
+            continue;
+        }
+        if session.run_frame().is_some() {
+            if let Some(stub) = &mut gdb {
+                // A lost connection shows on the next poll
+                stub.stop().ok();
+            }
+        }
+    }
+
+    finish(session);
+}
+
+/// Writes the end of the movie, trace and audio files
+fn finish(session: Session) {
+    if let Err(e) = session.finish() {
+        eprintln!("Could not write recordings: {}", e);
+        process::exit(1);
+    }
+}
+
+/// Restarts the game with a new build of its ROM, from where --watch-resume says
+fn reload_rom(
+    session: &mut Session,
+    rom: Vec<u8>,
+    resume: Resume,
+    saved_state: Option<&SaveState>,
+) -> Result<(), RomError> {
+    session.chip8.reload(rom)?;
+    match resume {
+        Resume::Start => (),
+        Resume::SavedState => {
+            if let Some(state) = saved_state {
+                session.chip8.load_state(state);
+                session.chip8.restore_program();
+            }
+        }
+        Resume::Breakpoint(address) => {
+            if !session.run_to(address, BREAKPOINT_FRAMES) {
+                eprintln!(
+                    "{:#05x} was not reached, restarting from the beginning",
+                    address
+                );
+                session.chip8.reset();
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_window(
+    mut session: Session,
+    sdl_context: &Sdl,
+    mut display: Display,
+    game: Option<(&Path, &RomInfo)>,
+    mut gdb: Option<GdbStub>,
+    database: &Database,
+    options: &Options,
+) {
+    let rom_path = game.map(|(path, _)| path);
+    let mut recent = Recent::load();
+    if let Some(path) = rom_path {
+        add_recent(&mut recent, path);
+    }
+    let rom_dir = PathBuf::from(&options.rom_dir);
+    let mut saved_state = None;
+    // Without a ROM, start by choosing one
+    let mut launcher = match rom_path {
+        Some(_) => None,
+        None => Some(Launcher::new(&rom_dir, &recent, database)),
+    };
+    let mut watcher = rom_path.filter(|_| options.watch).map(|path| Watcher::new(path, options.format));
+    let mut controls = Controls::new();
+    let mut title = rom_path.map_or(String::from("CHIP8"), game_title);
+    display.set_title(&status_title(&title, &controls, &session));
+    let mut overlay = Overlay::new();
+    if let Some((_, info)) = game {
+        overlay.info(rom_info_lines(info, session.chip8.quirks()));
+    }
+    if options.fps {
+        overlay.toggle_counter(0, 0);
+    }
+    let mut panel = options.debugger.then(Panel::new);
 
     // Listen to events in the main loop
     let mut event_pump = sdl_context.event_pump().unwrap();
     'main: loop {
         for evt in event_pump.poll_iter() {
+            if let Some(menu) = &mut launcher {
+                match evt {
+                    Event::Quit { .. } => break 'main,
+                    // Back to the game if there is one
+                    Event::KeyDown {
+                        keycode: Some(Keycode::Escape),
+                        ..
+                    } => {
+                        if session.chip8.rom().is_empty() {
+                            break 'main;
+                        }
+                        launcher = None;
+                    }
+                    Event::TextInput { text, .. } => menu.type_text(&text),
+                    Event::KeyDown {
+                        keycode: Some(Keycode::Backspace),
+                        ..
+                    } => menu.erase(),
+                    Event::KeyDown {
+                        keycode: Some(Keycode::Up),
+                        ..
+                    } => menu.move_selection(-1),
+                    Event::KeyDown {
+                        keycode: Some(Keycode::Down),
+                        ..
+                    } => menu.move_selection(1),
+                    Event::KeyDown {
+                        keycode: Some(Keycode::Return),
+                        ..
+                    } => {
+                        let Some(entry) = menu.selected() else {
+                            continue;
+                        };
+                        match new_game(&entry.path, &entry.info, options) {
+                            // Stay in the launcher to pick another one
+                            Err(e) => {
+                                let message = format!("{}: {}", entry.name, e);
+                                menu.set_message(message);
+                            }
+                            Ok((chip8, tickrate)) => {
+                                if let Err(e) = session.switch_game(chip8, tickrate) {
+                                    eprintln!("Could not write recordings: {}", e);
+                                    process::exit(1);
+                                }
+                                title = game_title(&entry.path);
+                                display.set_title(&status_title(&title, &controls, &session));
+                                if options.watch {
+                                    watcher = Some(Watcher::new(&entry.path, RomFormat::Auto));
+                                }
+                                display.set_colors(entry.info.colors);
+                                let quirks = session.chip8.quirks();
+                                overlay.info(rom_info_lines(&entry.info, quirks));
+                                add_recent(&mut recent, &entry.path);
+                                saved_state = None;
+                                launcher = None;
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+                continue;
+            }
+
             match evt {
                 Event::Quit { .. }
                 | Event::KeyDown {
@@ -40,26 +649,201 @@ fn main() {
                 } => {
                     break 'main;
                 }
+                // Quick save & load, except with a movie that can't hold them
                 Event::KeyDown {
-                    keycode: Some(key), ..
+                    keycode: Some(Keycode::F5 | Keycode::F9),
+                    ..
+                } if session.has_movie() => {
+                    let message = "Not while recording or replaying a movie";
+                    notify(&mut overlay, String::from(message));
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    ..
                 } => {
-                    chip8.keypad.handle_key(key, true);
+                    saved_state = Some(session.chip8.save_state());
+                    notify(&mut overlay, String::from("State saved"));
                 }
-                Event::KeyUp {
-                    keycode: Some(key), ..
+                Event::KeyDown {
+                    keycode: Some(Keycode::F9),
+                    ..
                 } => {
-                    chip8.keypad.handle_key(key, false);
+                    if let Some(state) = &saved_state {
+                        session.chip8.load_state(state);
+                        notify(&mut overlay, String::from("State loaded"));
+                    }
                 }
+                // Switch to another game
+                Event::KeyDown {
+                    keycode: Some(Keycode::F1),
+                    ..
+                } => {
+                    launcher = Some(Launcher::new(&rom_dir, &recent, database));
+                }
+                // Buzzer mute & volume
+                Event::KeyDown {
+                    keycode: Some(Keycode::M),
+                    repeat: false,
+                    ..
+                } => {
+                    let muted = session.speaker.toggle_mute();
+                    let state = if muted { "muted" } else { "unmuted" };
+                    notify(&mut overlay, format!("Sound {}", state));
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::PageUp),
+                    repeat: false,
+                    ..
+                } => {
+                    let volume = session.speaker.change_volume(0.05);
+                    notify(&mut overlay, format!("Volume {:.0}%", volume * 100.0));
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::PageDown),
+                    repeat: false,
+                    ..
+                } => {
+                    let volume = session.speaker.change_volume(-0.05);
+                    notify(&mut overlay, format!("Volume {:.0}%", volume * 100.0));
+                }
+                // Frame & instruction rate counter
+                Event::KeyDown {
+                    keycode: Some(Keycode::F4),
+                    ..
+                } => {
+                    overlay.toggle_counter(session.frame, session.instructions);
+                }
+                // Debugger panel page
+                Event::KeyDown {
+                    keycode: Some(Keycode::F6),
+                    ..
+                } => {
+                    if let Some(panel) = &mut panel {
+                        let page = match panel.toggle_sprites() {
+                            true => "Sprites",
+                            false => "Machine state",
+                        };
+                        notify(&mut overlay, String::from(page));
+                    }
+                }
+                // Debugger panel: click on an instruction to toggle its breakpoint,
+                // scroll the memory view, right click to make it follow I again
+                Event::MouseButtonDown {
+                    mouse_btn: MouseButton::Left,
+                    x,
+                    y,
+                    ..
+                } => {
+                    let Some((panel, row)) = panel.as_ref().zip(display.panel_line_at(x, y)) else {
+                        continue;
+                    };
+                    let lines = panel.lines(&session.chip8, &session.breakpoints, &session.sprites);
+                    if let Some(address) = lines.get(row).and_then(|line| line.address) {
+                        let set = session.breakpoints.toggle(address);
+                        let state = if set { "set" } else { "cleared" };
+                        notify(
+                            &mut overlay,
+                            format!("Breakpoint {} at {:#05x}", state, address),
+                        );
+                    }
+                }
+                Event::MouseButtonDown {
+                    mouse_btn: MouseButton::Right,
+                    ..
+                } => {
+                    if let Some(panel) = &mut panel {
+                        panel.follow_index();
+                    }
+                }
+                Event::MouseWheel { y, .. } => {
+                    if let Some(panel) = &mut panel {
+                        panel.scroll(-y as isize, &session.chip8);
+                    }
+                }
+                // Emulator hotkeys, then the keypad which only follows the movie while replaying
+                Event::KeyDown {
+                    keycode: Some(key),
+                    repeat,
+                    ..
+                } => match Hotkey::from_keycode(key) {
+                    Some(hotkey) => {
+                        if let Some(message) = controls.press(hotkey, repeat, &mut session) {
+                            notify(&mut overlay, message);
+                        }
+                        display.set_title(&status_title(&title, &controls, &session));
+                    }
+                    None if session.replay.is_none() => session.chip8.keypad.handle_key(key, true),
+                    None => (),
+                },
+                Event::KeyUp {
+                    keycode: Some(key), ..
+                } => match Hotkey::from_keycode(key) {
+                    Some(hotkey) => {
+                        controls.release(hotkey);
+                        display.set_title(&status_title(&title, &controls, &session));
+                    }
+                    None if session.replay.is_none() => session.chip8.keypad.handle_key(key, false),
+                    None => (),
+                },
                 _ => (),
             }
         }
 
-        for _ in 0..CYCLES_PER_SEC {
-            chip8.cycle();
+        if let Some(menu) = &launcher {
+            display.draw_text(&menu.lines(TEXT_ROWS));
+            continue;
         }
-
-        chip8.decrement_timers();
-        chip8.handle_sound();
-        chip8.display.draw_screen();
+        if let Some(watcher) = &mut watcher {
+            let reloaded = watcher.poll().map(|file| {
+                file.and_then(|file| {
+                    let resume = options.watch_resume;
+                    reload_rom(&mut session, file.program, resume, saved_state.as_ref())
+                })
+            });
+            match reloaded {
+                Some(Ok(())) => notify(
+                    &mut overlay,
+                    format!("Reloaded {}", watcher.path().display()),
+                ),
+                Some(Err(e)) => {
+                    eprintln!("{}: {}", watcher.path().display(), e);
+                    overlay.message(e.to_string());
+                }
+                None => (),
+            }
+        }
+        // GDB halting the machine comes first
+        let frames = match controls.pace() {
+            _ if !poll_gdb(&mut gdb, &mut session) => 0,
+            Pace::Frames(frames) => frames,
+            Pace::Unthrottled => u32::MAX,
+        };
+        let start = Instant::now();
+        for _ in 0..frames {
+            if options.frames.is_some_and(|frames| session.frame >= frames) {
+                break 'main;
+            }
+            if start.elapsed() >= FAST_FORWARD_TIME {
+                break;
+            }
+            if let Some(address) = session.run_frame() {
+                match &mut gdb {
+                    Some(stub) => {
+                        stub.stop().ok();
+                    }
+                    None => controls.pause(),
+                }
+                display.set_title(&status_title(&title, &controls, &session));
+                notify(&mut overlay, format!("Breakpoint at {:#05x}", address));
+                break;
+            }
+        }
+        overlay.count(session.frame, session.instructions);
+        let panel_lines = panel.as_ref().map_or(Vec::new(), |panel| {
+            panel.lines(&session.chip8, &session.breakpoints, &session.sprites)
+        });
+        display.draw_screen(&session.chip8.framebuffer, &overlay.lines(), &panel_lines);
     }
+
+    finish(session);
 }
@@ -0,0 +1,255 @@
+// What we know about ROMs: title, author, platform, quirks, speed, colors and keys.
+// Entries are keyed by the SHA-1 of the ROM bytes, in the format of the CHIP-8 database programs.json:
+// https://github.com/chip-8/chip-8-database
+// The bundled database covers the roms/ directory, entries in ~/.chip8_db.json take precedence.
+
+use crate::{
+    movie::rom_hash,
+    platform::Platform,
+    quirks::Quirks,
+    rom::{self, RomFile},
+};
+use serde::Deserialize;
+use std::{collections::HashMap, env, fs, io, path::Path};
+
+const BUNDLED: &str = include_str!("../data/chip8-db.json");
+
+#[derive(Deserialize)]
+struct Program {
+    title: String,
+    #[serde(default)]
+    authors: Vec<String>,
+    roms: HashMap<String, RomEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RomEntry {
+    #[serde(default)]
+    platforms: Vec<String>,
+    // Quirks differing from the platform ones, by platform
+    #[serde(default)]
+    quirky_platforms: HashMap<String, HashMap<String, bool>>,
+    tickrate: Option<u32>,
+    colors: Option<ColorEntry>,
+    #[serde(default)]
+    keys: HashMap<String, u8>,
+}
+
+#[derive(Deserialize)]
+struct ColorEntry {
+    // Background first, then foreground
+    #[serde(default)]
+    pixels: Vec<String>,
+}
+
+/// Display colors as RGB
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Colors {
+    pub on: [u8; 3],
+    pub off: [u8; 3],
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomInfo {
+    pub title: String,
+    pub author: Option<String>,
+    pub platform: Option<Platform>,
+    // Quirks the ROM needs, None when it is not in the database
+    pub quirks: Option<Quirks>,
+    // Instructions per frame
+    pub tickrate: Option<u32>,
+    pub colors: Option<Colors>,
+    // What the CHIP-8 keys do, e.g. ("left", 4)
+    pub keys: Vec<(String, u8)>,
+}
+
+impl RomInfo {
+    /// Info for a ROM missing from the database
+    pub fn unknown(title: &str) -> Self {
+        RomInfo {
+            title: title.to_string(),
+            author: None,
+            platform: None,
+            quirks: None,
+            tickrate: None,
+            colors: None,
+            keys: Vec::new(),
+        }
+    }
+
+    /// Key hints such as `left 4, right 6`
+    pub fn key_hints(&self) -> String {
+        let hints: Vec<String> = self
+            .keys
+            .iter()
+            .map(|(action, key)| format!("{} {:X}", action, key))
+            .collect();
+        hints.join(", ")
+    }
+}
+
+pub struct Database {
+    roms: HashMap<String, RomInfo>,
+}
+
+impl Database {
+    /// Database of the ROMs in roms/
+    pub fn bundled() -> Self {
+        Self::parse(BUNDLED).expect("Invalid bundled ROM database")
+    }
+
+    /// Loads the bundled database, then the user one if any
+    pub fn load() -> Self {
+        let mut database = Self::bundled();
+        if let Some(home) = env::var_os("HOME") {
+            let path = Path::new(&home).join(".chip8_db.json");
+            if let Ok(json) = fs::read_to_string(&path) {
+                match Self::parse(&json) {
+                    Ok(user) => database.roms.extend(user.roms),
+                    Err(e) => eprintln!("Ignoring {}: {}", path.display(), e),
+                }
+            }
+        }
+        database
+    }
+
+    pub fn parse(json: &str) -> io::Result<Self> {
+        let programs: Vec<Program> = serde_json::from_str(json)?;
+        let mut roms = HashMap::new();
+        for program in programs {
+            for (hash, entry) in program.roms {
+                let info = Self::rom_info(&program.title, &program.authors, entry)?;
+                roms.insert(hash.to_lowercase(), info);
+            }
+        }
+        Ok(Database { roms })
+    }
+
+    fn rom_info(title: &str, authors: &[String], entry: RomEntry) -> io::Result<RomInfo> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+
+        // The first platform is the one the ROM was made for
+        let platform = match entry.platforms.first() {
+            Some(id) => Some(id.parse::<Platform>().map_err(invalid)?),
+            None => None,
+        };
+        let quirks = platform.map(|platform| {
+            let mut quirks = platform.quirks();
+            let changes = entry.quirky_platforms.get(&platform.to_string());
+            for (name, &value) in changes.into_iter().flatten() {
+                apply_database_quirk(&mut quirks, name, value);
+            }
+            quirks
+        });
+
+        let colors = match entry.colors.map(|colors| colors.pixels) {
+            Some(pixels) if pixels.len() >= 2 => Some(Colors {
+                off: parse_color(&pixels[0]).map_err(invalid)?,
+                on: parse_color(&pixels[1]).map_err(invalid)?,
+            }),
+            _ => None,
+        };
+
+        let mut keys: Vec<(String, u8)> = entry.keys.into_iter().collect();
+        keys.sort_by_key(|&(_, key)| key);
+
+        Ok(RomInfo {
+            title: title.to_string(),
+            author: (!authors.is_empty()).then(|| authors.join(", ")),
+            platform,
+            quirks,
+            tickrate: entry.tickrate,
+            colors,
+            keys,
+        })
+    }
+
+    pub fn lookup(&self, rom: &[u8]) -> Option<&RomInfo> {
+        self.roms.get(&rom_hash(rom))
+    }
+
+    /// Info for a ROM file: its database entry, else the settings stored in the file,
+    /// else a guess of its platform from its instructions
+    pub fn lookup_file(&self, file: &RomFile, name: &str) -> RomInfo {
+        if let Some(info) = self.lookup(&file.program).or(file.info.as_ref()) {
+            return info.clone();
+        }
+        let platform = rom::detect_platform(&file.program);
+        RomInfo {
+            platform,
+            quirks: platform.map(Platform::quirks),
+            ..RomInfo::unknown(name)
+        }
+    }
+}
+
+/// Applies a quirk named like in the CHIP-8 database
+fn apply_database_quirk(quirks: &mut Quirks, name: &str, value: bool) {
+    match name {
+        "shift" => quirks.shift_vy = !value,
+        "memoryIncrementByX" => quirks.memory_increment_by_x = value,
+        "memoryLeaveIUnchanged" => quirks.memory_increment = !value,
+        "wrap" => quirks.clip = !value,
+        "jump" => quirks.jump_vx = value,
+        "logic" => quirks.vf_reset = value,
+        // Waiting for the display interrupt before drawing is not emulated
+        _ => (),
+    }
+}
+
+/// Parses `#rrggbb`
+pub fn parse_color(color: &str) -> Result<[u8; 3], String> {
+    let hex = color
+        .strip_prefix('#')
+        .filter(|hex| hex.len() == 6)
+        .ok_or_else(|| format!("Invalid color: {}", color))?;
+    let channel = |i: usize| {
+        u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("Invalid color: {}", color))
+    };
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_bundled_rom_is_known() {
+        let database = Database::bundled();
+        for entry in fs::read_dir("roms").unwrap() {
+            let rom = fs::read(entry.unwrap().path()).unwrap();
+            let info = database.lookup(&rom).unwrap();
+            assert!(info.platform.is_some() && info.tickrate.is_some());
+        }
+        let brix = database.lookup(&fs::read("roms/BRIX").unwrap()).unwrap();
+        assert_eq!(brix.title, "Brix");
+        assert_eq!(brix.key_hints(), "left 4, right 6");
+    }
+
+    #[test]
+    fn applies_platform_and_quirky_entries() {
+        let json = r##"[{
+            "title": "Demo",
+            "roms": {
+                "0A0B": {
+                    "platforms": ["superchip", "originalChip8"],
+                    "quirkyPlatforms": {"superchip": {"wrap": true, "shift": false}},
+                    "colors": {"pixels": ["#000000", "#ff8000"]}
+                }
+            }
+        }]"##;
+        let database = Database::parse(json).unwrap();
+        let info = &database.roms["0a0b"];
+        assert_eq!(info.platform, Some(Platform::SuperChip));
+        let quirks = info.quirks.unwrap();
+        assert!(quirks.jump_vx && !quirks.clip && quirks.shift_vy);
+        assert_eq!(info.colors.unwrap().on, [0xff, 0x80, 0]);
+        assert_eq!(info.author, None);
+
+        assert!(
+            Database::parse(r#"[{"title": "x", "roms": {"0a": {"platforms": ["zx81"]}}}]"#)
+                .is_err()
+        );
+    }
+}
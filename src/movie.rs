@@ -0,0 +1,222 @@
+/*
+Movie files record the key state changes of a session, so it can be replayed exactly.
+They are plain text: a header describing how the emulator was set up, then one line per key change.
+
+CHIP8-MOVIE 1
+rom 2ea5d3e7f8c1cb6fdc3a7e4d3f26a4d3e7b2cb26
+seed 12345
+random seeded
+quirks key_wait_release=1 key_wait_beep=0 vf_reset=0 ...
+tickrate 15 <- instructions per frame
+120 5 1     <- frame, key (hex), state (1 = down, 0 = up)
+126 5 0
+end 300     <- frame at which the recording stopped
+*/
+
+use crate::{keypad::KeyChange, quirks::Quirks, rng::RandomKind, session::CYCLES_PER_FRAME};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+};
+
+const MAGIC: &str = "CHIP8-MOVIE 1";
+
+/// Everything needed to start the emulator in the same state as the recorded session
+#[derive(Clone, Debug, PartialEq)]
+pub struct MovieHeader {
+    // SHA-1 of the ROM bytes
+    pub rom_hash: String,
+    pub seed: u64,
+    pub random: RandomKind,
+    pub quirks: Quirks,
+    pub tickrate: u32,
+}
+
+pub struct MovieRecorder {
+    out: BufWriter<File>,
+}
+
+impl MovieRecorder {
+    pub fn create(path: &str, header: &MovieHeader) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "{}", MAGIC)?;
+        writeln!(out, "rom {}", header.rom_hash)?;
+        writeln!(out, "seed {}", header.seed)?;
+        writeln!(out, "random {}", header.random)?;
+        writeln!(out, "quirks {}", header.quirks)?;
+        writeln!(out, "tickrate {}", header.tickrate)?;
+
+        Ok(MovieRecorder { out })
+    }
+
+    pub fn record(&mut self, frame: u64, change: KeyChange) -> io::Result<()> {
+        writeln!(
+            self.out,
+            "{} {:x} {}",
+            frame, change.key, change.state as u8
+        )
+    }
+
+    /// Marks the end of the recording and flushes the file
+    pub fn finish(mut self, frame: u64) -> io::Result<()> {
+        writeln!(self.out, "end {}", frame)?;
+        self.out.flush()
+    }
+}
+
+pub struct Movie {
+    pub header: MovieHeader,
+    // Pending key changes, with the frame they happen at
+    events: VecDeque<(u64, KeyChange)>,
+    end: Option<u64>,
+}
+
+impl Movie {
+    pub fn load(path: &str) -> io::Result<Self> {
+        Self::parse(BufReader::new(File::open(path)?))
+    }
+
+    pub(crate) fn parse(reader: impl BufRead) -> io::Result<Self> {
+        let mut lines = reader.lines();
+        if lines.next().transpose()?.as_deref() != Some(MAGIC) {
+            return Err(invalid("not a CHIP-8 movie file"));
+        }
+
+        let mut rom_hash = None;
+        let mut seed = None;
+        let mut random = RandomKind::Seeded;
+        let mut quirks = Quirks::default();
+        // Movies from before the tickrate was recorded
+        let mut tickrate = CYCLES_PER_FRAME;
+        let mut events = VecDeque::new();
+        let mut end = None;
+
+        for line in lines {
+            let line = line?;
+            let (field, value) = line.split_once(' ').unwrap_or((&line, ""));
+            match field {
+                "" => (),
+                "rom" => rom_hash = Some(value.to_string()),
+                "seed" => seed = Some(value.parse().map_err(|_| invalid("invalid seed"))?),
+                "random" => random = value.parse().map_err(|e: String| invalid(&e))?,
+                "quirks" => quirks = value.parse().map_err(|e: String| invalid(&e))?,
+                "tickrate" => tickrate = value.parse().map_err(|_| invalid("invalid tickrate"))?,
+                "end" => end = Some(value.parse().map_err(|_| invalid("invalid end frame"))?),
+                _ => events.push_back(Self::parse_event(&line)?),
+            }
+        }
+
+        let header = MovieHeader {
+            rom_hash: rom_hash.ok_or_else(|| invalid("missing rom hash"))?,
+            seed: seed.ok_or_else(|| invalid("missing seed"))?,
+            random,
+            quirks,
+            tickrate,
+        };
+
+        Ok(Movie {
+            header,
+            events,
+            end,
+        })
+    }
+
+    fn parse_event(line: &str) -> io::Result<(u64, KeyChange)> {
+        let error = || invalid(&format!("invalid key event: {}", line));
+        let mut fields = line.split_whitespace();
+        let frame = fields.next().and_then(|f| f.parse().ok());
+        let key = fields
+            .next()
+            .and_then(|k| usize::from_str_radix(k, 16).ok());
+        let state = match fields.next() {
+            Some("1") => Some(true),
+            Some("0") => Some(false),
+            _ => None,
+        };
+
+        match (frame, key, state) {
+            (Some(frame), Some(key), Some(state)) if key < 16 => {
+                Ok((frame, KeyChange { key, state }))
+            }
+            _ => Err(error()),
+        }
+    }
+
+    /// Returns the key changes to apply at the start of the given frame
+    pub fn changes_at(&mut self, frame: u64) -> Vec<KeyChange> {
+        let mut changes = Vec::new();
+        while let Some(&(at, change)) = self.events.front() {
+            if at > frame {
+                break;
+            }
+            changes.push(change);
+            self.events.pop_front();
+        }
+        changes
+    }
+
+    /// True once every key change has been played and the recorded end is reached
+    pub fn is_finished(&self, frame: u64) -> bool {
+        self.events.is_empty() && self.end.is_none_or(|end| frame >= end)
+    }
+}
+
+/// Identifies a ROM by the SHA-1 of its bytes
+pub fn rom_hash(rom: &[u8]) -> String {
+    sha1_smol::Sha1::from(rom).digest().to_string()
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    fn key(key: usize, state: bool) -> KeyChange {
+        KeyChange { key, state }
+    }
+
+    #[test]
+    fn record_then_replay() {
+        let path = env::temp_dir().join(format!("chip8-movie-{}.c8m", process::id()));
+        let path = path.to_str().unwrap();
+        let header = MovieHeader {
+            rom_hash: rom_hash(&[0x12, 0x00]),
+            seed: 42,
+            random: RandomKind::Scripted(vec![0x12, 0x34]),
+            quirks: Quirks {
+                key_wait_release: false,
+                key_wait_beep: true,
+                clip: true,
+                ..Quirks::default()
+            },
+            tickrate: 15,
+        };
+
+        let mut recorder = MovieRecorder::create(path, &header).unwrap();
+        recorder.record(3, key(0xa, true)).unwrap();
+        recorder.record(3, key(0x1, true)).unwrap();
+        recorder.record(7, key(0xa, false)).unwrap();
+        recorder.finish(10).unwrap();
+
+        let mut movie = Movie::load(path).unwrap();
+        assert_eq!(movie.header, header);
+        assert!(movie.changes_at(2).is_empty());
+        assert_eq!(movie.changes_at(3).len(), 2);
+        assert_eq!(movie.changes_at(8), vec![key(0xa, false)]);
+        assert!(!movie.is_finished(9));
+        assert!(movie.is_finished(10));
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        assert!(Movie::parse("hello".as_bytes()).is_err());
+        assert!(Movie::parse("CHIP8-MOVIE 1\nseed 1\n".as_bytes()).is_err());
+        assert!(Movie::parse("CHIP8-MOVIE 1\nrom ab\nseed 1\n5 g 1\n".as_bytes()).is_err());
+        assert!(Movie::parse("CHIP8-MOVIE 1\nrom ab\nseed 1\n5 10 1\n".as_bytes()).is_err());
+    }
+}
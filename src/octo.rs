@@ -0,0 +1,934 @@
+// Octo, the CHIP-8 assembly language of https://github.com/JohnEarnest/Octo, and its cartridges.
+// The assembler covers labels, :const, :alias, :org, :next, :unpack, :byte, :pointer, every instruction,
+// if/then, if/begin/else/end and loop/while/again. Macros, :calc, :stringmode and the comparison
+// operators built on VF (`<`, `>`, `<=`, `>=`) are not supported.
+
+use crate::{
+    chip8::{MEMORY_SIZE, START_ALLOWED_ADDRESS},
+    metadata::{parse_color, Colors},
+    quirks::Quirks,
+};
+use serde::Deserialize;
+use std::{collections::HashMap, fmt, io::Cursor};
+
+#[derive(Debug, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// How a label value goes into the bytes once it is known
+#[derive(Clone, Copy)]
+enum Patch {
+    // Low 12 bits of an instruction
+    Address,
+    // 16 bits, for `i := long` and :pointer
+    Word,
+    // High byte of the address, or-ed with a nibble, then the low byte, for :unpack
+    Unpack(u8),
+}
+
+struct Fixup {
+    offset: usize,
+    label: String,
+    patch: Patch,
+    line: usize,
+}
+
+enum Block {
+    // Offset of the jump to the else branch or to the end
+    If(usize),
+    Else(usize),
+    Loop { start: u16, breaks: Vec<usize> },
+}
+
+enum Condition {
+    Equal(u8, Operand),
+    NotEqual(u8, Operand),
+    Key(u8),
+    NotKey(u8),
+}
+
+enum Operand {
+    Register(u8),
+    Byte(u8),
+}
+
+struct Assembler<'a> {
+    tokens: Vec<(&'a str, usize)>,
+    position: usize,
+    // Memory from 0x200, and where the next byte goes
+    bytes: Vec<u8>,
+    here: u16,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, u16>,
+    aliases: HashMap<String, u8>,
+    fixups: Vec<Fixup>,
+    blocks: Vec<Block>,
+    // Label to define at the second byte of the next instruction
+    next: Option<String>,
+    line: usize,
+    // Address and source line of each instruction
+    lines: Vec<(u16, usize)>,
+}
+
+/// Assembled program with its source map
+pub struct Program {
+    pub bytes: Vec<u8>,
+    // Address and source line of each instruction, by address
+    lines: Vec<(u16, usize)>,
+}
+
+impl Program {
+    /// Line of the instruction at the address
+    pub fn line_at(&self, address: u16) -> Option<usize> {
+        let index = self
+            .lines
+            .binary_search_by_key(&address, |&(a, _)| a)
+            .ok()?;
+        Some(self.lines[index].1)
+    }
+
+    /// First instruction of the line, or of the next line with instructions.
+    /// Returns its address and line.
+    pub fn address_of(&self, line: usize) -> Option<(u16, usize)> {
+        self.lines
+            .iter()
+            .filter(|&&(_, l)| l >= line)
+            .min_by_key(|&&(address, l)| (l, address))
+            .copied()
+    }
+}
+
+/// Assembles the program, loaded at 0x200
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    assemble_program(source).map(|program| program.bytes)
+}
+
+/// Assembles the program, loaded at 0x200, keeping track of the source line of each instruction
+pub fn assemble_program(source: &str) -> Result<Program, AsmError> {
+    let tokens = source
+        .lines()
+        .enumerate()
+        .flat_map(|(i, line)| {
+            let code = line.split('#').next().unwrap_or_default();
+            code.split_whitespace().map(move |token| (token, i + 1))
+        })
+        .collect();
+    let mut assembler = Assembler {
+        tokens,
+        position: 0,
+        bytes: Vec::new(),
+        here: START_ALLOWED_ADDRESS as u16,
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        fixups: Vec::new(),
+        blocks: Vec::new(),
+        next: None,
+        line: 1,
+        lines: Vec::new(),
+    };
+    assembler.run()?;
+    let mut lines = assembler.lines;
+    // :org may go back, the last instruction assembled at an address wins
+    lines.reverse();
+    lines.sort_by_key(|&(address, _)| address);
+    lines.dedup_by_key(|&mut (address, _)| address);
+    Ok(Program {
+        bytes: assembler.bytes,
+        lines,
+    })
+}
+
+impl<'a> Assembler<'a> {
+    fn run(&mut self) -> Result<(), AsmError> {
+        // Like Octo, start with a jump to main unless the program begins there
+        let is_main = |w: &[(&str, usize)]| w[0].0 == ":" && w[1].0 == "main";
+        let has_main = self.tokens.windows(2).any(is_main);
+        let starts_with_main = self.tokens.len() >= 2 && is_main(&self.tokens[..2]);
+        if has_main && !starts_with_main {
+            self.jump_to(0x1000, "main")?;
+        }
+
+        while self.position < self.tokens.len() {
+            let (token, line) = self.tokens[self.position];
+            self.position += 1;
+            self.line = line;
+            self.statement(token)?;
+        }
+
+        if let Some(block) = self.blocks.last() {
+            let open = match block {
+                Block::If(_) | Block::Else(_) => "if ... begin without end",
+                Block::Loop { .. } => "loop without again",
+            };
+            return Err(self.error(open.to_string()));
+        }
+        for fixup in std::mem::take(&mut self.fixups) {
+            let value = match self.labels.get(&fixup.label) {
+                Some(&value) => value,
+                None => {
+                    return Err(AsmError {
+                        line: fixup.line,
+                        message: format!("undefined name: {}", fixup.label),
+                    })
+                }
+            };
+            self.patch(fixup.offset, fixup.patch, value);
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, token: &'a str) -> Result<(), AsmError> {
+        match token {
+            ":" => {
+                let name = self.name()?;
+                self.define(name, self.here)?;
+                return Ok(());
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.number_token()?;
+                self.constants.insert(name.to_string(), value);
+                return Ok(());
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let register = self.register()?;
+                self.aliases.insert(name.to_string(), register);
+                return Ok(());
+            }
+            ":org" => {
+                let address = self.number_token()?;
+                if !(START_ALLOWED_ADDRESS..MEMORY_SIZE).contains(&(address as usize)) {
+                    return Err(self.error(format!("cannot assemble at {:#05x}", address)));
+                }
+                self.here = address;
+                return Ok(());
+            }
+            ":next" => {
+                self.next = Some(self.name()?.to_string());
+                return Ok(());
+            }
+            ":breakpoint" => {
+                self.name()?;
+                return Ok(());
+            }
+            ":monitor" => {
+                self.next_token()?;
+                self.next_token()?;
+                return Ok(());
+            }
+            ":byte" => {
+                let value = self.byte()?;
+                self.emit(value)?;
+                return Ok(());
+            }
+            ":pointer" => {
+                let target = self.next_token()?;
+                let offset = self.offset();
+                self.emit_word(0)?;
+                self.reference(offset, target, Patch::Word)?;
+                return Ok(());
+            }
+            ":macro" | ":calc" | ":stringmode" | ":assert" => {
+                return Err(self.error(format!("{} is not supported", token)));
+            }
+            _ => (),
+        }
+        if let Some(value) = parse_number(token) {
+            let value = self.to_byte(value)?;
+            self.emit(value)?;
+            return Ok(());
+        }
+
+        let opcode = match token {
+            "clear" => 0x00e0,
+            "return" | ";" => 0x00ee,
+            "exit" => 0x00fd,
+            "lores" => 0x00fe,
+            "hires" => 0x00ff,
+            "scroll-left" => 0x00fc,
+            "scroll-right" => 0x00fb,
+            "scroll-down" => 0x00c0 | self.nibble()?,
+            "scroll-up" => 0x00d0 | self.nibble()?,
+            "audio" => 0xf002,
+            "bcd" => 0xf033 | self.x()?,
+            "saveflags" => 0xf075 | self.x()?,
+            "loadflags" => 0xf085 | self.x()?,
+            "save" | "load" => {
+                let x = self.register()?;
+                let range = self.tokens.get(self.position).map(|t| t.0) == Some("-");
+                if range {
+                    self.position += 1;
+                    let y = self.register()?;
+                    let low = if token == "save" { 2 } else { 3 };
+                    0x5000 | (x as u16) << 8 | (y as u16) << 4 | low
+                } else {
+                    let low = if token == "save" { 0x55 } else { 0x65 };
+                    0xf000 | (x as u16) << 8 | low
+                }
+            }
+            "sprite" => {
+                let x = self.register()? as u16;
+                let y = self.register()? as u16;
+                0xd000 | x << 8 | y << 4 | self.nibble()?
+            }
+            "plane" => 0xf001 | self.nibble()? << 8,
+            "jump" | "jump0" | ":call" => {
+                let base = match token {
+                    "jump" => 0x1000,
+                    "jump0" => 0xb000,
+                    _ => 0x2000,
+                };
+                let target = self.next_token()?;
+                let offset = self.offset();
+                self.emit_word(base)?;
+                self.reference(offset, target, Patch::Address)?;
+                return Ok(());
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let low = match token {
+                    "delay" => 0x15,
+                    "buzzer" => 0x18,
+                    _ => 0x3a,
+                };
+                0xf000 | self.x()? | low
+            }
+            "i" => return self.i_statement(),
+            "if" => return self.if_statement(),
+            "else" => {
+                let jump = match self.blocks.pop() {
+                    Some(Block::If(jump)) => jump,
+                    _ => return Err(self.error(String::from("else without if ... begin"))),
+                };
+                let offset = self.offset();
+                self.emit_word(0x1000)?;
+                self.patch(jump, Patch::Address, self.here);
+                self.blocks.push(Block::Else(offset));
+                return Ok(());
+            }
+            "end" => {
+                match self.blocks.pop() {
+                    Some(Block::If(jump) | Block::Else(jump)) => {
+                        self.patch(jump, Patch::Address, self.here)
+                    }
+                    _ => return Err(self.error(String::from("end without if ... begin"))),
+                }
+                return Ok(());
+            }
+            "loop" => {
+                self.blocks.push(Block::Loop {
+                    start: self.here,
+                    breaks: Vec::new(),
+                });
+                return Ok(());
+            }
+            "while" => {
+                let condition = self.condition()?;
+                self.emit_word(skip_if(&condition))?;
+                let offset = self.offset();
+                self.emit_word(0x1000)?;
+                match self
+                    .blocks
+                    .iter_mut()
+                    .rev()
+                    .find(|block| matches!(block, Block::Loop { .. }))
+                {
+                    Some(Block::Loop { breaks, .. }) => breaks.push(offset),
+                    _ => return Err(self.error(String::from("while outside of a loop"))),
+                }
+                return Ok(());
+            }
+            "again" => {
+                let (start, breaks) = match self.blocks.pop() {
+                    Some(Block::Loop { start, breaks }) => (start, breaks),
+                    _ => return Err(self.error(String::from("again without loop"))),
+                };
+                self.emit_word(0x1000 | start)?;
+                for offset in breaks {
+                    self.patch(offset, Patch::Address, self.here);
+                }
+                return Ok(());
+            }
+            ":unpack" => {
+                let high = self.nibble()? as u8;
+                let target = self.next_token()?;
+                let offset = self.offset();
+                // v0 := high byte, v1 := low byte
+                self.emit_word(0x6000)?;
+                self.emit_word(0x6100)?;
+                self.reference(offset, target, Patch::Unpack(high << 4))?;
+                return Ok(());
+            }
+            _ => {
+                if let Some(x) = self.register_named(token) {
+                    return self.register_statement(x);
+                }
+                // Anything else is a subroutine call
+                let offset = self.offset();
+                self.emit_word(0x2000)?;
+                self.reference(offset, token, Patch::Address)?;
+                return Ok(());
+            }
+        };
+        self.emit_word(opcode)?;
+        Ok(())
+    }
+
+    fn register_statement(&mut self, x: u8) -> Result<(), AsmError> {
+        let x16 = (x as u16) << 8;
+        let operator = self.next_token()?;
+        let operand = self.next_token()?;
+        let opcode = match (operator, operand) {
+            (":=", "random") => 0xc000 | x16 | self.byte()? as u16,
+            (":=", "delay") => 0xf007 | x16,
+            (":=", "key") => 0xf00a | x16,
+            (":=" | "+=" | "-=", _) => match self.operand(operand)? {
+                Operand::Register(y) => {
+                    let low = match operator {
+                        ":=" => 0,
+                        "+=" => 4,
+                        _ => 5,
+                    };
+                    0x8000 | x16 | (y as u16) << 4 | low
+                }
+                Operand::Byte(n) => match operator {
+                    ":=" => 0x6000 | x16 | n as u16,
+                    "+=" => 0x7000 | x16 | n as u16,
+                    _ => 0x7000 | x16 | n.wrapping_neg() as u16,
+                },
+            },
+            _ => {
+                let low = match operator {
+                    "|=" => 1,
+                    "&=" => 2,
+                    "^=" => 3,
+                    ">>=" => 6,
+                    "=-" => 7,
+                    "<<=" => 0xe,
+                    _ => return Err(self.error(format!("unknown operator: {}", operator))),
+                };
+                let y = self.register_or_error(operand)?;
+                0x8000 | x16 | (y as u16) << 4 | low
+            }
+        };
+        self.emit_word(opcode)?;
+        Ok(())
+    }
+
+    fn i_statement(&mut self) -> Result<(), AsmError> {
+        let operator = self.next_token()?;
+        if operator == "+=" {
+            let x = self.x()?;
+            self.emit_word(0xf01e | x)?;
+            return Ok(());
+        }
+        if operator != ":=" {
+            return Err(self.error(format!("unknown operator: {}", operator)));
+        }
+        let operand = self.next_token()?;
+        match operand {
+            "hex" => {
+                let x = self.x()?;
+                self.emit_word(0xf029 | x)?;
+            }
+            "bighex" => {
+                let x = self.x()?;
+                self.emit_word(0xf030 | x)?;
+            }
+            "long" => {
+                let target = self.next_token()?;
+                self.emit_word(0xf000)?;
+                let offset = self.offset();
+                self.emit_word(0)?;
+                self.reference(offset, target, Patch::Word)?;
+            }
+            _ => {
+                let offset = self.offset();
+                self.emit_word(0xa000)?;
+                self.reference(offset, operand, Patch::Address)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn if_statement(&mut self) -> Result<(), AsmError> {
+        let condition = self.condition()?;
+        match self.next_token()? {
+            "then" => self.emit_word(skip_unless(&condition))?,
+            "begin" => {
+                self.emit_word(skip_if(&condition))?;
+                let offset = self.offset();
+                self.emit_word(0x1000)?;
+                self.blocks.push(Block::If(offset));
+            }
+            other => return Err(self.error(format!("expected then or begin, found {}", other))),
+        }
+        Ok(())
+    }
+
+    fn condition(&mut self) -> Result<Condition, AsmError> {
+        let x = self.register()?;
+        let operator = self.next_token()?;
+        match operator {
+            "key" => Ok(Condition::Key(x)),
+            "-key" => Ok(Condition::NotKey(x)),
+            "==" | "!=" => {
+                let token = self.next_token()?;
+                let operand = self.operand(token)?;
+                Ok(if operator == "==" {
+                    Condition::Equal(x, operand)
+                } else {
+                    Condition::NotEqual(x, operand)
+                })
+            }
+            _ => Err(self.error(format!("comparison not supported: {}", operator))),
+        }
+    }
+
+    fn next_token(&mut self) -> Result<&'a str, AsmError> {
+        match self.tokens.get(self.position) {
+            Some(&(token, line)) => {
+                self.position += 1;
+                self.line = line;
+                Ok(token)
+            }
+            None => Err(self.error(String::from("unexpected end of file"))),
+        }
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), AsmError> {
+        let token = self.next_token()?;
+        if token != expected {
+            return Err(self.error(format!("expected {}, found {}", expected, token)));
+        }
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<&'a str, AsmError> {
+        let name = self.next_token()?;
+        if parse_number(name).is_some() || self.register_named(name).is_some() {
+            return Err(self.error(format!("invalid name: {}", name)));
+        }
+        Ok(name)
+    }
+
+    fn define(&mut self, name: &str, address: u16) -> Result<(), AsmError> {
+        if self.labels.insert(name.to_string(), address).is_some() {
+            return Err(self.error(format!("{} is defined twice", name)));
+        }
+        Ok(())
+    }
+
+    fn register_named(&self, token: &str) -> Option<u8> {
+        if let Some(&register) = self.aliases.get(token) {
+            return Some(register);
+        }
+        let digit = token.strip_prefix(['v', 'V'])?;
+        if digit.len() != 1 {
+            return None;
+        }
+        u8::from_str_radix(digit, 16).ok()
+    }
+
+    fn register_or_error(&self, token: &str) -> Result<u8, AsmError> {
+        self.register_named(token)
+            .ok_or_else(|| self.error(format!("expected a register, found {}", token)))
+    }
+
+    fn register(&mut self) -> Result<u8, AsmError> {
+        let token = self.next_token()?;
+        self.register_or_error(token)
+    }
+
+    /// Register in the x position of an instruction
+    fn x(&mut self) -> Result<u16, AsmError> {
+        Ok((self.register()? as u16) << 8)
+    }
+
+    fn operand(&self, token: &str) -> Result<Operand, AsmError> {
+        match self.register_named(token) {
+            Some(register) => Ok(Operand::Register(register)),
+            None => Ok(Operand::Byte(self.to_byte(self.value(token)?)?)),
+        }
+    }
+
+    /// A number or constant
+    fn value(&self, token: &str) -> Result<i32, AsmError> {
+        parse_number(token)
+            .or_else(|| self.constants.get(token).map(|&value| value as i32))
+            .ok_or_else(|| self.error(format!("expected a number, found {}", token)))
+    }
+
+    fn number_token(&mut self) -> Result<u16, AsmError> {
+        let token = self.next_token()?;
+        let value = self.value(token)?;
+        u16::try_from(value).map_err(|_| self.error(format!("{} is out of range", value)))
+    }
+
+    fn to_byte(&self, value: i32) -> Result<u8, AsmError> {
+        if !(-128..=255).contains(&value) {
+            return Err(self.error(format!("{} does not fit in a byte", value)));
+        }
+        Ok(value as u8)
+    }
+
+    fn byte(&mut self) -> Result<u8, AsmError> {
+        let token = self.next_token()?;
+        self.to_byte(self.value(token)?)
+    }
+
+    fn nibble(&mut self) -> Result<u16, AsmError> {
+        let token = self.next_token()?;
+        match self.value(token)? {
+            value @ 0..=15 => Ok(value as u16),
+            value => Err(self.error(format!("{} does not fit in a nibble", value))),
+        }
+    }
+
+    /// Uses the label or constant now if it is known, once all labels are defined otherwise
+    fn reference(&mut self, offset: usize, target: &str, patch: Patch) -> Result<(), AsmError> {
+        let known = parse_number(target)
+            .map(|value| value as u16)
+            .or_else(|| self.constants.get(target).copied())
+            .or_else(|| self.labels.get(target).copied());
+        match known {
+            Some(value) => self.patch(offset, patch, value),
+            None => self.fixups.push(Fixup {
+                offset,
+                label: target.to_string(),
+                patch,
+                line: self.line,
+            }),
+        }
+        Ok(())
+    }
+
+    fn patch(&mut self, offset: usize, patch: Patch, value: u16) {
+        match patch {
+            Patch::Address => {
+                self.bytes[offset] = self.bytes[offset] & 0xf0 | (value >> 8) as u8 & 0xf;
+                self.bytes[offset + 1] = value as u8;
+            }
+            Patch::Word => self.bytes[offset..offset + 2].copy_from_slice(&value.to_be_bytes()),
+            Patch::Unpack(high) => {
+                self.bytes[offset + 1] = high | (value >> 8) as u8 & 0xf;
+                self.bytes[offset + 3] = value as u8;
+            }
+        }
+    }
+
+    /// Offset in the bytes of the next byte
+    fn offset(&self) -> usize {
+        self.here as usize - START_ALLOWED_ADDRESS
+    }
+
+    fn emit(&mut self, byte: u8) -> Result<(), AsmError> {
+        if self.here as usize >= MEMORY_SIZE {
+            return Err(self.error(String::from("program doesn't fit in memory")));
+        }
+        let offset = self.offset();
+        if offset >= self.bytes.len() {
+            self.bytes.resize(offset + 1, 0);
+        }
+        self.bytes[offset] = byte;
+        self.here += 1;
+        Ok(())
+    }
+
+    fn emit_word(&mut self, word: u16) -> Result<(), AsmError> {
+        if let Some(name) = self.next.take() {
+            // Errors on duplicates are reported like for any label
+            self.labels.entry(name).or_insert(self.here + 1);
+        }
+        self.lines.push((self.here, self.line));
+        let [high, low] = word.to_be_bytes();
+        self.emit(high)?;
+        self.emit(low)
+    }
+
+    fn jump_to(&mut self, opcode: u16, label: &str) -> Result<(), AsmError> {
+        let offset = self.offset();
+        self.emit_word(opcode)?;
+        self.fixups.push(Fixup {
+            offset,
+            label: label.to_string(),
+            patch: Patch::Address,
+            line: 1,
+        });
+        Ok(())
+    }
+
+    fn error(&self, message: String) -> AsmError {
+        AsmError {
+            line: self.line,
+            message,
+        }
+    }
+}
+
+/// Instruction skipping the next one when the condition is false, for `if ... then`
+fn skip_unless(condition: &Condition) -> u16 {
+    match *condition {
+        Condition::Equal(x, Operand::Byte(n)) => 0x4000 | (x as u16) << 8 | n as u16,
+        Condition::NotEqual(x, Operand::Byte(n)) => 0x3000 | (x as u16) << 8 | n as u16,
+        Condition::Equal(x, Operand::Register(y)) => 0x9000 | (x as u16) << 8 | (y as u16) << 4,
+        Condition::NotEqual(x, Operand::Register(y)) => 0x5000 | (x as u16) << 8 | (y as u16) << 4,
+        Condition::Key(x) => 0xe0a1 | (x as u16) << 8,
+        Condition::NotKey(x) => 0xe09e | (x as u16) << 8,
+    }
+}
+
+/// Instruction skipping the next one when the condition is true, for `begin` and `while`
+fn skip_if(condition: &Condition) -> u16 {
+    match *condition {
+        Condition::Equal(x, Operand::Byte(n)) => 0x3000 | (x as u16) << 8 | n as u16,
+        Condition::NotEqual(x, Operand::Byte(n)) => 0x4000 | (x as u16) << 8 | n as u16,
+        Condition::Equal(x, Operand::Register(y)) => 0x5000 | (x as u16) << 8 | (y as u16) << 4,
+        Condition::NotEqual(x, Operand::Register(y)) => 0x9000 | (x as u16) << 8 | (y as u16) << 4,
+        Condition::Key(x) => 0xe09e | (x as u16) << 8,
+        Condition::NotKey(x) => 0xe0a1 | (x as u16) << 8,
+    }
+}
+
+/// Parses decimal, 0x hexadecimal and 0b binary numbers, possibly negative
+fn parse_number(token: &str) -> Option<i32> {
+    let (negative, digits) = match token.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, token),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i32::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i32::from_str_radix(binary, 2).ok()?
+    } else if digits.bytes().all(|b| b.is_ascii_digit()) && !digits.is_empty() {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+/// Source code and settings of an Octo cartridge
+pub struct Cartridge {
+    pub source: String,
+    pub quirks: Quirks,
+    pub tickrate: Option<u32>,
+    pub colors: Option<Colors>,
+}
+
+// Options as Octo saves them, quirk flags turn the modern behavior on
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct CartridgeOptions {
+    tickrate: Option<u32>,
+    fill_color: Option<String>,
+    background_color: Option<String>,
+    shift_quirks: bool,
+    load_store_quirks: bool,
+    clip_quirks: bool,
+    jump_quirks: bool,
+    logic_quirks: bool,
+}
+
+#[derive(Deserialize)]
+struct Payload {
+    program: String,
+    #[serde(default)]
+    options: CartridgeOptions,
+}
+
+/// Reads an Octo cartridge: a GIF whose pixels hide a JSON payload with the source code and options.
+/// Each payload byte is spread over the low two bits of four palette indices, most significant first,
+/// through every frame, after a 32-bit big-endian length.
+pub fn read_cartridge(gif: &[u8]) -> Result<Cartridge, String> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options
+        .read_info(Cursor::new(gif))
+        .map_err(|e| format!("invalid GIF: {}", e))?;
+    let mut pixels = Vec::new();
+    while let Some(frame) = decoder
+        .read_next_frame()
+        .map_err(|e| format!("invalid GIF: {}", e))?
+    {
+        pixels.extend_from_slice(&frame.buffer);
+    }
+
+    let bytes: Vec<u8> = pixels
+        .chunks_exact(4)
+        .map(|p| p.iter().fold(0, |byte, index| byte << 2 | index & 3))
+        .collect();
+    let not_a_cartridge = || String::from("the GIF is not an Octo cartridge");
+    let length = bytes
+        .get(..4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize)
+        .ok_or_else(not_a_cartridge)?;
+    let json = bytes.get(4..4 + length).ok_or_else(not_a_cartridge)?;
+    let payload: Payload = serde_json::from_slice(json).map_err(|_| not_a_cartridge())?;
+
+    let options = payload.options;
+    let quirks = Quirks {
+        shift_vy: !options.shift_quirks,
+        memory_increment: !options.load_store_quirks,
+        clip: options.clip_quirks,
+        jump_vx: options.jump_quirks,
+        vf_reset: options.logic_quirks,
+        ..Quirks::default()
+    };
+    let colors = match (&options.fill_color, &options.background_color) {
+        (Some(on), Some(off)) => Some(Colors {
+            on: parse_color(on)?,
+            off: parse_color(off)?,
+        }),
+        _ => None,
+    };
+    Ok(Cartridge {
+        source: payload.program,
+        quirks,
+        tickrate: options.tickrate,
+        colors,
+    })
+}
+
+/// Builds a cartridge image holding the payload, the way Octo does but without drawing a label
+#[cfg(test)]
+pub fn write_cartridge(payload: &str) -> Vec<u8> {
+    let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+    bytes.extend_from_slice(payload.as_bytes());
+    let mut pixels: Vec<u8> = bytes
+        .iter()
+        .flat_map(|byte| [byte >> 6, byte >> 4 & 3, byte >> 2 & 3, byte & 3])
+        .collect();
+    let width = 64;
+    pixels.resize(pixels.len().div_ceil(width) * width, 0);
+    let height = (pixels.len() / width) as u16;
+
+    let palette = [0, 0, 0, 85, 85, 85, 170, 170, 170, 255, 255, 255];
+    let mut gif = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(&mut gif, width as u16, height, &palette).unwrap();
+        let frame = gif::Frame::from_indexed_pixels(width as u16, height, pixels, None);
+        encoder.write_frame(&frame).unwrap();
+    }
+    gif
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assembles_instructions_and_control_flow() {
+        let source = "
+            :alias x v3
+            :const SPEED 2
+            : main
+                clear
+                x := 0
+                i := ball      # forward reference
+                loop
+                    sprite x v4 1
+                    x += SPEED
+                    if x == 60 then x := 0
+                    while x != 40
+                    v0 := key
+                    if v0 -key begin
+                        vf := 1
+                    else
+                        draw
+                    end
+                again
+                i := long ball
+                save v1 - v2
+            : draw
+                v1 <<= v2
+                return
+            : ball
+                0x80 0b11
+        ";
+        #[rustfmt::skip]
+        let expected = [
+            // jump main, as main is not first
+            0x12, 0x02,
+            0x00, 0xe0, 0x63, 0x00, 0xa2, 0x2c,
+            // loop
+            0xd3, 0x41, 0x73, 0x02, 0x43, 0x3c, 0x63, 0x00,
+            // while: leave when x == 40
+            0x43, 0x28, 0x12, 0x22,
+            0xf0, 0x0a,
+            // if -key begin ... else ... end
+            0xe0, 0xa1, 0x12, 0x1e, 0x6f, 0x01, 0x12, 0x20, 0x22, 0x28,
+            // again
+            0x12, 0x08,
+            0xf0, 0x00, 0x02, 0x2c, 0x51, 0x22,
+            // draw
+            0x81, 0x2e, 0x00, 0xee,
+            // ball
+            0x80, 0x03,
+        ];
+        assert_eq!(assemble(source).unwrap(), expected);
+    }
+
+    #[test]
+    fn jumps_to_main_and_reports_errors() {
+        let program = assemble(": data 1 2\n: main jump main").unwrap();
+        assert_eq!(program, [0x12, 0x04, 1, 2, 0x12, 0x04]);
+
+        let error = assemble("clear\n\nv0 += vg").err().unwrap();
+        assert_eq!(error.to_string(), "line 3: expected a number, found vg");
+        assert_eq!(
+            assemble("jump nowhere").err().unwrap().message,
+            "undefined name: nowhere"
+        );
+        assert_eq!(
+            assemble("loop clear").err().unwrap().message,
+            "loop without again"
+        );
+        assert_eq!(
+            assemble(":org 0xFFFF 1 2 3").err().unwrap().message,
+            "cannot assemble at 0xffff"
+        );
+        assert_eq!(
+            assemble(":org 0xFFE 1 2 3").err().unwrap().message,
+            "program doesn't fit in memory"
+        );
+        assert_eq!(assemble(":org 0xFFF 1").unwrap().len(), 0xe00);
+    }
+
+    #[test]
+    fn maps_lines_to_addresses() {
+        let program =
+            assemble_program(": main\n  v0 := 1\n\n  loop\n    v0 += 1\n  again\n").unwrap();
+        assert_eq!(program.line_at(0x200), Some(2));
+        assert_eq!(program.line_at(0x202), Some(5));
+        assert_eq!(program.line_at(0x203), None);
+        // Lines without instructions go to the next one
+        assert_eq!(program.address_of(3), Some((0x202, 5)));
+        assert_eq!(program.address_of(6), Some((0x204, 6)));
+        assert_eq!(program.address_of(7), None);
+    }
+
+    #[test]
+    fn reads_cartridges() {
+        let payload = r##"{"program": ": main v0 := 5 jump main",
+            "options": {"tickrate": 20, "shiftQuirks": true, "clipQuirks": true,
+                "fillColor": "#FFCC00", "backgroundColor": "#996600"}}"##;
+        let cartridge = read_cartridge(&write_cartridge(payload)).unwrap();
+        assert_eq!(cartridge.tickrate, Some(20));
+        assert!(!cartridge.quirks.shift_vy && cartridge.quirks.clip);
+        assert!(cartridge.quirks.memory_increment);
+        assert_eq!(cartridge.colors.unwrap().on, [0xff, 0xcc, 0]);
+        let program = assemble(&cartridge.source).unwrap();
+        assert_eq!(program, [0x60, 0x05, 0x12, 0x00]);
+    }
+}
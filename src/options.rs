@@ -0,0 +1,247 @@
+use crate::{
+    chip8::START_ALLOWED_ADDRESS,
+    platform::Platform,
+    quirks::Quirks,
+    rng::RandomKind,
+    rom::RomFormat,
+    speaker::ToneConfig,
+    trace::{TraceFilter, TraceFormat},
+    watch::Resume,
+};
+use std::ops::RangeInclusive;
+
+/// Command line options
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    // Rom file or name in the rom directory, the launcher opens when missing
+    pub rom: Option<String>,
+    pub rom_dir: String,
+    // How to read the ROM file
+    pub format: RomFormat,
+    // Platform whose quirks to use, instead of the one from the ROM database
+    pub platform: Option<Platform>,
+    // Quirks to change from the platform ones, by name
+    pub quirks: Vec<(String, bool)>,
+    // Instructions per frame, instead of the one from the ROM database
+    pub tickrate: Option<u32>,
+    // Where the ROM goes in memory and starts running
+    pub load_address: usize,
+    // Movie files to record into or replay
+    pub record: Option<String>,
+    pub replay: Option<String>,
+    // Random seed, picked at random when missing
+    pub seed: Option<u64>,
+    pub random: RandomKind,
+    pub tone: ToneConfig,
+    // WAV file to write the buzzer output to
+    pub audio_out: Option<String>,
+    // Run without window nor audio device
+    pub headless: bool,
+    // Stop after this many frames
+    pub frames: Option<u64>,
+    // Reload the ROM when its file changes, and where to restart then
+    pub watch: bool,
+    pub watch_resume: Resume,
+    // Show the frame & instruction rate counter from the start
+    pub fps: bool,
+    // Show the debugger panel next to the screen
+    pub debugger: bool,
+    // Port to wait for GDB on
+    pub gdb: Option<u16>,
+    // File to log the instructions run to, which ones and how
+    pub trace: Option<String>,
+    pub trace_format: TraceFormat,
+    pub trace_filter: TraceFilter,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            rom: None,
+            rom_dir: String::from("roms"),
+            format: RomFormat::Auto,
+            platform: None,
+            quirks: Vec::new(),
+            tickrate: None,
+            load_address: START_ALLOWED_ADDRESS,
+            record: None,
+            replay: None,
+            seed: None,
+            random: RandomKind::Seeded,
+            tone: ToneConfig::default(),
+            audio_out: None,
+            headless: false,
+            frames: None,
+            watch: false,
+            watch_resume: Resume::Start,
+            fps: false,
+            debugger: false,
+            gdb: None,
+            trace: None,
+            trace_format: TraceFormat::Text,
+            trace_filter: TraceFilter::default(),
+        }
+    }
+}
+
+impl Options {
+    /// Parses the arguments following the program name. The first non-flag argument is the rom.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+            match arg.as_str() {
+                // FX0A registers keys on press instead of release
+                "--key-press" => options.quirks.push(("key_wait_release".into(), false)),
+                // FX0A beeps while a key is held
+                "--key-beep" => options.quirks.push(("key_wait_beep".into(), true)),
+                // Any quirk, as name=0|1
+                "--quirk" => options.quirks.push(Quirks::parse_pair(&value()?)?),
+                "--platform" => options.platform = Some(value()?.parse()?),
+                // At least one instruction per frame, for the game to go anywhere
+                "--tickrate" => match parse_number(&value()?)? {
+                    0 => return Err(String::from("The tickrate must be at least 1")),
+                    tickrate => options.tickrate = Some(tickrate),
+                },
+                // 0x600 for ETI 660 ROMs
+                "--load-address" => options.load_address = parse_address(&value()?)?,
+                // Record key presses into a movie file
+                "--record" => options.record = Some(value()?),
+                // Replay a movie file instead of reading the keyboard
+                "--replay" => options.replay = Some(value()?),
+                // Seed of the random source, to get the same random numbers on every run
+                "--seed" => options.seed = Some(parse_number(&value()?)?),
+                // Random source: seeded, vip or script:0a,ff,...
+                "--rng" => options.random = value()?.parse()?,
+                // Buzzer
+                "--tone" => options.tone.frequency = parse_number(&value()?)?,
+                "--volume" => options.tone.volume = parse_fraction(&value()?)?,
+                "--duty" => options.tone.duty = parse_fraction(&value()?)?,
+                "--wave" => options.tone.waveform = value()?.parse()?,
+                // Write the buzzer output to a WAV file
+                "--audio-out" => options.audio_out = Some(value()?),
+                // Run without window nor sound, e.g. to replay a movie into a WAV file
+                "--headless" => options.headless = true,
+                // Stop after this many frames
+                "--frames" => options.frames = Some(parse_number(&value()?)?),
+                // Where to look for roms given by name, and listed by the launcher
+                "--rom-dir" => options.rom_dir = value()?,
+                // Read the ROM as a hexadecimal text dump, whatever its extension
+                "--format" => options.format = value()?.parse()?,
+                "--fps" => options.fps = true,
+                "--debugger" => options.debugger = true,
+                // Debug the ROM from GDB or another client of its remote protocol
+                "--gdb" => options.gdb = Some(parse_number(&value()?)?),
+                // Log every instruction run with the machine state, to compare with other emulators
+                "--trace" => options.trace = Some(value()?),
+                "--trace-format" => options.trace_format = value()?.parse()?,
+                // Only log the instructions in the address range, of the opcode classes, or from the frame
+                "--trace-range" => options.trace_filter.range = Some(parse_range(&value()?)?),
+                "--trace-opcodes" => {
+                    options.trace_filter.classes = Some(TraceFilter::parse_classes(&value()?)?)
+                }
+                "--trace-after" => options.trace_filter.after_frame = parse_number(&value()?)?,
+                // Reload the ROM on every build
+                "--watch" => options.watch = true,
+                // After reloading, restart from the quick save or from an instruction address
+                "--watch-resume" => {
+                    options.watch_resume = match value()?.as_str() {
+                        "start" => Resume::Start,
+                        "state" => Resume::SavedState,
+                        address => Resume::Breakpoint(parse_address(address)?),
+                    }
+                }
+                _ if arg.starts_with("--") => return Err(format!("Unknown option: {}", arg)),
+                _ => options.rom = Some(arg),
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid number: {}", value))
+}
+
+/// Parses a number from 0 to 1
+fn parse_fraction(value: &str) -> Result<f32, String> {
+    let fraction = parse_number(value)?;
+    match (0.0..=1.0).contains(&fraction) {
+        true => Ok(fraction),
+        false => Err(format!("Out of range: {} (0 to 1)", value)),
+    }
+}
+
+/// Parses a decimal or 0x-prefixed hexadecimal address
+pub fn parse_address(value: &str) -> Result<usize, String> {
+    match value.strip_prefix("0x") {
+        Some(hex) => {
+            usize::from_str_radix(hex, 16).map_err(|_| format!("Invalid address: {}", value))
+        }
+        None => parse_number(value),
+    }
+}
+
+/// Parses an inclusive address range, `START-END`
+fn parse_range(value: &str) -> Result<RangeInclusive<usize>, String> {
+    let (start, end) = value
+        .split_once('-')
+        .ok_or_else(|| format!("Invalid address range: {}", value))?;
+    Ok(parse_address(start)?..=parse_address(end)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::speaker::Waveform;
+
+    fn parse(args: &str) -> Result<Options, String> {
+        Options::parse(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn parses_rom_and_flags() {
+        let options =
+            parse("--seed 7 PONG --wave sine --volume 0.5 --key-press --load-address 0x600")
+                .unwrap();
+        assert_eq!(options.load_address, 0x600);
+        assert_eq!(options.rom.as_deref(), Some("PONG"));
+        assert_eq!(options.seed, Some(7));
+        assert_eq!(options.tone.waveform, Waveform::Sine);
+        assert_eq!(options.tone.volume, 0.5);
+        assert!(!Quirks::default().with(&options.quirks).key_wait_release);
+        assert_eq!(parse("").unwrap(), Options::default());
+
+        let options = parse("--watch --watch-resume 0x2a4 --gdb 1234").unwrap();
+        assert!(options.watch);
+        assert_eq!(options.gdb, Some(1234));
+        assert_eq!(options.watch_resume, Resume::Breakpoint(0x2a4));
+
+        let options = parse("--trace out.log --trace-range 0x200-0x2ff --trace-after 60").unwrap();
+        assert_eq!(options.trace_filter.range, Some(0x200..=0x2ff));
+        assert_eq!(options.trace_filter.after_frame, 60);
+
+        let options = parse("--format hex dump.txt").unwrap();
+        assert_eq!(options.format, RomFormat::Hex);
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(parse("--seed").is_err());
+        assert!(parse("--seed abc").is_err());
+        assert!(parse("--wave saw").is_err());
+        assert!(parse("--bogus").is_err());
+        assert!(parse("--quirk clip").is_err());
+        assert!(parse("--platform c64").is_err());
+        assert!(parse("--gdb 70000").is_err());
+        assert!(parse("--trace-range 0x200").is_err());
+        assert!(parse("--format zip").is_err());
+        assert!(parse("--volume 5").is_err());
+        assert!(parse("--duty -1").is_err());
+        assert!(parse("--duty NaN").is_err());
+        assert!(parse("--tickrate 0").is_err());
+    }
+}
@@ -0,0 +1,139 @@
+// Text drawn over the game: transient messages, the frame & instruction rate counter and game info.
+
+use std::time::{Duration, Instant};
+
+// How long messages stay on screen
+const MESSAGE_TIME: Duration = Duration::from_secs(2);
+// Game info shown when a game starts stays a bit longer
+const INFO_TIME: Duration = Duration::from_secs(5);
+// Messages shown at once, the older ones go first
+const MAX_MESSAGES: usize = 4;
+// Time over which the rates are measured
+const COUNTER_PERIOD: Duration = Duration::from_secs(1);
+
+/// Frames and instructions run per second, measured over COUNTER_PERIOD
+struct Counter {
+    start: Instant,
+    // Frames and instructions run when the period started
+    frames: u64,
+    instructions: u64,
+    // Rates of the last complete period
+    fps: f64,
+    ips: f64,
+}
+
+#[derive(Default)]
+pub struct Overlay {
+    // Text and when it goes away
+    messages: Vec<(String, Instant)>,
+    counter: Option<Counter>,
+}
+
+impl Overlay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn message(&mut self, text: String) {
+        self.show(text, MESSAGE_TIME, Instant::now());
+    }
+
+    /// Shows what the game is and how it runs, e.g. when it starts
+    pub fn info(&mut self, lines: Vec<String>) {
+        let now = Instant::now();
+        for line in lines {
+            self.show(line, INFO_TIME, now);
+        }
+    }
+
+    fn show(&mut self, text: String, duration: Duration, now: Instant) {
+        self.messages.push((text, now + duration));
+        if self.messages.len() > MAX_MESSAGES {
+            self.messages.remove(0);
+        }
+    }
+
+    /// Shows or hides the FPS/IPS counter, returns whether it is shown.
+    /// Takes the frames and instructions run so far, like `count`.
+    pub fn toggle_counter(&mut self, frames: u64, instructions: u64) -> bool {
+        self.counter = match self.counter {
+            Some(_) => None,
+            None => Some(Counter {
+                start: Instant::now(),
+                frames,
+                instructions,
+                fps: 0.0,
+                ips: 0.0,
+            }),
+        };
+        self.counter.is_some()
+    }
+
+    /// Feeds the counter with the totals run so far, called on every screen refresh
+    pub fn count(&mut self, frames: u64, instructions: u64) {
+        self.count_at(frames, instructions, Instant::now());
+    }
+
+    fn count_at(&mut self, frames: u64, instructions: u64, now: Instant) {
+        let Some(counter) = &mut self.counter else {
+            return;
+        };
+        let elapsed = now.duration_since(counter.start);
+        if elapsed < COUNTER_PERIOD {
+            return;
+        }
+        let seconds = elapsed.as_secs_f64();
+        counter.fps = (frames - counter.frames) as f64 / seconds;
+        counter.ips = (instructions - counter.instructions) as f64 / seconds;
+        (counter.start, counter.frames, counter.instructions) = (now, frames, instructions);
+    }
+
+    /// Lines to draw, the counter first
+    pub fn lines(&mut self) -> Vec<String> {
+        self.lines_at(Instant::now())
+    }
+
+    fn lines_at(&mut self, now: Instant) -> Vec<String> {
+        self.messages.retain(|&(_, end)| end > now);
+        let counter = self
+            .counter
+            .as_ref()
+            .map(|counter| format!("{:.0} FPS {:.0} IPS", counter.fps, counter.ips));
+        counter
+            .into_iter()
+            .chain(self.messages.iter().map(|(text, _)| text.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_expire() {
+        let mut overlay = Overlay::new();
+        let now = Instant::now();
+        overlay.show(String::from("State saved"), MESSAGE_TIME, now);
+        overlay.show(String::from("Brix"), INFO_TIME, now);
+        assert_eq!(overlay.lines_at(now), ["State saved", "Brix"]);
+        assert_eq!(overlay.lines_at(now + MESSAGE_TIME), ["Brix"]);
+        assert!(overlay.lines_at(now + INFO_TIME).is_empty());
+
+        for i in 0..6 {
+            overlay.show(i.to_string(), MESSAGE_TIME, now);
+        }
+        assert_eq!(overlay.lines_at(now), ["2", "3", "4", "5"]);
+    }
+
+    #[test]
+    fn counts_rates() {
+        let mut overlay = Overlay::new();
+        assert!(overlay.toggle_counter(0, 0));
+        let start = overlay.counter.as_ref().unwrap().start;
+        overlay.count_at(30, 450, start + Duration::from_millis(500));
+        overlay.count_at(120, 1800, start + Duration::from_secs(2));
+        assert_eq!(overlay.lines_at(start), ["60 FPS 900 IPS"]);
+        assert!(!overlay.toggle_counter(120, 1800));
+    }
+}
@@ -0,0 +1,114 @@
+// Machines and interpreters CHIP-8 programs were written for, each with its own quirks.
+// Identifiers and quirks follow the CHIP-8 database: https://github.com/chip-8/chip-8-database
+
+use crate::quirks::Quirks;
+use std::{fmt, str::FromStr};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Platform {
+    // COSMAC VIP interpreter
+    OriginalChip8,
+    // COSMAC VIP programs mixing CHIP-8 and machine code
+    HybridVip,
+    // What most modern interpreters do
+    ModernChip8,
+    // HP48 calculators
+    Chip48,
+    SuperChip1,
+    SuperChip,
+    MegaChip8,
+    XoChip,
+}
+
+const PLATFORMS: [(Platform, &str, &str); 8] = [
+    (
+        Platform::OriginalChip8,
+        "originalChip8",
+        "CHIP-8 (COSMAC VIP)",
+    ),
+    (
+        Platform::HybridVip,
+        "hybridVIP",
+        "CHIP-8 hybrid (COSMAC VIP)",
+    ),
+    (Platform::ModernChip8, "modernChip8", "CHIP-8 (modern)"),
+    (Platform::Chip48, "chip48", "CHIP-48 (HP48)"),
+    (Platform::SuperChip1, "superchip1", "SUPER-CHIP 1.0"),
+    (Platform::SuperChip, "superchip", "SUPER-CHIP 1.1"),
+    (Platform::MegaChip8, "megachip8", "MEGA-CHIP"),
+    (Platform::XoChip, "xochip", "XO-CHIP"),
+];
+
+impl Platform {
+    /// Human readable name
+    pub fn name(self) -> &'static str {
+        PLATFORMS.iter().find(|(p, _, _)| *p == self).unwrap().2
+    }
+
+    /// Bytes of memory, program included
+    pub fn memory_size(self) -> usize {
+        match self {
+            Platform::XoChip => 0x10000,
+            _ => 0x1000,
+        }
+    }
+
+    /// Quirks of the platform, FX0A quirks keep their default
+    pub fn quirks(self) -> Quirks {
+        let vip = Quirks {
+            vf_reset: true,
+            memory_increment: true,
+            shift_vy: true,
+            clip: true,
+            ..Quirks::default()
+        };
+        match self {
+            Platform::OriginalChip8 | Platform::HybridVip => vip,
+            Platform::ModernChip8 => Quirks {
+                vf_reset: false,
+                ..vip
+            },
+            Platform::Chip48 => Quirks {
+                memory_increment_by_x: true,
+                jump_vx: true,
+                clip: true,
+                ..Quirks::default()
+            },
+            Platform::SuperChip1 => Quirks {
+                memory_increment_by_x: true,
+                jump_vx: true,
+                clip: true,
+                ..Quirks::default()
+            },
+            Platform::SuperChip | Platform::MegaChip8 => Quirks {
+                jump_vx: true,
+                clip: true,
+                ..Quirks::default()
+            },
+            Platform::XoChip => Quirks {
+                memory_increment: true,
+                shift_vy: true,
+                ..Quirks::default()
+            },
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let id = PLATFORMS.iter().find(|(p, _, _)| p == self).unwrap().1;
+        write!(f, "{}", id)
+    }
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PLATFORMS
+            .iter()
+            .find(|(_, id, _)| *id == s)
+            .map(|(platform, _, _)| *platform)
+            .ok_or_else(|| format!("Unknown platform: {}", s))
+    }
+}
@@ -0,0 +1,141 @@
+// Behaviours that differ between CHIP-8 interpreters.
+// Reference: https://github.com/Timendus/chip8-test-suite#quirks-test
+
+use std::{fmt, str::FromStr};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quirks {
+    // FX0A registers the key once it is released (COSMAC VIP), instead of as soon as it is pressed
+    pub key_wait_release: bool,
+    // FX0A beeps while the pressed key is held down, like the COSMAC VIP
+    pub key_wait_beep: bool,
+    // 8XY1, 8XY2 and 8XY3 reset VF to 0 (COSMAC VIP)
+    pub vf_reset: bool,
+    // FX55 and FX65 leave I pointing after the last register (I += X + 1, COSMAC VIP)
+    pub memory_increment: bool,
+    // FX55 and FX65 increment I by X only (CHIP-48), takes precedence over memory_increment
+    pub memory_increment_by_x: bool,
+    // 8XY6 and 8XYE shift VY into VX (COSMAC VIP), instead of shifting VX in place
+    pub shift_vy: bool,
+    // BXNN jumps to XNN + VX (CHIP-48, SUPER-CHIP), instead of NNN + V0
+    pub jump_vx: bool,
+    // Sprites are cut at the edges of the screen instead of wrapping around
+    pub clip: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks {
+            key_wait_release: true,
+            key_wait_beep: false,
+            vf_reset: false,
+            memory_increment: false,
+            memory_increment_by_x: false,
+            shift_vy: false,
+            jump_vx: false,
+            clip: false,
+        }
+    }
+}
+
+impl Quirks {
+    /// Sets a quirk by name
+    pub fn set(&mut self, name: &str, value: bool) -> Result<(), String> {
+        let quirk = match name {
+            "key_wait_release" => &mut self.key_wait_release,
+            "key_wait_beep" => &mut self.key_wait_beep,
+            "vf_reset" => &mut self.vf_reset,
+            "memory_increment" => &mut self.memory_increment,
+            "memory_increment_by_x" => &mut self.memory_increment_by_x,
+            "shift_vy" => &mut self.shift_vy,
+            "jump_vx" => &mut self.jump_vx,
+            "clip" => &mut self.clip,
+            _ => return Err(format!("Unknown quirk: {}", name)),
+        };
+        *quirk = value;
+        Ok(())
+    }
+
+    /// Parses a `name=0|1` pair
+    pub fn parse_pair(pair: &str) -> Result<(String, bool), String> {
+        let (name, value) = pair
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid quirk: {}", pair))?;
+        let value = match value {
+            "0" => false,
+            "1" => true,
+            _ => return Err(format!("Invalid value for quirk {}: {}", name, value)),
+        };
+        // Check the name
+        Quirks::default().set(name, value)?;
+        Ok((name.to_string(), value))
+    }
+
+    /// Names of the quirks that are on, separated by spaces
+    pub fn enabled(&self) -> String {
+        let pairs = self.to_string();
+        let names: Vec<&str> = pairs
+            .split(' ')
+            .filter_map(|pair| pair.strip_suffix("=1"))
+            .collect();
+        names.join(" ")
+    }
+
+    /// Returns these quirks with the given ones changed
+    pub fn with(mut self, changes: &[(String, bool)]) -> Self {
+        for (name, value) in changes {
+            // Names are checked when parsing
+            let _ = self.set(name, *value);
+        }
+        self
+    }
+}
+
+// Quirks are written as space-separated `name=0|1` pairs, e.g. in movie file headers
+impl fmt::Display for Quirks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "key_wait_release={} key_wait_beep={} vf_reset={} memory_increment={} memory_increment_by_x={} shift_vy={} jump_vx={} clip={}",
+            self.key_wait_release as u8,
+            self.key_wait_beep as u8,
+            self.vf_reset as u8,
+            self.memory_increment as u8,
+            self.memory_increment_by_x as u8,
+            self.shift_vy as u8,
+            self.jump_vx as u8,
+            self.clip as u8
+        )
+    }
+}
+
+impl FromStr for Quirks {
+    type Err = String;
+
+    /// Missing quirks keep their default value
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let changes = s
+            .split_whitespace()
+            .map(Quirks::parse_pair)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Quirks::default().with(&changes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_what_it_writes() {
+        let quirks = Quirks {
+            vf_reset: true,
+            clip: true,
+            ..Quirks::default()
+        };
+        assert_eq!(quirks.to_string().parse::<Quirks>().unwrap(), quirks);
+        assert_eq!("clip=1 vf_reset=1".parse::<Quirks>().unwrap(), quirks);
+        assert!("clip=2".parse::<Quirks>().is_err());
+        assert!("wobble=1".parse::<Quirks>().is_err());
+    }
+}
@@ -0,0 +1,251 @@
+// Random number sources for CXKK.
+// Every source keeps its whole state in a u64, so it can be stored in save states.
+
+use std::{fmt, str::FromStr};
+
+pub trait RandomSource {
+    fn next_byte(&mut self) -> u8;
+    fn state(&self) -> u64;
+    fn set_state(&mut self, state: u64);
+}
+
+/// SplitMix64, a small PRNG whose whole state is a single u64.
+/// Reference: https://prng.di.unimi.it/splitmix64.c
+pub struct SeededRandom {
+    state: u64,
+}
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> Self {
+        SeededRandom { state: seed }
+    }
+}
+
+impl RandomSource for SeededRandom {
+    fn next_byte(&mut self) -> u8 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        ((z ^ (z >> 31)) >> 56) as u8
+    }
+
+    fn state(&self) -> u64 {
+        self.state
+    }
+
+    fn set_state(&mut self, state: u64) {
+        self.state = state;
+    }
+}
+
+/// The COSMAC VIP interpreter's own CXKK routine.
+/// It increments R9, adds the byte of the interpreter's second page (0x0100-0x01FF) at R9.0 to R9.1,
+/// then adds that sum, shifted right through the carry, to itself. The result goes to R9.1 and,
+/// masked with kk, to Vx.
+/// Reference: the interpreter listing in the RCA COSMAC VIP manual, CXKK at 0x01D9
+pub struct VipRandom {
+    r9: u16,
+}
+
+// Second page of the VIP interpreter, which the routine reads as a table
+#[rustfmt::skip]
+const VIP_PAGE: [u8; 256] = [
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x45, 0xa3, 0x98, 0x56, 0xd4, 0xf8, 0x81, 0xbc, 0xf8, 0x95, 0xac,
+    0x22, 0xdc, 0x12, 0x56, 0xd4, 0x06, 0xb8, 0xd4, 0x06, 0xa8, 0xd4, 0x64, 0x0a, 0x01, 0xe6, 0x8a,
+    0xf4, 0xaa, 0x3b, 0x28, 0x9a, 0xfc, 0x01, 0xba, 0xd4, 0xf8, 0x81, 0xba, 0x06, 0xfa, 0x0f, 0xaa,
+    0x0a, 0xaa, 0xd4, 0xe6, 0x06, 0xbf, 0x93, 0xbe, 0xf8, 0x1b, 0xae, 0x2a, 0x1a, 0xf8, 0x00, 0x5a,
+    0x0e, 0xf5, 0x3b, 0x4b, 0x56, 0x0a, 0xfc, 0x01, 0x5a, 0x30, 0x40, 0x4e, 0xf6, 0x3b, 0x3c, 0x9f,
+    0x56, 0x2a, 0x2a, 0xd4, 0x00, 0x22, 0x86, 0x52, 0xf8, 0xf0, 0xa7, 0x07, 0x5a, 0x87, 0xf3, 0x17,
+    0x1a, 0x3a, 0x5b, 0x12, 0xd4, 0x22, 0x86, 0x52, 0xf8, 0xf0, 0xa7, 0x0a, 0x57, 0x87, 0xf3, 0x17,
+    0x1a, 0x3a, 0x6b, 0x12, 0xd4, 0x15, 0x85, 0x22, 0x73, 0x95, 0x52, 0x25, 0x45, 0xa5, 0x86, 0xfa,
+    0x0f, 0xb5, 0xd4, 0x45, 0xe6, 0xf3, 0x3a, 0x82, 0x15, 0x15, 0xd4, 0x45, 0xe6, 0xf3, 0x3a, 0x88,
+    0xd4, 0x45, 0x07, 0x30, 0x8c, 0x45, 0x07, 0x30, 0x84, 0xe6, 0x62, 0x26, 0x45, 0xa3, 0x36, 0x88,
+    0xd4, 0x3e, 0x88, 0xd4, 0xf8, 0xf0, 0xa7, 0xe7, 0x45, 0xf4, 0xa5, 0x86, 0xfa, 0x0f, 0x3b, 0xb2,
+    0xfc, 0x01, 0xb5, 0xd4, 0x45, 0x56, 0xd4, 0x45, 0xe6, 0xf4, 0x56, 0xd4, 0x45, 0xfa, 0x0f, 0x3a,
+    0xc4, 0x07, 0x56, 0xd4, 0xaf, 0x22, 0xf8, 0xd3, 0x73, 0x8f, 0xf9, 0xf0, 0x52, 0xe6, 0x07, 0xd2,
+    0x56, 0xf8, 0xff, 0xa6, 0xf8, 0x00, 0x7e, 0x56, 0xd4, 0x19, 0x89, 0xae, 0x93, 0xbe, 0x99, 0xee,
+    0xf4, 0x56, 0x76, 0xe6, 0xf4, 0xb9, 0x56, 0x45, 0xf2, 0x56, 0xd4, 0x45, 0xaa, 0x86, 0xfa, 0x0f,
+    0xba, 0xd4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+impl VipRandom {
+    pub fn new(seed: u64) -> Self {
+        VipRandom { r9: seed as u16 }
+    }
+}
+
+impl RandomSource for VipRandom {
+    fn next_byte(&mut self) -> u8 {
+        self.r9 = self.r9.wrapping_add(1);
+        let [high, low] = self.r9.to_be_bytes();
+        let (sum, carry) = high.overflowing_add(VIP_PAGE[low as usize]);
+        let shifted = sum >> 1 | (carry as u8) << 7;
+        let value = sum.wrapping_add(shifted);
+        self.r9 = u16::from_be_bytes([value, low]);
+        value
+    }
+
+    fn state(&self) -> u64 {
+        self.r9 as u64
+    }
+
+    fn set_state(&mut self, state: u64) {
+        self.r9 = state as u16;
+    }
+}
+
+/// Plays back a fixed sequence of bytes, looping at the end. Mostly useful for tests.
+pub struct ScriptedRandom {
+    bytes: Vec<u8>,
+    position: usize,
+}
+
+impl ScriptedRandom {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        ScriptedRandom { bytes, position: 0 }
+    }
+}
+
+impl RandomSource for ScriptedRandom {
+    fn next_byte(&mut self) -> u8 {
+        if self.bytes.is_empty() {
+            return 0;
+        }
+        let byte = self.bytes[self.position % self.bytes.len()];
+        self.position = (self.position + 1) % self.bytes.len();
+        byte
+    }
+
+    fn state(&self) -> u64 {
+        self.position as u64
+    }
+
+    fn set_state(&mut self, state: u64) {
+        self.position = state as usize;
+    }
+}
+
+/// Which random source to use, as given on the command line (`seeded`, `vip` or `script:0a,ff,...`)
+#[derive(Clone, Debug, PartialEq)]
+pub enum RandomKind {
+    Seeded,
+    Vip,
+    Scripted(Vec<u8>),
+}
+
+impl RandomKind {
+    pub fn build(&self, seed: u64) -> Box<dyn RandomSource> {
+        match self {
+            RandomKind::Seeded => Box::new(SeededRandom::new(seed)),
+            RandomKind::Vip => Box::new(VipRandom::new(seed)),
+            RandomKind::Scripted(bytes) => Box::new(ScriptedRandom::new(bytes.clone())),
+        }
+    }
+}
+
+/// Returns a random seed, for runs that don't need to be reproduced
+pub fn random_seed() -> u64 {
+    rand::random()
+}
+
+impl fmt::Display for RandomKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RandomKind::Seeded => write!(f, "seeded"),
+            RandomKind::Vip => write!(f, "vip"),
+            RandomKind::Scripted(bytes) => {
+                let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                write!(f, "script:{}", bytes.join(","))
+            }
+        }
+    }
+}
+
+impl FromStr for RandomKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "seeded" => Ok(RandomKind::Seeded),
+            "vip" => Ok(RandomKind::Vip),
+            _ => {
+                let script = s
+                    .strip_prefix("script:")
+                    .ok_or_else(|| format!("Unknown random source: {}", s))?;
+                script
+                    .split(',')
+                    .map(|b| u8::from_str_radix(b.trim(), 16))
+                    .collect::<Result<Vec<u8>, _>>()
+                    .map(RandomKind::Scripted)
+                    .map_err(|_| format!("Invalid random script: {}", script))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn take(source: &mut dyn RandomSource, count: usize) -> Vec<u8> {
+        (0..count).map(|_| source.next_byte()).collect()
+    }
+
+    #[test]
+    fn same_seed_same_sequence() {
+        for kind in [RandomKind::Seeded, RandomKind::Vip] {
+            let mut a = kind.build(1234);
+            let mut b = kind.build(1234);
+            assert_eq!(take(a.as_mut(), 32), take(b.as_mut(), 32));
+            assert_ne!(take(a.as_mut(), 32), take(kind.build(1235).as_mut(), 32));
+        }
+    }
+
+    #[test]
+    fn restoring_state_repeats_sequence() {
+        let kinds = [
+            RandomKind::Seeded,
+            RandomKind::Vip,
+            RandomKind::Scripted(vec![1, 2, 3]),
+        ];
+        for kind in kinds {
+            let mut source = kind.build(99);
+            take(source.as_mut(), 5);
+            let state = source.state();
+            let expected = take(source.as_mut(), 8);
+            source.set_state(state);
+            assert_eq!(take(source.as_mut(), 8), expected);
+        }
+    }
+
+    #[test]
+    fn script_loops() {
+        let kind: RandomKind = "script:0a,FF,3".parse().unwrap();
+        assert_eq!(kind, RandomKind::Scripted(vec![0x0a, 0xff, 0x03]));
+        assert_eq!(kind.to_string(), "script:0a,ff,03");
+        assert_eq!(
+            take(kind.build(0).as_mut(), 4),
+            vec![0x0a, 0xff, 0x03, 0x0a]
+        );
+        assert!("script:zz".parse::<RandomKind>().is_err());
+    }
+
+    #[test]
+    fn vip_walks_the_interpreter_page() {
+        let kind: RandomKind = "vip".parse().unwrap();
+        assert_eq!(kind.to_string(), "vip");
+        // From R9 = 0: the page starts with 5 zero bytes, then 0x45 gives 0x45 + 0x22, and 0xA3
+        // overflows 0x67 into 0x0A, shifted in with its carry
+        let expected = [0x00, 0x00, 0x00, 0x00, 0x67, 0x8f, 0xba, 0x98, 0x22, 0xa7, 0xbc, 0x34];
+        assert_eq!(take(kind.build(0).as_mut(), 12), expected);
+        // R9.0 crosses into R9.1 like a 16-bit register
+        let mut source = kind.build(0x00fe);
+        assert_eq!(take(source.as_mut(), 3), [0x00, 0x01, 0x01]);
+        assert_eq!(source.state(), 0x0101);
+        let mut source = VipRandom::new(0x1234);
+        let expected = [0x39, 0x32, 0x68, 0x10, 0x40, 0x65, 0xd6, 0x68];
+        assert_eq!(take(&mut source, 8), expected);
+        assert_eq!(source.state(), 0x683c);
+    }
+}
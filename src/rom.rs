@@ -0,0 +1,413 @@
+// Reading ROM files and checking they fit in memory.
+// Besides raw binaries, ROMs can come as ZIP archives holding a single ROM, Octo cartridges (GIF images),
+// Octo source files and hexadecimal text dumps. Archives and cartridges are recognized by their magic bytes,
+// sources and text dumps by their extension or `--format`, as text can't be told from a binary that only
+// holds such bytes.
+
+use crate::{
+    chip8::MEMORY_SIZE,
+    metadata::RomInfo,
+    octo::{self, Cartridge},
+    platform::Platform,
+};
+use std::{fmt, fs, io, path::Path, str::FromStr};
+
+// Largest file taken out of an archive, far more than any CHIP-8 platform can load
+const MAX_UNPACKED_SIZE: usize = 1 << 20;
+// Extensions of the files kept when an archive holds several
+const ROM_EXTENSIONS: [&str; 8] = ["ch8", "c8", "sc8", "xo8", "c8h", "hex", "8o", "rom"];
+// Extensions of hexadecimal text dumps
+const HEX_EXTENSIONS: [&str; 2] = ["c8h", "hex"];
+
+/// How to read ROM files, given with --format when their name doesn't say
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RomFormat {
+    // By the magic bytes, then the extension, else as a binary
+    #[default]
+    Auto,
+    // Hexadecimal text dump, whatever the extension
+    Hex,
+}
+
+impl FromStr for RomFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(RomFormat::Auto),
+            "hex" => Ok(RomFormat::Hex),
+            _ => Err(format!("Unknown ROM format: {} (auto or hex)", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RomError {
+    Io(io::Error),
+    // Unreadable archive, cartridge or text dump
+    Format(String),
+    Empty,
+    // The load address is outside of the memory available to programs
+    InvalidLoadAddress(usize),
+    TooLarge {
+        size: usize,
+        // Room left in memory from the load address
+        max: usize,
+        // Platform the ROM seems to be made for
+        platform: Option<Platform>,
+    },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::Io(e) => write!(f, "could not read the ROM: {}", e),
+            RomError::Format(e) => write!(f, "{}", e),
+            RomError::Empty => write!(f, "the ROM is empty"),
+            RomError::InvalidLoadAddress(address) => {
+                write!(f, "cannot load a ROM at {:#05x}", address)
+            }
+            RomError::TooLarge {
+                size,
+                max,
+                platform,
+            } => {
+                write!(
+                    f,
+                    "the ROM is too large: {} bytes, only {} fit in memory",
+                    size, max
+                )?;
+                match platform {
+                    Some(platform) if platform.memory_size() > MEMORY_SIZE => write!(
+                        f,
+                        " (it looks like a {} ROM, whose larger memory is not emulated)",
+                        platform.name()
+                    ),
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl From<io::Error> for RomError {
+    fn from(e: io::Error) -> Self {
+        RomError::Io(e)
+    }
+}
+
+/// A program and what the file it came from says about it
+pub struct RomFile {
+    pub program: Vec<u8>,
+    // Settings stored with the program, as Octo cartridges do
+    pub info: Option<RomInfo>,
+}
+
+impl RomFile {
+    fn binary(program: Vec<u8>) -> Self {
+        RomFile {
+            program,
+            info: None,
+        }
+    }
+}
+
+pub fn read(path: &Path) -> Result<RomFile, RomError> {
+    read_as(path, RomFormat::Auto)
+}
+
+pub fn read_as(path: &Path, format: RomFormat) -> Result<RomFile, RomError> {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    decode(&name, fs::read(path)?, format, true)
+}
+
+/// Turns the file content into a program, the name telling Octo sources and text dumps apart
+fn decode(name: &str, data: Vec<u8>, format: RomFormat, unpack: bool) -> Result<RomFile, RomError> {
+    if unpack && data.starts_with(b"PK\x03\x04") {
+        let (name, data) =
+            unzip(&data).map_err(|e| RomError::Format(format!("invalid ZIP archive: {}", e)))?;
+        return decode(&name, data, format, false);
+    }
+    if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        let cartridge = octo::read_cartridge(&data).map_err(RomError::Format)?;
+        return from_cartridge(name, cartridge);
+    }
+    if has_extension(name, "8o") {
+        let source = String::from_utf8_lossy(&data);
+        return Ok(RomFile::binary(assemble(name, &source)?));
+    }
+    if format == RomFormat::Hex || HEX_EXTENSIONS.iter().any(|e| has_extension(name, e)) {
+        return parse_hex(&data).map(RomFile::binary);
+    }
+    Ok(RomFile::binary(data))
+}
+
+fn has_extension(name: &str, extension: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case(extension))
+}
+
+fn assemble(name: &str, source: &str) -> Result<Vec<u8>, RomError> {
+    octo::assemble(source)
+        .map_err(|e| RomError::Format(format!("could not assemble {}: {}", name, e)))
+}
+
+fn from_cartridge(name: &str, cartridge: Cartridge) -> Result<RomFile, RomError> {
+    let program = assemble(name, &cartridge.source)?;
+    let title = Path::new(name)
+        .file_stem()
+        .map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+    let info = RomInfo {
+        quirks: Some(cartridge.quirks),
+        tickrate: cartridge.tickrate,
+        colors: cartridge.colors,
+        ..RomInfo::unknown(&title)
+    };
+    Ok(RomFile {
+        program,
+        info: Some(info),
+    })
+}
+
+/// Hexadecimal bytes separated by spaces or commas, possibly prefixed with 0x
+fn parse_hex(data: &[u8]) -> Result<Vec<u8>, RomError> {
+    let text = String::from_utf8_lossy(data);
+    let mut program = Vec::new();
+    for token in text.split(|c: char| c.is_whitespace() || c == ',') {
+        let digits = token.strip_prefix("0x").unwrap_or(token);
+        if digits.len() % 2 != 0 || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(RomError::Format(format!(
+                "invalid hexadecimal byte: {}",
+                token
+            )));
+        }
+        for i in (0..digits.len()).step_by(2) {
+            program.push(u8::from_str_radix(&digits[i..i + 2], 16).unwrap());
+        }
+    }
+    Ok(program)
+}
+
+/// Extracts the only ROM of a ZIP archive, with its name
+fn unzip(zip: &[u8]) -> Result<(String, Vec<u8>), String> {
+    let u16_at = |at: usize| {
+        zip.get(at..at + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]) as usize)
+            .ok_or_else(|| String::from("truncated"))
+    };
+    let u32_at = |at: usize| {
+        zip.get(at..at + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+            .ok_or_else(|| String::from("truncated"))
+    };
+
+    // The end of central directory record closes the archive, before an optional comment
+    let end = (0..zip.len().saturating_sub(21))
+        .rev()
+        .find(|&at| zip[at..].starts_with(b"PK\x05\x06"))
+        .ok_or("no central directory")?;
+    let count = u16_at(end + 10)?;
+    let mut at = u32_at(end + 16)?;
+
+    let mut files = Vec::new();
+    for _ in 0..count {
+        if !zip
+            .get(at..)
+            .is_some_and(|entry| entry.starts_with(b"PK\x01\x02"))
+        {
+            return Err(String::from("corrupted central directory"));
+        }
+        let name_length = u16_at(at + 28)?;
+        let name = zip.get(at + 46..at + 46 + name_length).ok_or("truncated")?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        // Compression method, compressed size and where the local header is
+        let entry = (u16_at(at + 10)?, u32_at(at + 20)?, u32_at(at + 42)?);
+        if !name.ends_with('/') && !name.starts_with("__MACOSX/") {
+            files.push((name, entry));
+        }
+        at += 46 + name_length + u16_at(at + 30)? + u16_at(at + 32)?;
+    }
+    if files.len() > 1 {
+        files.retain(|(name, _)| ROM_EXTENSIONS.iter().any(|e| has_extension(name, e)));
+    }
+    if files.len() != 1 {
+        return Err(format!("expected a single ROM, found {}", files.len()));
+    }
+
+    let (name, (method, size, header)) = files.remove(0);
+    if !zip
+        .get(header..)
+        .is_some_and(|h| h.starts_with(b"PK\x03\x04"))
+    {
+        return Err(String::from("corrupted local header"));
+    }
+    let start = header + 30 + u16_at(header + 26)? + u16_at(header + 28)?;
+    let data = zip.get(start..start + size).ok_or("truncated")?;
+    let data = match method {
+        0 => data.to_vec(),
+        8 => miniz_oxide::inflate::decompress_to_vec_with_limit(data, MAX_UNPACKED_SIZE)
+            .map_err(|e| format!("could not inflate {}: {}", name, e))?,
+        _ => return Err(format!("unsupported compression method {}", method)),
+    };
+    Ok((name, data))
+}
+
+/// Guesses the platform from instructions only later platforms have, None for plain CHIP-8.
+/// Data can look like instructions too, so a platform needs several kinds of its instructions.
+pub fn detect_platform(rom: &[u8]) -> Option<Platform> {
+    let mut schip = [false; 7];
+    let mut xochip = [false; 5];
+    for opcode in rom
+        .chunks_exact(2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+    {
+        let (x, low) = ((opcode >> 8) & 0xf, opcode & 0xff);
+        match (opcode >> 12, x, low) {
+            // Scroll down, scroll right, scroll left, exit, low and high resolution
+            (0x0, 0, 0xc0..=0xcf) => schip[0] = true,
+            (0x0, 0, 0xfb | 0xfc) => schip[1] = true,
+            (0x0, 0, 0xfd) => schip[2] = true,
+            (0x0, 0, 0xfe | 0xff) => schip[3] = true,
+            // 16x16 sprites
+            (0xd, _, _) if opcode & 0xf == 0 => schip[4] = true,
+            // Large font, RPL flags
+            (0xf, _, 0x30) => schip[5] = true,
+            (0xf, _, 0x75 | 0x85) => schip[6] = true,
+            // Scroll up, register ranges, long I, planes, audio pattern & pitch
+            (0x0, 0, 0xd0..=0xdf) => xochip[0] = true,
+            (0x5, _, _) if matches!(opcode & 0xf, 2 | 3) => xochip[1] = true,
+            (0xf, 0, 0x00) => xochip[2] = true,
+            (0xf, _, 0x01) => xochip[3] = true,
+            (0xf, _, 0x02 | 0x3a) => xochip[4] = true,
+            _ => (),
+        }
+    }
+
+    let count = |kinds: &[bool]| kinds.iter().filter(|&&found| found).count();
+    if count(&xochip) >= 2 {
+        Some(Platform::XoChip)
+    } else if count(&schip) >= 3 {
+        Some(Platform::SuperChip)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_platforms() {
+        // High resolution, 16x16 sprite, large font
+        let schip = [0x00, 0xff, 0xd1, 0x20, 0xf0, 0x30, 0x12, 0x00];
+        assert_eq!(detect_platform(&schip), Some(Platform::SuperChip));
+        // Long I, plane selection
+        let xochip = [0xf0, 0x00, 0x12, 0x34, 0xf3, 0x01, 0x12, 0x00];
+        assert_eq!(detect_platform(&xochip), Some(Platform::XoChip));
+        assert_eq!(detect_platform(&fs::read("roms/BRIX").unwrap()), None);
+    }
+
+    /// A ZIP archive holding the files, deflated or stored
+    fn zip(files: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut zip = Vec::new();
+        let mut directory = Vec::new();
+        for &(name, data, deflate) in files {
+            let (method, data) = match deflate {
+                true => (8u16, miniz_oxide::deflate::compress_to_vec(data, 6)),
+                false => (0, data.to_vec()),
+            };
+            let header = zip.len() as u32;
+            // Fields we don't read are left to zero: version, flags, time, CRC and attributes
+            zip.extend_from_slice(b"PK\x03\x04");
+            zip.extend_from_slice(&[0; 4]);
+            zip.extend_from_slice(&method.to_le_bytes());
+            zip.extend_from_slice(&[0; 8]);
+            zip.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            zip.extend_from_slice(&[0; 4]);
+            zip.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            zip.extend_from_slice(&[0; 2]);
+            zip.extend_from_slice(name.as_bytes());
+            zip.extend_from_slice(&data);
+
+            directory.extend_from_slice(b"PK\x01\x02");
+            directory.extend_from_slice(&[0; 6]);
+            directory.extend_from_slice(&method.to_le_bytes());
+            directory.extend_from_slice(&[0; 8]);
+            directory.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            directory.extend_from_slice(&[0; 4]);
+            directory.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            directory.extend_from_slice(&[0; 12]);
+            directory.extend_from_slice(&header.to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+        }
+        let directory_start = zip.len() as u32;
+        zip.extend_from_slice(&directory);
+        zip.extend_from_slice(b"PK\x05\x06");
+        zip.extend_from_slice(&[0; 4]);
+        zip.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        zip.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        zip.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        zip.extend_from_slice(&directory_start.to_le_bytes());
+        zip.extend_from_slice(&[0; 2]);
+        zip
+    }
+
+    #[test]
+    fn unpacks_archives() {
+        let brix = fs::read("roms/BRIX").unwrap();
+        let archive = zip(&[
+            ("readme.txt", b"Have fun", false),
+            ("brix.ch8", &brix, true),
+        ]);
+        assert_eq!(decode("brix.zip", archive, RomFormat::Auto, true).unwrap().program, brix);
+
+        let archive = zip(&[("pong.c8h", b"6a 02 6b 0c", false)]);
+        assert_eq!(
+            decode("pong.zip", archive, RomFormat::Auto, true).unwrap().program,
+            [0x6a, 0x02, 0x6b, 0x0c]
+        );
+
+        let archive = zip(&[("a.ch8", b"12", false), ("b.ch8", b"34", false)]);
+        assert_eq!(
+            decode("two.zip", archive, RomFormat::Auto, true).err().unwrap().to_string(),
+            "invalid ZIP archive: expected a single ROM, found 2"
+        );
+    }
+
+    #[test]
+    fn reads_text_and_cartridges() {
+        let dump = b"0x00,0xE0, 0x12 0x00\n";
+        let read = |name, format| decode(name, dump.to_vec(), format, true).unwrap().program;
+        assert_eq!(read("clear.hex", RomFormat::Auto), [0, 0xe0, 0x12, 0]);
+        assert_eq!(read("clear", RomFormat::Hex), [0, 0xe0, 0x12, 0]);
+        // Binaries made of bytes that look like text stay binaries
+        assert_eq!(read("clear", RomFormat::Auto), dump);
+        assert!(decode("bad.c8h", b"00 e".to_vec(), RomFormat::Auto, true).is_err());
+        assert!("bin".parse::<RomFormat>().is_err());
+
+        let payload = r#"{"program": ": main clear jump main", "options": {"tickrate": 7}}"#;
+        let file = decode("demo.gif", octo::write_cartridge(payload), RomFormat::Auto, true).unwrap();
+        assert_eq!(file.program, [0x00, 0xe0, 0x12, 0x00]);
+        let info = file.info.unwrap();
+        assert_eq!((info.title.as_str(), info.tickrate), ("demo", Some(7)));
+    }
+
+    #[test]
+    fn explains_errors() {
+        let error = RomError::TooLarge {
+            size: 5000,
+            max: 3584,
+            platform: Some(Platform::XoChip),
+        };
+        assert_eq!(
+            error.to_string(),
+            "the ROM is too large: 5000 bytes, only 3584 fit in memory (it looks like a XO-CHIP ROM, whose larger memory is not emulated)"
+        );
+        assert_eq!(RomError::Empty.to_string(), "the ROM is empty");
+    }
+}
@@ -0,0 +1,140 @@
+use crate::{
+    chip8::Chip8,
+    debugger::Breakpoints,
+    movie::{Movie, MovieRecorder},
+    speaker::Speaker,
+    sprites::SpriteRecorder,
+    trace::Tracer,
+};
+use std::io;
+
+// Instructions per frame for ROMs the database doesn't know
+pub const CYCLES_PER_FRAME: u32 = 10; // Sweet spot?
+
+/// A running game: the machine with its speaker and movie, shared by the windowed and headless loops
+pub struct Session {
+    pub chip8: Chip8,
+    pub speaker: Speaker,
+    // Movie being replayed, the keypad only follows it until it ends
+    pub replay: Option<Movie>,
+    pub recorder: Option<MovieRecorder>,
+    // Number of frames and instructions run so far
+    pub frame: u64,
+    pub instructions: u64,
+    // Instructions run per frame
+    pub cycles_per_frame: u32,
+    pub breakpoints: Breakpoints,
+    pub tracer: Option<Tracer>,
+    // Sprites drawn so far, for the debugger panel
+    pub sprites: SpriteRecorder,
+}
+
+impl Session {
+    /// Runs one frame (1/60s): applies the input, runs the CPU then ticks the timers and the sound.
+    /// The CPU stops early at breakpoints, returns the address of the one it stopped at.
+    pub fn run_frame(&mut self) -> Option<usize> {
+        if let Some(movie) = &mut self.replay {
+            for change in movie.changes_at(self.frame) {
+                self.chip8.keypad.set_key(change.key, change.state);
+            }
+            if movie.is_finished(self.frame) {
+                println!("Replay finished at frame {}", self.frame);
+                self.replay = None;
+            }
+        }
+
+        for change in self.chip8.keypad.take_changes() {
+            if let Some(recorder) = &mut self.recorder {
+                recorder
+                    .record(self.frame, change)
+                    .expect("Could not write movie file");
+            }
+        }
+
+        if let Some(tracer) = &mut self.tracer {
+            tracer.start_frame(self.frame);
+        }
+        let mut breakpoint = None;
+        let mut watchers = (&mut self.tracer, &mut self.sprites);
+        let mut hooks = (&mut self.breakpoints, &mut watchers);
+        for _ in 0..self.cycles_per_frame {
+            if !self.chip8.cycle_with(&mut hooks) {
+                breakpoint = Some(self.chip8.pc());
+                break;
+            }
+            self.instructions += 1;
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.check().expect("Could not write trace file");
+        }
+
+        self.chip8.decrement_timers();
+        self.chip8.handle_sound(&mut self.speaker);
+        self.speaker
+            .end_frame()
+            .expect("Could not write audio file");
+        self.frame += 1;
+        breakpoint
+    }
+
+    /// Runs the next instruction with the hooks, between two frames, e.g. for the debugger's step.
+    /// A breakpoint on it doesn't stop it.
+    pub fn step(&mut self) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.start_frame(self.frame);
+        }
+        let mut watchers = (&mut self.tracer, &mut self.sprites);
+        let mut hooks = (&mut self.breakpoints, &mut watchers);
+        // Once stopped at a breakpoint, the instruction runs the next time
+        if self.chip8.cycle_with(&mut hooks) || self.chip8.cycle_with(&mut hooks) {
+            self.instructions += 1;
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.check().expect("Could not write trace file");
+        }
+    }
+
+    /// Whether a movie is being recorded or replayed, which only holds the keypad
+    pub fn has_movie(&self) -> bool {
+        self.recorder.is_some() || self.replay.is_some()
+    }
+
+    /// Runs the machine without sound until it is about to execute the instruction at the address,
+    /// for at most the number of frames. Returns whether it got there.
+    pub fn run_to(&mut self, address: usize, max_frames: u64) -> bool {
+        for _ in 0..max_frames {
+            for _ in 0..self.cycles_per_frame {
+                if self.chip8.pc() == address {
+                    return true;
+                }
+                self.chip8.cycle();
+            }
+            self.chip8.decrement_timers();
+        }
+        false
+    }
+
+    /// Switches to another game, ending the movies of the previous one
+    pub fn switch_game(&mut self, chip8: Chip8, cycles_per_frame: u32) -> io::Result<()> {
+        if let Some(recorder) = self.recorder.take() {
+            recorder.finish(self.frame)?;
+        }
+        self.replay = None;
+        self.speaker.set_sound_timer(0);
+        self.chip8 = chip8;
+        self.sprites.clear();
+        self.cycles_per_frame = cycles_per_frame;
+        Ok(())
+    }
+
+    /// Completes the movie and audio recordings, and the trace
+    pub fn finish(self) -> io::Result<()> {
+        if let Some(recorder) = self.recorder {
+            recorder.finish(self.frame)?;
+        }
+        if let Some(tracer) = self.tracer {
+            tracer.finish()?;
+        }
+        self.speaker.finish()
+    }
+}
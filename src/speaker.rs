@@ -1,66 +1,247 @@
-// Reference: https://nukep.github.io/glium-sdl2/sdl2/audio/index.html
+use crate::audio::{AudioSink, NullSink, SdlSink, WavSink};
+use sdl2::Sdl;
+use std::{io, str::FromStr};
 
-use sdl2::{
-    audio::{AudioCallback, AudioDevice, AudioSpecDesired},
-    Sdl,
-};
+// Time for the amplitude to go from silent to full volume (or back), removes the clicks
+const RAMP_SECONDS: f32 = 0.005;
 
-struct SquareWave {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+    Noise,
+}
+
+impl FromStr for Waveform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "square" => Ok(Waveform::Square),
+            "sine" => Ok(Waveform::Sine),
+            "triangle" => Ok(Waveform::Triangle),
+            "noise" => Ok(Waveform::Noise),
+            _ => Err(format!("Unknown waveform: {}", s)),
+        }
+    }
+}
+
+/// How the buzzer sounds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ToneConfig {
+    // Hz
+    pub frequency: f32,
+    // 0.0 to 1.0
+    pub volume: f32,
+    // Part of the period spent high, for square waves (0.5 = 50%)
+    pub duty: f32,
+    pub waveform: Waveform,
+}
+
+impl Default for ToneConfig {
+    fn default() -> Self {
+        ToneConfig {
+            frequency: 440.0,
+            volume: 0.25,
+            duty: 0.5,
+            waveform: Waveform::Square,
+        }
+    }
+}
+
+/// Generates the buzzer waveform
+pub struct Tone {
+    config: ToneConfig,
     phase_inc: f32,
     phase: f32,
-    volume: f32,
+    // Current amplitude, ramped towards 1.0 while playing and 0.0 while stopped
+    gain: f32,
+    gain_step: f32,
+    muted: bool,
+    // 16-bit LFSR for the noise waveform, and its current output
+    lfsr: u16,
+    noise: f32,
 }
 
-impl AudioCallback for SquareWave {
-    type Channel = f32;
+impl Tone {
+    pub fn new(config: ToneConfig, sample_rate: u32) -> Self {
+        Tone {
+            config,
+            phase_inc: config.frequency / sample_rate as f32,
+            phase: 0.0,
+            gain: 0.0,
+            gain_step: 1.0 / (RAMP_SECONDS * sample_rate as f32),
+            muted: false,
+            lfsr: 0xace1,
+            noise: 1.0,
+        }
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.config.volume = volume;
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    /// Returns the wave value for the current phase, between -1.0 and 1.0
+    fn wave(&mut self) -> f32 {
+        match self.config.waveform {
+            Waveform::Square => match self.phase {
+                p if p < self.config.duty => 1.0,
+                _ => -1.0,
+            },
+            Waveform::Sine => (self.phase * std::f32::consts::TAU).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (self.phase - 0.5).abs(),
+            Waveform::Noise => self.noise,
+        }
+    }
 
-    fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave
+    /// Fills the buffer, playing the tone for the first `remaining` samples then fading out.
+    /// Returns how many samples of tone are left afterwards.
+    pub fn fill(&mut self, out: &mut [f32], mut remaining: u32) -> u32 {
         for x in out.iter_mut() {
-            *x = match self.phase {
-                v if v >= 0.0 && v <= 0.5 => self.volume,
-                _ => -self.volume,
+            let target = if remaining > 0 && !self.muted {
+                1.0
+            } else {
+                0.0
             };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
+            remaining = remaining.saturating_sub(1);
+
+            if self.gain < target {
+                self.gain = (self.gain + self.gain_step).min(target);
+            } else if self.gain > target {
+                self.gain = (self.gain - self.gain_step).max(target);
+            }
+
+            *x = self.wave() * self.config.volume * self.gain;
+
+            self.phase += self.phase_inc;
+            if self.phase >= 1.0 {
+                self.phase %= 1.0;
+                // Noise picks a new level once per period, so it keeps the tone's pitch
+                let bit = (self.lfsr ^ (self.lfsr >> 2) ^ (self.lfsr >> 3) ^ (self.lfsr >> 5)) & 1;
+                self.lfsr = (self.lfsr >> 1) | (bit << 15);
+                self.noise = if self.lfsr & 1 == 1 { 1.0 } else { -1.0 };
+            }
         }
+        remaining
     }
 }
 
+/// Plays the buzzer through an audio sink, and optionally records it to a WAV file
 pub struct Speaker {
-    audio: AudioDevice<SquareWave>,
+    output: Box<dyn AudioSink>,
+    // Recorded alongside the output, not affected by mute and volume changes
+    recording: Option<WavSink>,
+    config: ToneConfig,
+    volume: f32,
+    muted: bool,
 }
 
 impl Speaker {
-    pub fn new(sdl: &Sdl) -> Self {
-        let audio_subsystem = sdl.audio().unwrap();
+    pub fn new(output: Box<dyn AudioSink>, config: ToneConfig) -> Self {
+        Speaker {
+            output,
+            recording: None,
+            config,
+            volume: config.volume,
+            muted: false,
+        }
+    }
 
-        let desired_spec = AudioSpecDesired {
-            freq: Some(44100),
-            channels: Some(1), // mono
-            samples: None,     // default sample size
+    /// Plays through the default audio device, or without sound if there is none
+    pub fn open(sdl: &Sdl, config: ToneConfig) -> Self {
+        let output: Box<dyn AudioSink> = match SdlSink::open(sdl, config) {
+            Ok(sink) => Box::new(sink),
+            Err(e) => {
+                eprintln!("Warning: no audio device ({}), running without sound", e);
+                Box::new(NullSink)
+            }
         };
+        Self::new(output, config)
+    }
+
+    /// Speaker without sound output, e.g. to only record to a file
+    pub fn silent(config: ToneConfig) -> Self {
+        Self::new(Box::new(NullSink), config)
+    }
+
+    /// Also writes the buzzer output to a WAV file, one 60th of a second per emulated frame
+    pub fn record_to(&mut self, path: &str) -> io::Result<()> {
+        self.recording = Some(WavSink::create(path, self.config)?);
+        Ok(())
+    }
+
+    /// Plays the buzzer for the given number of 60Hz ticks, or stops it when 0.
+    /// Called whenever the sound timer is set, the sinks then count it down sample by sample.
+    pub fn set_sound_timer(&mut self, ticks: u8) {
+        self.output.set_sound_timer(ticks);
+        if let Some(recording) = &mut self.recording {
+            recording.set_sound_timer(ticks);
+        }
+    }
 
-        let audio = audio_subsystem
-            .open_playback(None, &desired_spec, |spec| {
-                // initialize the audio callback
-                SquareWave {
-                    phase_inc: 440.0 / spec.freq as f32,
-                    phase: 0.0,
-                    volume: 0.25,
-                }
-            })
-            .unwrap();
+    /// Keeps the buzzer playing for at least one more tick
+    pub fn sustain(&mut self) {
+        self.output.sustain();
+        if let Some(recording) = &mut self.recording {
+            recording.sustain();
+        }
+    }
+
+    /// Called once per emulated frame, for the sinks that follow emulated time
+    pub fn end_frame(&mut self) -> io::Result<()> {
+        self.output.end_frame()?;
+        if let Some(recording) = &mut self.recording {
+            recording.end_frame()?;
+        }
+        Ok(())
+    }
+
+    /// Completes the recording if any
+    pub fn finish(self) -> io::Result<()> {
+        match self.recording {
+            Some(recording) => recording.finish(),
+            None => Ok(()),
+        }
+    }
 
-        Speaker { audio }
+    /// Mutes or unmutes the buzzer, returns true if it is now muted
+    pub fn toggle_mute(&mut self) -> bool {
+        self.muted = !self.muted;
+        self.output.set_muted(self.muted);
+        self.muted
     }
 
-    /// Emit a buzz sound
-    pub fn emit_sound(&mut self) {
-        self.audio.resume()
+    /// Changes the volume by the given amount, returns the new volume
+    pub fn change_volume(&mut self, delta: f32) -> f32 {
+        self.volume = (self.volume + delta).clamp(0.0, 1.0);
+        self.output.set_volume(self.volume);
+        self.volume
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tone_ramps_in_and_out() {
+        let mut tone = Tone::new(ToneConfig::default(), 44100);
+        let mut out = [0.0; 1000];
+        assert_eq!(tone.fill(&mut out, 0), 0);
+        assert!(out.iter().all(|&x| x == 0.0));
+
+        // No jump to full volume on the first sample
+        assert_eq!(tone.fill(&mut out, 5000), 4000);
+        assert!(out[0].abs() < 0.01);
+        assert_eq!(out[999].abs(), 0.25);
 
-    /// Stop emitting buzz sound
-    pub fn stop_emitting(&mut self) {
-        self.audio.pause()
+        tone.fill(&mut out, 0);
+        assert!(out[0].abs() > 0.24);
+        assert_eq!(out[999], 0.0);
     }
 }
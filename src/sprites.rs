@@ -0,0 +1,301 @@
+// Sprite viewer: memory shown as 8xN sprites, or 16x16 SUPER-CHIP ones, in a grid, the sprites DXYN
+// actually drew while the game ran, and PNG export of the grid.
+
+use crate::chip8::{Chip8, Hook};
+use std::collections::BTreeMap;
+
+// Colors of the PNG images, the display's
+const ON_COLOR: [u8; 3] = [22, 22, 22];
+const OFF_COLOR: [u8; 3] = [248, 171, 18];
+const GAP_COLOR: [u8; 3] = [40, 40, 40];
+// Pixels between sprites in the grid
+const GAP: usize = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Shape {
+    // 8 pixels wide, a byte per row
+    Narrow(usize),
+    // 16x16, two bytes per row, drawn by DXY0 on SUPER-CHIP
+    Wide,
+}
+
+impl Shape {
+    /// Shape DXYN draws
+    pub fn of_draw(opcode: u16) -> Self {
+        match (opcode & 0xf) as usize {
+            0 => Shape::Wide,
+            n => Shape::Narrow(n),
+        }
+    }
+
+    pub fn width(self) -> usize {
+        match self {
+            Shape::Narrow(_) => 8,
+            Shape::Wide => 16,
+        }
+    }
+
+    pub fn height(self) -> usize {
+        match self {
+            Shape::Narrow(rows) => rows,
+            Shape::Wide => 16,
+        }
+    }
+
+    /// Bytes the sprite takes in memory
+    pub fn size(self) -> usize {
+        self.width() / 8 * self.height()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sprite {
+    pub address: usize,
+    pub shape: Shape,
+}
+
+impl Sprite {
+    /// Sprites one after the other from start, up to end
+    pub fn tile(start: usize, end: usize, shape: Shape) -> Vec<Sprite> {
+        let addresses = (start..end).step_by(shape.size().max(1));
+        addresses.map(|address| Sprite { address, shape }).collect()
+    }
+
+    /// Whether the pixel is on, memory wraps around like for DXYN
+    fn pixel(&self, memory: &[u8], x: usize, y: usize) -> bool {
+        let bytes_per_row = self.shape.width() / 8;
+        let address = self.address + y * bytes_per_row + x / 8;
+        memory[address % memory.len()] & (0x80 >> (x % 8)) != 0
+    }
+}
+
+/// Records the sprites DXYN draws, with the address in I and how many times
+#[derive(Default)]
+pub struct SpriteRecorder {
+    drawn: BTreeMap<(usize, Shape), u64>,
+}
+
+impl SpriteRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sprites drawn so far by address, with how many times
+    pub fn drawn(&self) -> Vec<(Sprite, u64)> {
+        let drawn = self.drawn.iter();
+        drawn
+            .map(|(&(address, shape), &draws)| (Sprite { address, shape }, draws))
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.drawn.clear();
+    }
+}
+
+impl Hook for SpriteRecorder {
+    fn after_execute(&mut self, chip8: &Chip8, _address: usize, opcode: u16) {
+        if opcode >> 12 == 0xd {
+            *self.drawn.entry((chip8.i(), Shape::of_draw(opcode))).or_default() += 1;
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pixel {
+    Off,
+    On,
+    // Between sprites
+    Gap,
+}
+
+/// Sprites laid out in a grid
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pixels: Vec<Pixel>,
+}
+
+impl Image {
+    /// Sprites in rows of columns, each in a cell as large as the largest one
+    pub fn grid(memory: &[u8], sprites: &[Sprite], columns: usize) -> Self {
+        let cell_width = sprites.iter().map(|s| s.shape.width()).max().unwrap_or(0);
+        let cell_height = sprites.iter().map(|s| s.shape.height()).max().unwrap_or(0);
+        let columns = columns.min(sprites.len()).max(1);
+        let rows = sprites.len().div_ceil(columns);
+        let width = columns * (cell_width + GAP) - GAP;
+        let height = (rows * (cell_height + GAP)).saturating_sub(GAP);
+        let mut image = Image {
+            width,
+            height,
+            pixels: vec![Pixel::Gap; width * height],
+        };
+        for (index, sprite) in sprites.iter().enumerate() {
+            let left = index % columns * (cell_width + GAP);
+            let top = index / columns * (cell_height + GAP);
+            for y in 0..sprite.shape.height() {
+                for x in 0..sprite.shape.width() {
+                    image.pixels[(top + y) * width + left + x] = match sprite.pixel(memory, x, y) {
+                        true => Pixel::On,
+                        false => Pixel::Off,
+                    };
+                }
+            }
+        }
+        image
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Pixel {
+        self.pixels[y * self.width + x]
+    }
+
+    /// One line per row, `#` for pixels that are on and `.` for those that are off
+    pub fn to_text(&self) -> String {
+        let rows = self.pixels.chunks(self.width.max(1)).map(|row| {
+            let pixels = row.iter().map(|pixel| match pixel {
+                Pixel::Off => '.',
+                Pixel::On => '#',
+                Pixel::Gap => ' ',
+            });
+            pixels.collect::<String>().trim_end().to_string() + "\n"
+        });
+        rows.collect()
+    }
+
+    /// PNG file of the image, each pixel a square of scale by scale
+    pub fn to_png(&self, scale: usize) -> Vec<u8> {
+        let (width, height) = (self.width * scale, self.height * scale);
+        let mut data = Vec::with_capacity((width * 3 + 1) * height);
+        for y in 0..height {
+            // No filter
+            data.push(0);
+            for x in 0..width {
+                data.extend(match self.pixel(x / scale, y / scale) {
+                    Pixel::Off => OFF_COLOR,
+                    Pixel::On => ON_COLOR,
+                    Pixel::Gap => GAP_COLOR,
+                });
+            }
+        }
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        let mut header = Vec::new();
+        header.extend((width as u32).to_be_bytes());
+        header.extend((height as u32).to_be_bytes());
+        // 8 bit RGB, default compression, filters and no interlacing
+        header.extend([8, 2, 0, 0, 0]);
+        write_chunk(&mut png, b"IHDR", &header);
+        let compressed = miniz_oxide::deflate::compress_to_vec_zlib(&data, 6);
+        write_chunk(&mut png, b"IDAT", &compressed);
+        write_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend(kind);
+    png.extend(data);
+    let crc = crc32(&png[start..]);
+    png.extend(crc.to_be_bytes());
+}
+
+/// CRC-32 of PNG chunks, as in the PNG specification
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb88320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Sprites as text in rows of columns, each row of sprites under their addresses
+pub fn to_text(memory: &[u8], sprites: &[Sprite], columns: usize) -> String {
+    let mut text = String::new();
+    for row in sprites.chunks(columns.max(1)) {
+        let cell_width = row.iter().map(|s| s.shape.width()).max().unwrap_or(0);
+        let addresses: Vec<String> = row
+            .iter()
+            .map(|sprite| format!("{:<1$}", format!("0x{:03X}", sprite.address), cell_width))
+            .collect();
+        text += addresses.join(" ").trim_end();
+        text.push('\n');
+        text += &Image::grid(memory, row, columns).to_text();
+        text.push('\n');
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lays_sprites_out_in_a_grid() {
+        let memory = [0x80, 0x40, 0xff, 0x01, 0x00, 0x00];
+        let sprites = Sprite::tile(0, 6, Shape::Narrow(2));
+        assert_eq!(
+            to_text(&memory, &sprites, 2),
+            "0x000    0x002
+#....... ########
+.#...... .......#
+
+0x004
+........
+........
+
+"
+        );
+        let image = Image::grid(&memory, &sprites, 2);
+        assert_eq!((image.width, image.height), (17, 5));
+        assert_eq!(image.pixel(8, 0), Pixel::Gap);
+
+        // Two bytes per row
+        let wide = Sprite {
+            address: 2,
+            shape: Shape::Wide,
+        };
+        let image = Image::grid(&memory, &[wide], 4);
+        let text = image.to_text();
+        let rows: Vec<&str> = text.lines().collect();
+        assert_eq!(rows.len(), 16);
+        assert_eq!(rows[0], "########.......#");
+        // Past the end of memory, from its start
+        assert_eq!(rows[2], "#........#......");
+    }
+
+    #[test]
+    fn records_drawn_sprites() {
+        // LD I, 0x20A; DRW V0, V1, 3; DRW V0, V1, 3; LD I, 0x20D; DRW V0, V1, 0
+        let program = [0xa2, 0x0a, 0xd0, 0x13, 0xd0, 0x13, 0xa2, 0x0d, 0xd0, 0x10];
+        let mut chip8 = Chip8::from_program(&program);
+        let mut recorder = SpriteRecorder::new();
+        for _ in 0..5 {
+            chip8.cycle_with(&mut recorder);
+        }
+        let narrow = Sprite {
+            address: 0x20a,
+            shape: Shape::Narrow(3),
+        };
+        let wide = Sprite {
+            address: 0x20d,
+            shape: Shape::Wide,
+        };
+        assert_eq!(recorder.drawn(), [(narrow, 2), (wide, 1)]);
+    }
+
+    #[test]
+    fn writes_png() {
+        let image = Image::grid(&[0x80], &[Sprite::tile(0, 1, Shape::Narrow(1))[0]], 1);
+        let png = image.to_png(2);
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        // IHDR: 16x2
+        assert_eq!(&png[16..24], [0, 0, 0, 16, 0, 0, 0, 2]);
+        assert_eq!(crc32(b"IEND"), 0xae426082);
+        assert_eq!(&png[png.len() - 4..], 0xae426082u32.to_be_bytes());
+    }
+}
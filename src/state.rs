@@ -0,0 +1,17 @@
+use crate::{chip8::MEMORY_SIZE, framebuffer::Screen};
+
+/// Snapshot of the whole machine, taken with `Chip8::save_state` and restored with `Chip8::load_state`
+#[derive(Clone)]
+pub struct SaveState {
+    pub pc: usize,
+    pub v: [u8; 16],
+    pub i: usize,
+    pub stack: [usize; 16],
+    pub sp: usize,
+    pub memory: [u8; MEMORY_SIZE],
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub screen: Screen,
+    // State of the random source, so CXKK gives the same numbers after loading
+    pub rng: u64,
+}
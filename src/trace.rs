@@ -0,0 +1,335 @@
+/*
+Traces log every instruction run, with the machine state right before it, to compare runs with other emulators.
+The text format has one line per instruction:
+
+PC   OPCODE MNEMONIC         | V0 .. VF                                        I    SP DT ST
+0202 D013   DRW V0, V1, 3    | 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 020A 00 3C 00
+
+The binary format, for long runs, starts with the magic then has 25 bytes per instruction:
+PC and opcode (2 bytes each, big-endian), V0-VF, I (2 bytes), SP, DT and ST.
+
+Reference traces from other emulators may add the screen to text lines, after ST: 512 hex digits,
+8 pixels per byte from the top left, leftmost pixel in the high bit.
+*/
+
+use crate::{
+    chip8::{Chip8, Hook},
+    disassembler::disassemble,
+    framebuffer::{Screen, WIDTH},
+};
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, ErrorKind, Write},
+    ops::RangeInclusive,
+    str::FromStr,
+};
+
+pub const BINARY_MAGIC: &[u8; 8] = b"CHIP8TR1";
+const BINARY_ENTRY_SIZE: usize = 25;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TraceFormat {
+    #[default]
+    Text,
+    Binary,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(TraceFormat::Text),
+            "binary" => Ok(TraceFormat::Binary),
+            _ => Err(format!("Unknown trace format: {} (text or binary)", s)),
+        }
+    }
+}
+
+/// Which instructions get logged
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TraceFilter {
+    // Addresses of the instructions
+    pub range: Option<RangeInclusive<usize>>,
+    // Opcode classes (high nibbles) as bits, None for all of them
+    pub classes: Option<u16>,
+    // First frame logged
+    pub after_frame: u64,
+}
+
+impl TraceFilter {
+    /// Parses opcode classes such as `8,D,F`
+    pub fn parse_classes(text: &str) -> Result<u16, String> {
+        text.split(',').try_fold(0, |classes, class| {
+            match u8::from_str_radix(class.trim(), 16) {
+                Ok(class) if class < 16 => Ok(classes | 1 << class),
+                _ => Err(format!("Invalid opcode class: {} (0 to F)", class)),
+            }
+        })
+    }
+
+    fn accepts(&self, frame: u64, address: usize, opcode: u16) -> bool {
+        frame >= self.after_frame
+            && self
+                .range
+                .as_ref()
+                .is_none_or(|range| range.contains(&address))
+            && self
+                .classes
+                .is_none_or(|classes| classes >> (opcode >> 12) & 1 == 1)
+    }
+}
+
+/// Machine state before an instruction
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEntry {
+    pub pc: u16,
+    pub opcode: u16,
+    pub v: [u8; 16],
+    pub i: u16,
+    pub sp: u8,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    // Packed screen, only in reference traces
+    pub screen: Option<Vec<u8>>,
+}
+
+impl TraceEntry {
+    pub fn new(chip8: &Chip8, address: usize, opcode: u16) -> Self {
+        TraceEntry {
+            pc: address as u16,
+            opcode,
+            v: *chip8.v(),
+            i: chip8.i() as u16,
+            sp: chip8.sp() as u8,
+            delay_timer: chip8.delay_timer(),
+            sound_timer: chip8.sound_timer(),
+            screen: None,
+        }
+    }
+
+    /// Parses a text trace line, None if it is not one
+    pub fn parse(line: &str) -> Option<Self> {
+        let (instruction, state) = line.split_once('|')?;
+        let mut instruction = instruction.split_whitespace();
+        let pc = u16::from_str_radix(instruction.next()?, 16).ok()?;
+        let opcode = u16::from_str_radix(instruction.next()?, 16).ok()?;
+        let fields: Vec<&str> = state.split_whitespace().collect();
+        if !(20..=21).contains(&fields.len()) {
+            return None;
+        }
+        let byte = |field: &str| u8::from_str_radix(field, 16).ok();
+        let mut v = [0; 16];
+        for (value, field) in v.iter_mut().zip(&fields) {
+            *value = byte(field)?;
+        }
+        let screen = match fields.get(20) {
+            Some(hex) if hex.len() == 2 * SCREEN_BYTES => Some(
+                (0..hex.len())
+                    .step_by(2)
+                    .map(|i| byte(hex.get(i..i + 2)?))
+                    .collect::<Option<_>>()?,
+            ),
+            Some(_) => return None,
+            None => None,
+        };
+        Some(TraceEntry {
+            pc,
+            opcode,
+            v,
+            i: u16::from_str_radix(fields[16], 16).ok()?,
+            sp: byte(fields[17])?,
+            delay_timer: byte(fields[18])?,
+            sound_timer: byte(fields[19])?,
+            screen,
+        })
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        TraceEntry {
+            pc: u16::from_be_bytes([bytes[0], bytes[1]]),
+            opcode: u16::from_be_bytes([bytes[2], bytes[3]]),
+            v: bytes[4..20].try_into().unwrap(),
+            i: u16::from_be_bytes([bytes[20], bytes[21]]),
+            sp: bytes[22],
+            delay_timer: bytes[23],
+            sound_timer: bytes[24],
+            screen: None,
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let v: Vec<String> = self
+            .v
+            .iter()
+            .map(|value| format!("{:02X}", value))
+            .collect();
+        format!(
+            "{:04X} {:04X}   {:<16} | {} {:04X} {:02X} {:02X} {:02X}",
+            self.pc,
+            self.opcode,
+            disassemble(self.opcode),
+            v.join(" "),
+            self.i,
+            self.sp,
+            self.delay_timer,
+            self.sound_timer
+        )
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(BINARY_ENTRY_SIZE);
+        bytes.extend_from_slice(&self.pc.to_be_bytes());
+        bytes.extend_from_slice(&self.opcode.to_be_bytes());
+        bytes.extend_from_slice(&self.v);
+        bytes.extend_from_slice(&self.i.to_be_bytes());
+        bytes.extend_from_slice(&[self.sp, self.delay_timer, self.sound_timer]);
+        bytes
+    }
+}
+
+const SCREEN_BYTES: usize = WIDTH * crate::framebuffer::HEIGHT / 8;
+
+/// Screen with 8 pixels per byte, as in reference traces
+pub fn pack_screen(screen: &Screen) -> Vec<u8> {
+    let pixels: Vec<u8> = screen.iter().flatten().copied().collect();
+    pixels
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0, |packed, &pixel| packed << 1 | pixel))
+        .collect()
+}
+
+/// Reads a text or binary trace
+pub fn read(path: &str) -> io::Result<Vec<TraceEntry>> {
+    let data = fs::read(path)?;
+    if let Some(entries) = data.strip_prefix(BINARY_MAGIC) {
+        if entries.len() % BINARY_ENTRY_SIZE != 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "truncated binary trace",
+            ));
+        }
+        return Ok(entries
+            .chunks(BINARY_ENTRY_SIZE)
+            .map(TraceEntry::from_bytes)
+            .collect());
+    }
+    let text = String::from_utf8(data).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    let lines = text.lines().enumerate();
+    // Blank lines and comments are allowed
+    let lines = lines.filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'));
+    lines
+        .map(|(n, line)| {
+            TraceEntry::parse(line).ok_or_else(|| {
+                let message = format!("line {}: not a trace line: {}", n + 1, line);
+                io::Error::new(ErrorKind::InvalidData, message)
+            })
+        })
+        .collect()
+}
+
+/// Writes the instructions the filter lets through to a trace file
+pub struct Tracer {
+    out: BufWriter<File>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    // Frame being run
+    frame: u64,
+    // First write error, reported by `check` as hooks can't fail
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn create(path: &str, format: TraceFormat, filter: TraceFilter) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        if format == TraceFormat::Binary {
+            out.write_all(BINARY_MAGIC)?;
+        }
+        Ok(Tracer {
+            out,
+            format,
+            filter,
+            frame: 0,
+            error: None,
+        })
+    }
+
+    /// Called at the start of every frame
+    pub fn start_frame(&mut self, frame: u64) {
+        self.frame = frame;
+    }
+
+    /// Returns the first write error since the last call
+    pub fn check(&mut self) -> io::Result<()> {
+        self.error.take().map_or(Ok(()), Err)
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.check()?;
+        self.out.flush()
+    }
+}
+
+impl Hook for Tracer {
+    fn before_execute(&mut self, chip8: &Chip8, address: usize, opcode: u16) -> bool {
+        if self.error.is_none() && self.filter.accepts(self.frame, address, opcode) {
+            let entry = TraceEntry::new(chip8, address, opcode);
+            let written = match self.format {
+                TraceFormat::Text => writeln!(self.out, "{}", entry.to_text()),
+                TraceFormat::Binary => self.out.write_all(&entry.to_bytes()),
+            };
+            self.error = written.err();
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    #[test]
+    fn logs_filtered_instructions() {
+        let path = env::temp_dir().join(format!("chip8-trace-{}.log", process::id()));
+        let path = path.to_str().unwrap();
+        let filter = TraceFilter {
+            range: Some(0x200..=0x204),
+            classes: Some(TraceFilter::parse_classes("6,a").unwrap()),
+            after_frame: 1,
+        };
+        let mut tracer = Tracer::create(path, TraceFormat::Text, filter).unwrap();
+        // LD V0, 0x12; LD I, 0x20A; LD V1, 0x34; LD V2, 0x56
+        let mut chip8 = Chip8::from_program(&[0x60, 0x12, 0xa2, 0x0a, 0x61, 0x34, 0x62, 0x56]);
+        chip8.cycle_with(&mut tracer);
+        tracer.start_frame(1);
+        for _ in 0..3 {
+            chip8.cycle_with(&mut tracer);
+        }
+        tracer.finish().unwrap();
+
+        let trace = fs::read_to_string(path).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        let registers = |v0, v1| format!("{:02X} {:02X} {}", v0, v1, ["00"; 14].join(" "));
+        assert_eq!(
+            lines,
+            [
+                format!(
+                    "0202 A20A   LD I, 0x20A      | {} 0000 00 00 00",
+                    registers(0x12, 0)
+                ),
+                format!(
+                    "0204 6134   LD V1, 0x34      | {} 020A 00 00 00",
+                    registers(0x12, 0)
+                ),
+            ]
+        );
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            TraceFilter::parse_classes("8, D,f"),
+            Ok(0b1010_0001_0000_0000)
+        );
+        assert!(TraceFilter::parse_classes("10").is_err());
+    }
+}
@@ -0,0 +1,92 @@
+// Polling the ROM file for changes, to reload it on every build while working on a game (--watch).
+
+use crate::rom::{self, RomError, RomFile, RomFormat};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+// Time between two looks at the file
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Where the game restarts after reloading its ROM
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resume {
+    // From the beginning
+    Start,
+    // From the quick save, with the new ROM bytes
+    SavedState,
+    // From the first time the instruction at the address is about to run
+    Breakpoint(usize),
+}
+
+pub struct Watcher {
+    path: PathBuf,
+    format: RomFormat,
+    // Modification time and size of the file when it was last read, None if it could not be checked
+    version: Option<(SystemTime, u64)>,
+    last_poll: Instant,
+}
+
+impl Watcher {
+    pub fn new(path: &Path, format: RomFormat) -> Self {
+        Watcher {
+            path: path.to_path_buf(),
+            format,
+            version: Self::version(path),
+            last_poll: Instant::now(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn version(path: &Path) -> Option<(SystemTime, u64)> {
+        let metadata = fs::metadata(path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+
+    /// Called every frame, looks at the file from time to time
+    pub fn poll(&mut self) -> Option<Result<RomFile, RomError>> {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return None;
+        }
+        self.last_poll = Instant::now();
+        self.check()
+    }
+
+    /// Reads the ROM again if the file changed since it was last read
+    fn check(&mut self) -> Option<Result<RomFile, RomError>> {
+        let version = Self::version(&self.path);
+        // A missing file is probably being rebuilt
+        if version.is_none() || version == self.version {
+            return None;
+        }
+        self.version = version;
+        Some(rom::read_as(&self.path, self.format))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn reads_changed_files_once() {
+        let path = env::temp_dir().join(format!("chip8-watch-{}.ch8", process::id()));
+        fs::write(&path, [0x12, 0x00]).unwrap();
+        let mut watcher = Watcher::new(&path, RomFormat::Auto);
+        assert!(watcher.check().is_none());
+
+        fs::write(&path, [0x00, 0xe0, 0x12, 0x00]).unwrap();
+        let file = watcher.check().unwrap().unwrap();
+        assert_eq!(file.program, [0x00, 0xe0, 0x12, 0x00]);
+        assert!(watcher.check().is_none());
+
+        fs::remove_file(&path).unwrap();
+        assert!(watcher.check().is_none());
+    }
+}
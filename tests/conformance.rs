@@ -0,0 +1,238 @@
+// Conformance suite: test ROMs run headless for a fixed number of frames, then the screen is compared
+// with a golden image.
+// The ROMs in tests/conformance are Octo sources written in the spirit of the community test ROMs.
+// Community ROMs run too: corax89's opcode test, bundled in roms, always, and BC_test and the Timendus
+// suite (https://github.com/Timendus/chip8-test-suite) once copied to tests/conformance/community.
+// Their golden images there start with the SHA-1 of the ROM they were made with, so that another build
+// of a ROM doesn't pass for it, and a missing ROM or golden image fails.
+// Golden images have one line per row, `#` for pixels that are on and `.` for those that are off.
+// CHIP8_BLESS=1 rewrites them from the current output, check the diff before committing it.
+
+use chip8::{
+    chip8::Chip8,
+    framebuffer::Screen,
+    movie::rom_hash,
+    octo,
+    platform::Platform,
+};
+use std::{env, fs, path::PathBuf};
+
+const DIRECTORY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/conformance");
+// Enough for every ROM to get to its final loop
+const FRAMES: u64 = 60;
+const CYCLES_PER_FRAME: u32 = 100;
+// Where the community ROMs read the test or platform to run, when it is not 0
+const SELECTION_ADDRESS: usize = 0x1ff;
+
+/// Key going down or up at the start of a frame
+struct KeyEvent {
+    frame: u64,
+    key: usize,
+    state: bool,
+}
+
+fn press(frame: u64, key: usize, state: bool) -> KeyEvent {
+    KeyEvent { frame, key, state }
+}
+
+/// Runs the ROM, with a byte at SELECTION_ADDRESS if given, returns the final screen as text
+fn run(rom: Vec<u8>, platform: Platform, selection: Option<u8>, keys: &[KeyEvent]) -> String {
+    let mut chip8 = Chip8::from_program(&rom);
+    chip8.set_quirks(platform.quirks());
+    if let Some(selection) = selection {
+        chip8.write_memory(SELECTION_ADDRESS, &[selection]);
+    }
+    for frame in 0..FRAMES {
+        for event in keys.iter().filter(|event| event.frame == frame) {
+            chip8.keypad.set_key(event.key, event.state);
+        }
+        for _ in 0..CYCLES_PER_FRAME {
+            chip8.cycle();
+        }
+        chip8.decrement_timers();
+    }
+    to_text(chip8.framebuffer.screen())
+}
+
+/// Assembles and runs one of the Octo test ROMs
+fn run_source(rom: &str, platform: Platform, keys: &[KeyEvent]) -> String {
+    let source = fs::read_to_string(PathBuf::from(DIRECTORY).join(format!("{}.8o", rom)))
+        .unwrap_or_else(|e| panic!("Could not read {}: {}", rom, e));
+    let bytes = octo::assemble(&source).unwrap_or_else(|e| panic!("{}: {}", rom, e));
+    run(bytes, platform, None, keys)
+}
+
+fn to_text(screen: &Screen) -> String {
+    let rows = screen.iter().map(|row| {
+        let pixels = row.iter().map(|&pixel| if pixel == 1 { '#' } else { '.' });
+        pixels.chain(['\n']).collect::<String>()
+    });
+    rows.collect()
+}
+
+/// Compares the screen with the golden image, or rewrites it with CHIP8_BLESS
+fn check(golden: &str, screen: String) {
+    let path = PathBuf::from(DIRECTORY).join(format!("{}.txt", golden));
+    if env::var_os("CHIP8_BLESS").is_some() {
+        fs::write(&path, &screen).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Could not read golden image {}: {}", golden, e));
+    assert!(
+        screen == expected,
+        "{} differs from its golden image, got:\n{}",
+        golden,
+        screen
+    );
+}
+
+#[test]
+fn logo() {
+    check("logo", run_source("logo", Platform::ModernChip8, &[]));
+}
+
+#[test]
+fn opcodes() {
+    check("opcodes", run_source("opcodes", Platform::ModernChip8, &[]));
+}
+
+#[test]
+fn flags() {
+    // The flags don't depend on the quirks
+    for platform in [Platform::OriginalChip8, Platform::ModernChip8] {
+        check("flags", run_source("flags", platform, &[]));
+    }
+}
+
+#[test]
+fn quirks() {
+    check("quirks-vip", run_source("quirks", Platform::OriginalChip8, &[]));
+    check("quirks-chip48", run_source("quirks", Platform::Chip48, &[]));
+    check("quirks-modern", run_source("quirks", Platform::ModernChip8, &[]));
+    check("quirks-xochip", run_source("quirks", Platform::XoChip, &[]));
+}
+
+#[test]
+fn keypad() {
+    // FX0A gets A then 3, whether it registers keys when they go down or up
+    let keys = [
+        press(5, 0xA, true),
+        press(8, 0xA, false),
+        press(12, 0x3, true),
+        press(15, 0x3, false),
+        press(20, 0xE, true),
+    ];
+    check("keypad", run_source("keypad", Platform::OriginalChip8, &keys));
+    check("keypad", run_source("keypad", Platform::ModernChip8, &keys));
+}
+
+/// Community ROM, from the root of the repository, run with a platform, the byte at SELECTION_ADDRESS
+/// and keys
+struct Community {
+    file: &'static str,
+    golden: &'static str,
+    platform: Platform,
+    selection: Option<u8>,
+    keys: Vec<KeyEvent>,
+}
+
+fn community(file: &'static str, golden: &'static str, platform: Platform) -> Community {
+    Community {
+        file,
+        golden,
+        platform,
+        selection: None,
+        keys: Vec::new(),
+    }
+}
+
+/// Runs community ROMs against their golden images, which must have been made with the same ROM
+fn check_community(roms: Vec<Community>) {
+    for test in roms {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(test.file);
+        let rom = fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        let screen = run(rom.clone(), test.platform, test.selection, &test.keys);
+        let pinned = format!("sha1 {}\n{}", rom_hash(&rom), screen);
+        let golden = format!("community/{}", test.golden);
+        let path = PathBuf::from(DIRECTORY).join(format!("{}.txt", golden));
+        if env::var_os("CHIP8_BLESS").is_none() {
+            let expected = fs::read_to_string(&path).unwrap_or_else(|_| {
+                panic!("No golden image for {}, make it with CHIP8_BLESS=1", golden)
+            });
+            let hash = expected.lines().next().unwrap_or_default();
+            assert!(
+                hash == format!("sha1 {}", rom_hash(&rom)),
+                "{} is not the ROM {} was made with ({})",
+                test.file,
+                golden,
+                hash
+            );
+        } else {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+        }
+        check(&golden, pinned);
+    }
+}
+
+/// corax89's opcode test, bundled as roms/TEST
+#[test]
+fn corax() {
+    check_community(vec![
+        community("roms/TEST", "corax", Platform::OriginalChip8),
+        community("roms/TEST", "corax", Platform::ModernChip8),
+    ]);
+}
+
+/// The test suites that aren't bundled yet: BestCoder's BC_test and Timendus' chip8-test-suite
+#[test]
+#[ignore = "copy the ROMs to tests/conformance/community first, see the README"]
+fn test_suites() {
+    let key_test = |golden, selection, key| Community {
+        selection: Some(selection),
+        keys: vec![press(5, key, true), press(10, key, false)],
+        ..community(
+            "tests/conformance/community/6-keypad.ch8",
+            golden,
+            Platform::OriginalChip8,
+        )
+    };
+    check_community(vec![
+        community(
+            "tests/conformance/community/BC_test.ch8",
+            "bc-test",
+            Platform::ModernChip8,
+        ),
+        community(
+            "tests/conformance/community/1-chip8-logo.ch8",
+            "chip8-logo",
+            Platform::ModernChip8,
+        ),
+        community(
+            "tests/conformance/community/2-ibm-logo.ch8",
+            "ibm-logo",
+            Platform::ModernChip8,
+        ),
+        community(
+            "tests/conformance/community/3-corax+.ch8",
+            "corax+",
+            Platform::ModernChip8,
+        ),
+        community(
+            "tests/conformance/community/4-flags.ch8",
+            "flags",
+            Platform::ModernChip8,
+        ),
+        Community {
+            selection: Some(1),
+            ..community(
+                "tests/conformance/community/5-quirks.ch8",
+                "quirks-chip8",
+                Platform::OriginalChip8,
+            )
+        },
+        key_test("keypad-ex9e", 1, 0x5),
+        key_test("keypad-exa1", 2, 0x5),
+        key_test("keypad-fx0a", 3, 0xA),
+    ]);
+}